@@ -0,0 +1,102 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use nes::bus::Bus;
+use nes::cpu::CPU;
+use nes::ppu::NesPPU;
+use nes::render::{self, frame::Frame};
+use nes::rom::Rom;
+
+fn nestest_rom() -> Rom {
+    let raw = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/nestest.nes")).unwrap();
+    Rom::new(&raw).unwrap()
+}
+
+// NROM-256 image whose reset vector points at an endless INX / INY / LDA / JMP loop
+fn dispatch_rom() -> Rom {
+    let mut raw = vec![0x4e, 0x45, 0x53, 0x1a, 0x02, 0x01, 0x00, 0x00];
+    raw.resize(16, 0);
+
+    let mut prg_rom = vec![0xea; 0x8000];
+    let program = [
+        0xe8, // INX
+        0xc8, // INY
+        0xa5, 0x10, // LDA $10
+        0x4c, 0x00, 0x80, // JMP $8000
+    ];
+    prg_rom[..program.len()].copy_from_slice(&program);
+    prg_rom[0x7ffc] = 0x00;
+    prg_rom[0x7ffd] = 0x80;
+
+    raw.extend(prg_rom);
+    raw.extend(vec![0; 0x2000]);
+    Rom::new(&raw).unwrap()
+}
+
+fn run_frames(c: &mut Criterion) {
+    c.bench_function("nestest 10 frames", |b| {
+        b.iter(|| {
+            let frames = Rc::new(Cell::new(0));
+            let counter = frames.clone();
+            let bus = Bus::new(nestest_rom(), move |ppu, _, _| {
+                let mut frame = Frame::new();
+                render::render(ppu, &mut frame);
+                counter.set(counter.get() + 1);
+            });
+
+            let mut cpu = CPU::new(bus);
+            cpu.reset();
+            while frames.get() < 10 && cpu.step() {}
+        })
+    });
+}
+
+fn cpu_dispatch(c: &mut Criterion) {
+    let mut cpu = CPU::new(Bus::new(dispatch_rom(), |_, _, _| {}));
+    cpu.reset();
+
+    c.bench_function("cpu 1000 instructions", |b| {
+        b.iter(|| {
+            for _ in 0..1000 {
+                black_box(cpu.step());
+            }
+        })
+    });
+}
+
+fn ppu_tick(c: &mut Criterion) {
+    let mut ppu = NesPPU::new_empty_rom();
+
+    // One frame is 262 scanlines of 341 dots, fed 6 dots (a 2 cycle instruction) at a time
+    c.bench_function("ppu tick 1 frame", |b| {
+        b.iter(|| {
+            for _ in 0..(262 * 341 / 6) {
+                black_box(ppu.tick(6));
+            }
+        })
+    });
+}
+
+fn render_frame(c: &mut Criterion) {
+    let rom = nestest_rom();
+    let mut ppu = NesPPU::new(rom.chr_rom, rom.screen_mirroring);
+    for (i, byte) in ppu.vram.iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+    for (i, byte) in ppu.palette_table.iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+    for (i, byte) in ppu.oam_data.iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+    ppu.write_to_mask(0b0001_1110);
+
+    let mut frame = Frame::new();
+    c.bench_function("render 1 frame", |b| {
+        b.iter(|| render::render(black_box(&ppu), &mut frame))
+    });
+}
+
+criterion_group!(benches, run_frames, cpu_dispatch, ppu_tick, render_frame);
+criterion_main!(benches);
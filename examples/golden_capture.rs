@@ -0,0 +1,39 @@
+//! Regenerates a golden frame baseline: runs a ROM headlessly up to a frame and writes
+//! `<rom name>_<frame>.png` and `.hash` into a baseline directory.
+//!
+//! Only run it after checking that the new output is actually right:
+//!
+//! ```sh
+//! cargo run --example golden_capture -- nestest.nes 60 baselines
+//! ```
+
+use std::path::Path;
+use std::process;
+
+use nes::golden;
+use nes::rom::Rom;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 4 {
+        eprintln!("usage: {} <rom> <frame> <baseline dir>", args[0]);
+        process::exit(2);
+    }
+
+    let rom_path = Path::new(&args[1]);
+    let frame_no: u64 = args[2].parse().expect("frame must be a number");
+    let raw = std::fs::read(rom_path).expect("can't read the ROM");
+    let rom = Rom::new(&raw).unwrap();
+
+    let frame = golden::run_to_frame(rom, frame_no);
+    let name = format!(
+        "{}_{}",
+        rom_path.file_stem().unwrap().to_string_lossy(),
+        frame_no
+    );
+    let (png_path, hash_path) = golden::write_baseline(Path::new(&args[3]), &name, &frame)
+        .expect("can't write the baseline");
+
+    println!("{:016x}", golden::frame_hash(&frame));
+    println!("wrote {} and {}", png_path.display(), hash_path.display());
+}
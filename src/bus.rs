@@ -1,4 +1,19 @@
-use crate::{cpu::Mem, joypad::Joypad, ppu::NesPPU, rom::Rom};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::ops::RangeInclusive;
+use std::rc::Rc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cheat::{Cheat, CheatError},
+    cpu::{Inspector, Mem},
+    joypad::{FourScore, Joypad},
+    mapper::{self, Mapper},
+    ppu::{NesPPU, PpuEvent},
+    rom::{Region, Rom},
+    zapper::Zapper,
+};
 
 //  _______________ $10000  _______________
 // | PRG-ROM       |       |               |
@@ -28,57 +43,483 @@ use crate::{cpu::Mem, joypad::Joypad, ppu::NesPPU, rom::Rom};
 // | Zero Page     |       |               |
 // |_______________| $0000 |_______________|
 
+type InputPoll<'call> = Box<dyn FnMut(&mut Joypad) + 'call>;
+type GameloopCallback<'call> = Box<dyn FnMut(&NesPPU, &mut Joypad, &mut Joypad) + 'call>;
+type AccessHook<'call> = Box<dyn FnMut(Access) + 'call>;
+
+/// A CPU read or write reported to the hook set with `Bus::set_access_hook`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Access {
+    pub addr: u16,
+    pub value: u8,
+    pub kind: AccessKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+// Save states carry the machine but not the cheats, frontend hooks or the mapper, which the
+// CPU stores separately. `Bus::reconnect` moves those over from the running bus.
+#[derive(Serialize, Deserialize)]
 pub struct Bus<'call> {
+    #[serde(with = "crate::serde_array")]
     cpu_vram: [u8; 2048],
-    prg_rom: Vec<u8>,
+    ram_pattern: Vec<u8>,
+    // Cheats: RAM addresses (mirrored down) locked to a value
+    #[serde(skip)]
+    frozen: HashMap<u16, u8>,
+    // Game Genie codes patching ROM reads
+    #[serde(skip)]
+    cheats: Vec<Cheat>,
+    #[serde(skip, default = "mapper::detached")]
+    mapper: Rc<RefCell<dyn Mapper>>,
+    // `Rom::crc32` of the inserted cartridge. Save states carry it in their header
+    #[serde(skip)]
+    rom_crc: u32,
     ppu: NesPPU,
     joypad: Joypad,
+    joypad2: Joypad,
+    // Players 3 and 4, when the adapter is plugged in
+    four_score: Option<FourScore>,
+    // Takes the place of joypad 2. Shared with the frontend, which feeds it frames and the mouse
+    #[serde(skip)]
+    zapper: Option<Rc<RefCell<Zapper>>>,
+    // Last value driven on the CPU data bus
+    open_bus: u8,
+    // PAL runs 3.2 PPU dots per CPU cycle; the fraction carried over, in fifths of a dot
+    pal_dot_fifths: u8,
+    // Set when the PPU enters vblank, until `take_frame_ready`
+    frame_ready: bool,
+    // Page written to $4014, copied once the writing instruction has finished
+    oam_dma_page: Option<u8>,
+    // Debugger watchpoints, and the last watched address accessed until `take_watch_hit`
+    #[serde(skip)]
+    read_watchpoints: HashSet<u16>,
+    #[serde(skip)]
+    write_watchpoints: HashSet<u16>,
+    #[serde(skip)]
+    watch_hit: Option<u16>,
 
     pub cycles: usize,
-    gameloop_callback: Box<dyn FnMut(&NesPPU, &mut Joypad) + 'call>,
+    // Every CPU cycle run on this bus, DMA included. Unlike `cycles` it is never reset or
+    // restored from a save state
+    #[serde(skip)]
+    total_cycles: u64,
+    #[serde(skip, default = "no_gameloop_callback")]
+    gameloop_callback: GameloopCallback<'call>,
+    #[serde(skip)]
+    input_poll: Option<(u16, InputPoll<'call>)>,
+    #[serde(skip)]
+    access_hook: Option<(RangeInclusive<u16>, AccessHook<'call>)>,
+}
+
+fn no_gameloop_callback<'call>() -> GameloopCallback<'call> {
+    Box::new(|_, _, _| {})
 }
 
 impl<'call> Bus<'call> {
     pub fn new<F>(rom: Rom, gameloop_callback: F) -> Bus<'call>
     where
-        F: FnMut(&NesPPU, &mut Joypad) + 'call,
+        F: FnMut(&NesPPU, &mut Joypad, &mut Joypad) + 'call,
     {
-        let ppu = NesPPU::new(rom.chr_rom, rom.screen_mirroring);
+        let region = rom.region;
+        let rom_crc = rom.crc32();
+        let mapper = mapper::new(rom);
+        let ppu = NesPPU::with_mapper(mapper.clone(), region);
 
         Bus {
             cpu_vram: [0; 2048],
-            prg_rom: rom.prg_rom,
+            ram_pattern: Vec::new(),
+            frozen: HashMap::new(),
+            cheats: Vec::new(),
+            mapper,
+            rom_crc,
             ppu,
             joypad: Joypad::new(),
+            joypad2: Joypad::new(),
+            four_score: None,
+            zapper: None,
+            open_bus: 0,
+            pal_dot_fifths: 0,
+            frame_ready: false,
+            oam_dma_page: None,
+            read_watchpoints: HashSet::new(),
+            write_watchpoints: HashSet::new(),
+            watch_hit: None,
             cycles: 0,
+            total_cycles: 0,
             gameloop_callback: Box::from(gameloop_callback),
+            input_poll: None,
+            access_hook: None,
         }
     }
 
     pub fn tick(&mut self, cycles: u8) {
         self.cycles += cycles as usize;
+        self.total_cycles += cycles as u64;
+
+        {
+            let mut mapper = self.mapper.borrow_mut();
+            for _ in 0..cycles {
+                mapper.on_cpu_cycle();
+            }
+        }
 
         let nmi_before = self.ppu.nmi_interrupt.is_some();
-        self.ppu.tick(cycles * 3);
+        let (scanline_before, _) = self.ppu.position();
+        let dots = match self.ppu.region() {
+            Region::Ntsc => cycles * 3,
+            Region::Pal => {
+                let fifths = cycles as u16 * 16 + self.pal_dot_fifths as u16;
+                self.pal_dot_fifths = (fifths % 5) as u8;
+                (fifths / 5) as u8
+            }
+        };
+        self.ppu.tick(dots);
         let nmi_after = self.ppu.nmi_interrupt.is_some();
+        let (scanline_after, _) = self.ppu.position();
 
         if !nmi_before && nmi_after {
-            (self.gameloop_callback)(&self.ppu, &mut self.joypad);
+            (self.gameloop_callback)(&self.ppu, &mut self.joypad, &mut self.joypad2);
+        }
+
+        if scanline_before != scanline_after && scanline_after == 241 {
+            self.frame_ready = true;
+        }
+
+        if let Some((scanline, poll)) = &mut self.input_poll {
+            if scanline_before != scanline_after && scanline_after == *scanline {
+                poll(&mut self.joypad);
+            }
         }
     }
 
+    /// Calls `poll` with the controller every frame when the PPU enters `scanline`, so input
+    /// can be applied mid-frame instead of in the gameloop callback at vblank. Picking a
+    /// scanline just before the game reads `$4016` cuts up to a frame of input latency.
+    pub fn set_input_poll<F>(&mut self, scanline: u16, poll: F)
+    where
+        F: FnMut(&mut Joypad) + 'call,
+    {
+        self.input_poll = Some((scanline, Box::from(poll)));
+    }
+
+    /// Calls `hook` for every CPU read and write of an address in `range`, e.g. $2000-$2007
+    /// to follow PPU register accesses. `inspect` doesn't count as an access. Addresses are
+    /// reported as accessed, except that a PPU register mirror is reported once, as the
+    /// register it mirrors.
+    pub fn set_access_hook<F>(&mut self, range: RangeInclusive<u16>, hook: F)
+    where
+        F: FnMut(Access) + 'call,
+    {
+        self.access_hook = Some((range, Box::from(hook)));
+    }
+
+    pub fn clear_access_hook(&mut self) {
+        self.access_hook = None;
+    }
+
+    fn report_access(&mut self, addr: u16, value: u8, kind: AccessKind) {
+        if let Some((range, hook)) = &mut self.access_hook {
+            if range.contains(&addr) {
+                hook(Access { addr, value, kind });
+            }
+        }
+    }
+
+    /// Fills the 2KB of CPU RAM by repeating `pattern`, so games that seed their RNG from
+    /// uninitialized RAM behave the same on every run. Call before `CPU::reset`.
+    /// An empty pattern clears RAM. The pattern is also used by `power_cycle`.
+    pub fn seed_ram(&mut self, pattern: &[u8]) {
+        self.ram_pattern = pattern.to_vec();
+        self.init_ram();
+    }
+
+    fn init_ram(&mut self) {
+        if self.ram_pattern.is_empty() {
+            self.cpu_vram = [0; 2048];
+        } else {
+            let pattern = self.ram_pattern.iter().cycle();
+            for (byte, seed) in self.cpu_vram.iter_mut().zip(pattern) {
+                *byte = *seed;
+            }
+        }
+        // Frozen bytes stay locked through a power cycle
+        self.apply_frozen();
+    }
+
+    /// Puts everything on the bus back to its power-on state, as if the console had been
+    /// switched off and on: RAM is re-initialized from the `seed_ram` pattern (zeros by
+    /// default), the PPU and controllers are rebuilt and the mapper registers are reset. The
+    /// cartridge memory and settings (four score, OAMADDR bug, turbo) are kept.
+    pub fn power_cycle(&mut self) {
+        self.init_ram();
+        self.mapper.borrow_mut().reset();
+        self.ppu.power_cycle();
+        let mut joypad = Joypad::new();
+        joypad.keep_turbo(&self.joypad);
+        self.joypad = joypad;
+        let mut joypad2 = Joypad::new();
+        joypad2.keep_turbo(&self.joypad2);
+        self.joypad2 = joypad2;
+        if self.four_score.is_some() {
+            self.four_score = Some(FourScore::new());
+        }
+        self.open_bus = 0;
+        self.pal_dot_fifths = 0;
+        self.frame_ready = false;
+        self.oam_dma_page = None;
+        self.cycles = 0;
+    }
+
+    /// Locks the CPU RAM byte at `addr` ($0000-$1FFF, mirrors included) to `value`: reads
+    /// always return it and writes from the program are overwritten again.
+    pub fn freeze(&mut self, addr: u16, value: u8) {
+        assert!(
+            addr <= RAM_MIRRORS_END,
+            "Only RAM can be frozen: {:x}",
+            addr
+        );
+        let mirror_down_addr = addr & 0b0000_0111_1111_1111;
+        self.frozen.insert(mirror_down_addr, value);
+        self.cpu_vram[mirror_down_addr as usize] = value;
+    }
+
+    /// Releases a `freeze`. The byte keeps the frozen value until the program writes it.
+    pub fn unfreeze(&mut self, addr: u16) {
+        self.frozen.remove(&(addr & 0b0000_0111_1111_1111));
+    }
+
+    fn apply_frozen(&mut self) {
+        for (&addr, &value) in &self.frozen {
+            self.cpu_vram[addr as usize] = value;
+        }
+    }
+
+    /// Adds a Game Genie code, see `Cheat::decode`.
+    pub fn add_cheat(&mut self, code: &str) -> Result<(), CheatError> {
+        self.cheats.push(Cheat::decode(code)?);
+        Ok(())
+    }
+
+    pub fn clear_cheats(&mut self) {
+        self.cheats.clear();
+    }
+
+    pub fn set_four_score(&mut self, enabled: bool) {
+        self.four_score = if enabled {
+            Some(FourScore::new())
+        } else {
+            None
+        };
+    }
+
+    pub fn four_score_mut(&mut self) -> Option<&mut FourScore> {
+        self.four_score.as_mut()
+    }
+
+    /// Plugs a Zapper into the second port in place of joypad 2, or unplugs it with `None`.
+    pub fn set_zapper(&mut self, zapper: Option<Rc<RefCell<Zapper>>>) {
+        self.zapper = zapper;
+    }
+
+    /// Makes `CPU::run_until_break` stop after an instruction reads `addr`. The address is
+    /// matched as accessed, so a RAM mirror has to be watched separately. PPU register
+    /// mirrors are the exception: they match the register they mirror.
+    pub fn add_read_watchpoint(&mut self, addr: u16) {
+        self.read_watchpoints.insert(addr);
+    }
+
+    /// Makes `CPU::run_until_break` stop after an instruction writes `addr`.
+    pub fn add_write_watchpoint(&mut self, addr: u16) {
+        self.write_watchpoints.insert(addr);
+    }
+
+    pub fn remove_watchpoint(&mut self, addr: u16) {
+        self.read_watchpoints.remove(&addr);
+        self.write_watchpoints.remove(&addr);
+    }
+
+    /// The watched address accessed most recently, if any, since the last call.
+    pub fn take_watch_hit(&mut self) -> Option<u16> {
+        self.watch_hit.take()
+    }
+
+    /// See `NesPPU::set_oam_addr_bug`.
+    pub fn set_oam_addr_bug(&mut self, enabled: bool) {
+        self.ppu.set_oam_addr_bug(enabled);
+    }
+
+    pub fn ppu(&self) -> &NesPPU {
+        &self.ppu
+    }
+
+    /// See `NesPPU::set_event_log`.
+    pub fn set_ppu_event_log(&mut self, enabled: bool) {
+        self.ppu.set_event_log(enabled);
+    }
+
+    pub fn take_ppu_events(&mut self) -> Vec<(u16, usize, PpuEvent)> {
+        self.ppu.take_events()
+    }
+
+    /// Whether the PPU finished drawing a frame (entered vblank) since the last call. Unlike
+    /// the gameloop callback this doesn't depend on the game enabling NMI.
+    pub fn take_frame_ready(&mut self) -> bool {
+        std::mem::take(&mut self.frame_ready)
+    }
+
+    pub fn get_ppu_position(&self) -> (u16, usize) {
+        self.ppu.position()
+    }
+
+    /// See `CPU::total_cycles`.
+    pub fn total_cycles(&self) -> u64 {
+        self.total_cycles
+    }
+
+    pub fn ppu_frame(&self) -> u64 {
+        self.ppu.frame()
+    }
+
     pub fn poll_nmi_status(&mut self) -> Option<u8> {
         self.ppu.nmi_interrupt.take()
     }
 
-    fn read_prg_rom(&self, mut addr: u16) -> u8 {
-        addr -= 0x8000;
-        if self.prg_rom.len() == 0x4000 && addr >= 0x4000 {
-            // Mirror if needed
-            addr = addr % 0x4000;
+    pub(crate) fn mapper_state(&self) -> Vec<u8> {
+        self.mapper.borrow().save_state()
+    }
+
+    pub(crate) fn rom_crc(&self) -> u32 {
+        self.rom_crc
+    }
+
+    pub(crate) fn load_mapper_state(&mut self, state: &[u8]) -> Result<(), String> {
+        self.mapper.borrow_mut().load_state(state)
+    }
+
+    /// Takes the mapper, gameloop callback, input poll, access hook, Zapper, watchpoints and
+    /// cheats from `running`, so a bus restored from a save state can replace it. Frozen RAM
+    /// bytes get their values back and the joypads keep their turbo settings.
+    pub(crate) fn reconnect(&mut self, running: &mut Bus<'call>) {
+        std::mem::swap(&mut self.mapper, &mut running.mapper);
+        self.rom_crc = running.rom_crc;
+        std::mem::swap(&mut self.gameloop_callback, &mut running.gameloop_callback);
+        std::mem::swap(&mut self.input_poll, &mut running.input_poll);
+        std::mem::swap(&mut self.access_hook, &mut running.access_hook);
+        std::mem::swap(&mut self.zapper, &mut running.zapper);
+        std::mem::swap(&mut self.read_watchpoints, &mut running.read_watchpoints);
+        std::mem::swap(&mut self.write_watchpoints, &mut running.write_watchpoints);
+        std::mem::swap(&mut self.frozen, &mut running.frozen);
+        std::mem::swap(&mut self.cheats, &mut running.cheats);
+        self.apply_frozen();
+        self.joypad.keep_turbo(&running.joypad);
+        self.joypad2.keep_turbo(&running.joypad2);
+        self.total_cycles = running.total_cycles;
+        self.ppu.set_mapper(self.mapper.clone());
+    }
+
+    /// Contents of the cartridge PRG-RAM ($6000-$7FFF), for writing out battery saves.
+    pub fn save_sram(&self) -> Vec<u8> {
+        self.mapper.borrow().prg_ram().to_vec()
+    }
+
+    /// Restores PRG-RAM from a battery save. The save has to match the cartridge's RAM size.
+    pub fn load_sram(&mut self, data: &[u8]) -> Result<(), String> {
+        let mut mapper = self.mapper.borrow_mut();
+        let prg_ram = mapper.prg_ram_mut();
+        if data.len() != prg_ram.len() {
+            return Err(format!(
+                "Save is {} bytes but the cartridge has {} bytes of PRG-RAM.",
+                data.len(),
+                prg_ram.len()
+            ));
+        }
+        prg_ram.copy_from_slice(data);
+        Ok(())
+    }
+
+    /// Reads CPU RAM ($0000-$1FFF) or cartridge PRG-RAM ($6000-$7FFF) directly, without
+    /// going through registers, cheats or open bus. Anything else, including PRG-RAM on boards
+    /// that have none, reads as 0.
+    pub fn peek_ram(&self, addr: u16) -> u8 {
+        match addr {
+            RAM..=RAM_MIRRORS_END => self.cpu_vram[(addr & 0b0000_0111_1111_1111) as usize],
+            0x6000..=0x7fff => {
+                let mapper = self.mapper.borrow();
+                mapper
+                    .prg_ram()
+                    .get((addr - 0x6000) as usize)
+                    .copied()
+                    .unwrap_or(0)
+            }
+            _ => 0,
+        }
+    }
+
+    /// Writes CPU RAM or cartridge PRG-RAM directly, e.g. from a cheat search. Fails for any
+    /// other address rather than writing a register.
+    pub fn poke_ram(&mut self, addr: u16, data: u8) -> Result<(), String> {
+        match addr {
+            RAM..=RAM_MIRRORS_END => {
+                self.cpu_vram[(addr & 0b0000_0111_1111_1111) as usize] = data;
+                Ok(())
+            }
+            0x6000..=0x7fff => {
+                let mut mapper = self.mapper.borrow_mut();
+                match mapper.prg_ram_mut().get_mut((addr - 0x6000) as usize) {
+                    Some(byte) => {
+                        *byte = data;
+                        Ok(())
+                    }
+                    None => Err(format!("No PRG-RAM at ${:04X}.", addr)),
+                }
+            }
+            _ => Err(format!("${:04X} is neither RAM nor PRG-RAM.", addr)),
         }
+    }
 
-        self.prg_rom[addr as usize]
+    /// Whether something on the bus holds the IRQ line low. Unlike NMI this is a level, so
+    /// it stays asserted until the source is acknowledged.
+    pub fn poll_irq_status(&self) -> bool {
+        self.mapper.borrow().irq()
+    }
+
+    // Copies $XX00-$XXFF into OAM. Every source byte is read through `mem_read`, so any page
+    // works like a CPU read would, cartridge pages included: those go through the mapper.
+    //
+    // The CPU is halted for 513 cycles, or 514 when the DMA would start on an odd cycle: one
+    // halt cycle, an optional alignment cycle, then a read and a write to $2004 for each byte.
+    // Every cycle is ticked on its own so the PPU runs alongside the copy.
+    //
+    // A write to $4014 only records the page. The CPU starts the copy with `run_oam_dma`
+    // after ticking the writing instruction, so the alignment is taken from the cycle the DMA
+    // really starts on and the stall isn't counted as part of the instruction.
+    fn oam_dma(&mut self, page: u8) {
+        let align = self.cycles % 2 == 1;
+        self.tick(1);
+        if align {
+            self.tick(1);
+        }
+
+        let hi: u16 = (page as u16) << 8;
+        for i in 0..=0xff {
+            let byte = self.mem_read(hi.wrapping_add(i));
+            self.tick(1);
+            self.ppu.write_to_oam_data(byte);
+            self.tick(1);
+        }
+    }
+
+    /// Runs the OAM DMA started by a write to $4014, if any. The CPU calls this after each
+    /// instruction; code writing the bus directly has to call it itself.
+    pub fn run_oam_dma(&mut self) {
+        if let Some(page) = self.oam_dma_page.take() {
+            self.oam_dma(page);
+        }
     }
 }
 
@@ -87,41 +528,99 @@ const RAM_MIRRORS_END: u16 = 0x1FFF;
 const PPU_REGISTERS: u16 = 0x2000;
 const PPU_REGISTERS_MIRRORS_END: u16 = 0x3FFF;
 
+// $2008-$3FFF repeat the eight PPU registers
+fn mirror_down_ppu_register(addr: u16) -> u16 {
+    match addr {
+        0x2008..=PPU_REGISTERS_MIRRORS_END => addr & 0b0010_0000_0000_0111,
+        _ => addr,
+    }
+}
+
+impl Inspector for Bus<'_> {
+    fn inspect(&self, addr: u16) -> u8 {
+        match addr {
+            RAM..=RAM_MIRRORS_END => self.cpu_vram[(addr & 0b0000_0111_1111_1111) as usize],
+            // Register reads have side effects, show the PPU latch instead
+            PPU_REGISTERS..=PPU_REGISTERS_MIRRORS_END => self.ppu.read_open_bus(),
+            0x4020..=0xFFFF => self.mapper.borrow_mut().cpu_read(addr),
+            _ => self.open_bus,
+        }
+    }
+}
+
 impl Mem for Bus<'_> {
     fn mem_read(&mut self, addr: u16) -> u8 {
-        match addr {
+        let addr = mirror_down_ppu_register(addr);
+        let value = match addr {
             RAM..=RAM_MIRRORS_END => {
                 let mirror_down_addr = addr & 0b0000_0111_1111_1111;
                 self.cpu_vram[mirror_down_addr as usize]
             }
-            0x2000 | 0x2001 | 0x2003 | 0x2005 | 0x2006 | 0x4014 => {
-                panic!("Attempt to read from write-only PPU address {:x}", addr);
-            }
+            0x2000 | 0x2001 | 0x2003 | 0x2005 | 0x2006 => self.ppu.read_open_bus(),
             0x2002 => self.ppu.read_status(),
             0x2004 => self.ppu.read_oam_data(),
             0x2007 => self.ppu.read_data(),
 
-            0x4000..=0x4015 => 0,         // Ignore APU
-            0x4016 => self.joypad.read(), // Ignore Joypad 1
-            0x4017 => 0,                  // Ignore Joypad 2
+            // APU and I/O registers are write-only and don't drive the bus. $4015 would put the
+            // APU status on every bit but 5; without an APU those read as 0.
+            0x4000..=0x4014 => self.open_bus,
+            0x4015 => self.open_bus & 0b0010_0000,
+            // Controllers only drive the low bits, bits 7-5 keep the open bus value
+            0x4016 => {
+                let bit = match &mut self.four_score {
+                    Some(four_score) => four_score.read(0, &mut self.joypad, &mut self.joypad2),
+                    None => self.joypad.read(),
+                };
+                (self.open_bus & 0b1110_0000) | bit
+            }
+            0x4017 => {
+                let bit = match (&self.zapper, &mut self.four_score) {
+                    (Some(zapper), _) => zapper.borrow().read(),
+                    (None, Some(four_score)) => {
+                        four_score.read(1, &mut self.joypad, &mut self.joypad2)
+                    }
+                    (None, None) => self.joypad2.read(),
+                };
+                (self.open_bus & 0b1110_0000) | bit
+            }
 
-            0x2008..=PPU_REGISTERS_MIRRORS_END => {
-                let mirror_down_addr = addr & 0b0010_0000_0000_0111;
-                self.mem_read(mirror_down_addr)
+            0x8000..=0xFFFF => {
+                let value = self.mapper.borrow_mut().cpu_read(addr);
+                self.cheats
+                    .iter()
+                    .fold(value, |value, cheat| cheat.apply(addr, value))
             }
-            0x8000..=0xFFFF => self.read_prg_rom(addr),
+            // Cartridge space: expansion registers and PRG-RAM
+            0x4020..=0x7FFF => self.mapper.borrow_mut().cpu_read(addr),
             _ => {
                 println!("Ignoring mem access(read) at {:x}", addr);
                 0
             }
+        };
+
+        if self.read_watchpoints.contains(&addr) {
+            self.watch_hit = Some(addr);
+        }
+        self.report_access(addr, value, AccessKind::Read);
+        // $4015 is inside the CPU, so reading it doesn't drive the external bus
+        if addr != 0x4015 {
+            self.open_bus = value;
         }
+        value
     }
 
     fn mem_write(&mut self, addr: u16, data: u8) {
+        let addr = mirror_down_ppu_register(addr);
+        if self.write_watchpoints.contains(&addr) {
+            self.watch_hit = Some(addr);
+        }
+        self.report_access(addr, data, AccessKind::Write);
+        self.open_bus = data;
         match addr {
             RAM..=RAM_MIRRORS_END => {
                 let mirror_down_addr = addr & 0b0000_0111_1111_1111;
-                self.cpu_vram[mirror_down_addr as usize] = data;
+                let value = self.frozen.get(&mirror_down_addr).copied();
+                self.cpu_vram[mirror_down_addr as usize] = value.unwrap_or(data);
             }
             0x2000 => {
                 self.ppu.write_to_ctrl(data);
@@ -150,30 +649,18 @@ impl Mem for Bus<'_> {
 
             0x4000..=0x4013 | 0x4015 => {} // Ignore APU
 
-            0x4016 => self.joypad.write(data), // Ignore Joypad 1
-            0x4017 => {}                       // Ignore Joypad 2
-
-            0x4014 => {
-                let mut buffer: [u8; 256] = [0; 256];
-                let hi: u16 = (data as u16) << 8;
-                for i in 0..256u16 {
-                    buffer[i as usize] = self.mem_read(hi + i);
+            0x4016 => {
+                self.joypad.write(data);
+                self.joypad2.write(data);
+                if let Some(four_score) = &mut self.four_score {
+                    four_score.write(data);
                 }
-
-                self.ppu.write_oam_dma(&buffer);
-
-                // todo: handle this eventually
-                // let add_cycles: u16 = if self.cycles % 2 == 1 { 514 } else { 513 };
-                // self.tick(add_cycles); //todo this will cause weird effects as PPU will have 513/514 * 3 ticks
             }
+            0x4017 => {} // APU frame counter, not emulated
 
-            0x2008..=PPU_REGISTERS_MIRRORS_END => {
-                let mirror_down_addr = addr & 0b0010_0000_0000_0111;
-                self.mem_write(mirror_down_addr, data);
-            }
-            0x8000..=0xFFFF => {
-                panic!("Attempted to write to Cartridge ROM space")
-            }
+            0x4014 => self.oam_dma_page = Some(data),
+
+            0x4020..=0xFFFF => self.mapper.borrow_mut().cpu_write(addr, data),
             _ => {
                 println!("Ignoring mem access(write) at {:x}", addr);
             }
@@ -184,12 +671,480 @@ impl Mem for Bus<'_> {
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::rom::test;
+    use crate::cpu::CPU;
+    use crate::joypad::JoypadButton;
+    use crate::rom::{test, Mirroring};
 
     #[test]
     fn test_mem_read_write_to_ram() {
-        let mut bus = Bus::new(test::TestRom::create_test_rom(vec![]), |_, _| {});
+        let mut bus = Bus::new(test::TestRom::create_test_rom(vec![]), |_, _, _| {});
         bus.mem_write(0x01, 0x55);
         assert_eq!(bus.mem_read(0x01), 0x55);
     }
+
+    #[test]
+    fn test_inspect_range() {
+        let mut bus = Bus::new(test::TestRom::create_test_rom(vec![]), |_, _, _| {});
+        for i in 0..16 {
+            bus.mem_write(i, 0xa0 | i as u8);
+        }
+        let expected: Vec<u8> = (0..16).map(|i| 0xa0 | i).collect();
+        assert_eq!(bus.inspect_range(0x0000, 16), expected);
+
+        // Wraps from $FFFF to $0000
+        let dump = bus.inspect_range(0xfff8, 16);
+        assert_eq!(dump[0..8], bus.inspect_range(0xfff8, 8)[..]);
+        assert_eq!(dump[8..], expected[0..8]);
+
+        // $2002 is shown without clearing vblank
+        bus.ppu.status.set_vblank_status(true);
+        bus.inspect_range(0x2000, 8);
+        assert!(bus.ppu.status.is_in_vblank());
+    }
+
+    #[test]
+    fn test_peek_and_poke_ram() {
+        let mut bus = Bus::new(test::TestRom::create_test_rom(vec![]), |_, _, _| {});
+        bus.poke_ram(0x0300, 0x42).unwrap();
+        assert_eq!(bus.peek_ram(0x0300), 0x42);
+        assert_eq!(bus.peek_ram(0x0b00), 0x42); // mirror
+        assert_eq!(bus.mem_read(0x0300), 0x42);
+
+        bus.poke_ram(0x6010, 0x99).unwrap();
+        assert_eq!(bus.peek_ram(0x6010), 0x99);
+        assert_eq!(bus.mem_read(0x6010), 0x99);
+
+        bus.open_bus = 0x11;
+        bus.poke_ram(0x2000, 0x80)
+            .expect_err("PPU registers aren't RAM");
+        assert_eq!(bus.ppu.ctrl.bits(), 0);
+        assert_eq!(bus.peek_ram(0x8000), 0);
+        assert_eq!(bus.open_bus, 0x11);
+    }
+
+    #[test]
+    fn test_seed_ram_is_reproducible() {
+        // Derive a "random" value from uninitialized RAM: LDA $10; EOR $0777; STA $00
+        let program = vec![0xa5, 0x10, 0x4d, 0x77, 0x07, 0x85, 0x00, 0x00];
+        let run = |pattern: &[u8]| {
+            let mut bus = Bus::new(
+                test::TestRom::create_test_rom(program.clone()),
+                |_, _, _| {},
+            );
+            bus.seed_ram(pattern);
+            let mut cpu = CPU::new(bus);
+            cpu.reset();
+            cpu.run();
+            cpu.mem_read(0x00)
+        };
+
+        assert_eq!(run(&[0x12, 0x34, 0x56]), run(&[0x12, 0x34, 0x56]));
+        assert_eq!(run(&[0x12, 0x34, 0x56]), 0x34 ^ 0x12); // $10 -> 0x34, $0777 -> 0x12
+        assert_ne!(run(&[0x12, 0x34, 0x56]), run(&[0xff, 0x00]));
+    }
+
+    #[test]
+    fn test_power_cycle() {
+        let mut bus = Bus::new(
+            test::TestRom::create_test_rom(vec![0xe8, 0x00]),
+            |_, _, _| {},
+        );
+        bus.seed_ram(&[0xaa, 0x55]);
+        bus.mem_write(0x0000, 0x01);
+        bus.mem_write(0x2000, 0x80);
+        bus.tick(50);
+
+        bus.power_cycle();
+
+        assert_eq!(bus.mem_read(0x0000), 0xaa);
+        assert_eq!(bus.mem_read(0x0001), 0x55);
+        assert!(!bus.ppu.ctrl.generate_vblank_nmi());
+        assert_eq!(bus.get_ppu_position(), (0, 0));
+        assert_eq!(bus.cycles, 0);
+        // the cartridge stays inserted
+        assert_eq!(bus.mem_read(0x8000), 0xe8);
+
+        // MMC1 with 128KB PRG in 8KB banks 0-15: the 16KB bank 2 selected at $8000 goes
+        // back to bank 0
+        let mut bus = crate::mapper_test_utils::test_bus(1, 8, 1);
+        for bit in 0..5 {
+            bus.mem_write(0xe000, (0b10 >> bit) & 1);
+        }
+        bus.mem_write(0x6000, 0x42);
+        assert_eq!(bus.mem_read(0x8000), 4);
+
+        bus.power_cycle();
+
+        assert_eq!(bus.mem_read(0x8000), 0);
+        assert_eq!(bus.mem_read(0xc000), 14);
+        // battery RAM survives
+        assert_eq!(bus.mem_read(0x6000), 0x42);
+    }
+
+    #[test]
+    fn test_power_cycle_and_load_state_keep_turbo() {
+        fn turbo_a_reads(joypad: &mut Joypad) -> u8 {
+            joypad.set_button_pressed_status(JoypadButton::BUTTON_A, true);
+            joypad.tick_turbo();
+            joypad.write(1);
+            joypad.write(0);
+            joypad.read()
+        }
+
+        let mut cpu = CPU::new(Bus::new(
+            test::TestRom::create_test_rom(vec![]),
+            |_, _, _| {},
+        ));
+        cpu.reset();
+        let state = cpu.save_state();
+        cpu.bus.joypad.set_turbo(JoypadButton::BUTTON_A, true);
+        cpu.bus.joypad2.set_turbo(JoypadButton::BUTTON_A, true);
+        cpu.bus.joypad2.set_turbo_rate(2);
+
+        cpu.bus.power_cycle();
+        assert_eq!(turbo_a_reads(&mut cpu.bus.joypad), 0);
+        assert_eq!(turbo_a_reads(&mut cpu.bus.joypad2), 1);
+        assert_eq!(turbo_a_reads(&mut cpu.bus.joypad2), 0);
+
+        cpu.load_state(&state).unwrap();
+        assert_eq!(turbo_a_reads(&mut cpu.bus.joypad), 0);
+        assert_eq!(turbo_a_reads(&mut cpu.bus.joypad2), 1);
+        assert_eq!(turbo_a_reads(&mut cpu.bus.joypad2), 0);
+    }
+
+    // Raises IRQ once `counter` CPU cycles have gone by, like an FME-7 counting down.
+    // PRG is mapped flat at $8000-$FFFF.
+    #[derive(Serialize, Deserialize)]
+    struct CycleTimer {
+        prg: Vec<u8>,
+        counter: u16,
+        irq: bool,
+    }
+
+    impl crate::mapper::CartridgeRom for CycleTimer {
+        fn take_rom(&mut self, _running: &mut Self) {}
+    }
+
+    impl Mapper for CycleTimer {
+        fn cpu_read(&mut self, addr: u16) -> u8 {
+            if addr >= 0x8000 {
+                self.prg[(addr - 0x8000) as usize]
+            } else {
+                0
+            }
+        }
+
+        fn cpu_write(&mut self, _addr: u16, _data: u8) {}
+
+        fn ppu_read(&mut self, _addr: u16) -> u8 {
+            0
+        }
+
+        fn ppu_write(&mut self, _addr: u16, _data: u8) {}
+
+        fn mirroring(&self) -> Mirroring {
+            Mirroring::Horizontal
+        }
+
+        fn on_cpu_cycle(&mut self) {
+            if self.counter > 0 {
+                self.counter -= 1;
+                self.irq = self.counter == 0;
+            }
+        }
+
+        fn irq(&self) -> bool {
+            self.irq
+        }
+    }
+
+    #[test]
+    fn test_mapper_cpu_cycle_irq() {
+        let mut prg = vec![0; 0x8000];
+        prg[..5].copy_from_slice(&[
+            0x58, // CLI
+            0xe8, // INX
+            0x4c, 0x01, 0x80, // JMP $8001
+        ]);
+        prg[0x1000..0x1003].copy_from_slice(&[0x4c, 0x00, 0x90]); // JMP $9000
+        prg[0x7ffc..].copy_from_slice(&[0x00, 0x80, 0x00, 0x90]);
+
+        let mut bus = Bus::new(test::TestRom::create_test_rom(vec![]), |_, _, _| {});
+        bus.mapper = Rc::new(RefCell::new(CycleTimer {
+            prg,
+            counter: 100,
+            irq: false,
+        }));
+        bus.ppu.set_mapper(bus.mapper.clone());
+        let mut cpu = CPU::new(bus);
+        cpu.reset();
+
+        while cpu.program_counter != 0x9000 {
+            assert!(cpu.bus.cycles < 120, "IRQ not taken");
+            cpu.step();
+        }
+        assert!(cpu.bus.cycles >= 100);
+        assert!(cpu.register_x > 0);
+    }
+
+    #[test]
+    fn test_joypad2() {
+        let mut bus = Bus::new(test::TestRom::create_test_rom(vec![]), |_, _, _| {});
+        bus.joypad2
+            .set_button_pressed_status(JoypadButton::BUTTON_B, true);
+        bus.mem_write(0x4016, 1);
+        bus.mem_write(0x4016, 0);
+
+        let port0: Vec<u8> = (0..8).map(|_| bus.mem_read(0x4016) & 1).collect();
+        let port1: Vec<u8> = (0..8).map(|_| bus.mem_read(0x4017) & 1).collect();
+        assert_eq!(port0, [0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(port1, [0, 1, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_four_score_signature() {
+        let mut bus = Bus::new(test::TestRom::create_test_rom(vec![]), |_, _, _| {});
+        bus.set_four_score(true);
+        bus.mem_write(0x4016, 1);
+        bus.mem_write(0x4016, 0);
+
+        let port0: Vec<u8> = (0..24).map(|_| bus.mem_read(0x4016)).collect();
+        let port1: Vec<u8> = (0..24).map(|_| bus.mem_read(0x4017)).collect();
+
+        assert_eq!(&port0[16..24], &[0, 0, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(&port1[16..24], &[0, 0, 1, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_oam_dma_from_last_page() {
+        let mut rom = test::TestRom::create_test_rom(vec![]);
+        rom.prg_rom[0x7f00] = 0x11;
+        rom.prg_rom[0x7fff] = 0x22;
+        let mut bus = Bus::new(rom, |_, _, _| {});
+
+        bus.mem_write(0x4014, 0xff);
+        bus.run_oam_dma();
+
+        assert_eq!(bus.ppu.oam_data[0x00], 0x11);
+        assert_eq!(bus.ppu.oam_data[0xff], 0x22);
+    }
+
+    #[test]
+    fn test_apu_registers_read_open_bus() {
+        let mut bus = Bus::new(test::TestRom::create_test_rom(vec![]), |_, _, _| {});
+        bus.mem_write(0x0001, 0x7e);
+        assert_eq!(bus.mem_read(0x4000), 0x7e);
+        assert_eq!(bus.mem_read(0x4014), 0x7e);
+        assert_eq!(bus.mem_read(0x4015), 0x20);
+        // Reading $4015 leaves the open bus value alone
+        assert_eq!(bus.mem_read(0x4000), 0x7e);
+
+        bus.mem_write(0x0001, 0x81);
+        assert_eq!(bus.mem_read(0x4015), 0x00);
+    }
+
+    #[test]
+    fn test_game_genie() {
+        let mut rom = test::TestRom::create_test_rom(vec![]);
+        rom.prg_rom[0x11d9] = 0xde; // $91D9
+        rom.prg_rom[0x14a7] = 0x03; // $94A7
+        let mut bus = Bus::new(rom, |_, _, _| {});
+
+        bus.add_cheat("SXIOPO").unwrap();
+        bus.add_cheat("ZEXPYGLA").unwrap();
+        assert_eq!(bus.add_cheat("SXIO"), Err(CheatError::InvalidLength(4)));
+        assert_eq!(bus.mem_read(0x91d9), 0xad);
+        assert_eq!(bus.mem_read(0x94a7), 0x02);
+
+        bus.clear_cheats();
+        assert_eq!(bus.mem_read(0x91d9), 0xde);
+    }
+
+    #[test]
+    fn test_oam_dma_cycles() {
+        let mut bus = Bus::new(test::TestRom::create_test_rom(vec![]), |_, _, _| {});
+        let mut lump = Bus::new(test::TestRom::create_test_rom(vec![]), |_, _, _| {});
+        bus.mem_write(0x4014, 0x02);
+        bus.run_oam_dma();
+        for _ in 0..513 {
+            lump.tick(1);
+        }
+        assert_eq!(bus.cycles, 513);
+        assert_eq!(bus.ppu.position(), lump.ppu.position());
+
+        // Starting on an odd cycle costs one more for alignment
+        bus.mem_write(0x4014, 0x02);
+        bus.run_oam_dma();
+        assert_eq!(bus.cycles, 513 + 514);
+    }
+
+    #[test]
+    fn test_oam_dma_sees_ppu_advance() {
+        let mut bus = Bus::new(test::TestRom::create_test_rom(vec![]), |_, _, _| {});
+        while bus.ppu.position() < (240, 200) {
+            bus.tick(1);
+        }
+
+        // Page $20 reads $2002 every 8 bytes. Vblank starts part way through the copy, so a
+        // later read sees it (and clears it) while the first one doesn't.
+        bus.mem_write(0x4014, 0x20);
+        bus.run_oam_dma();
+        let vblank: Vec<usize> = (0x02..0x100)
+            .step_by(8)
+            .filter(|&i| bus.ppu.oam_data[i] & 0x80 != 0)
+            .collect();
+        assert_eq!(vblank.len(), 1);
+        assert_ne!(vblank[0], 0x02);
+    }
+
+    #[test]
+    fn test_oam_dma_from_io_page() {
+        let mut bus = Bus::new(test::TestRom::create_test_rom(vec![]), |_, _, _| {});
+        // $4000-$40FF includes $4014 itself, which must not panic when read
+        bus.mem_write(0x4014, 0x40);
+        bus.run_oam_dma();
+        // It reads open bus, still the page number written to start the DMA
+        assert_eq!(bus.ppu.oam_data[0x14], 0x40);
+    }
+
+    #[test]
+    fn test_joypad_read_keeps_open_bus_bits() {
+        // LDA $4016
+        let test_rom = test::TestRom::create_test_rom(vec![0xad, 0x16, 0x40, 0x00]);
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
+        cpu.reset();
+        cpu.bus
+            .joypad
+            .set_button_pressed_status(JoypadButton::BUTTON_A, true);
+        cpu.run();
+
+        // The last byte on the bus before the read is the operand's high byte, $40
+        assert_eq!(cpu.register_a, 0x41);
+    }
+
+    #[test]
+    fn test_access_hook() {
+        let accesses = RefCell::new(Vec::new());
+        let mut bus = Bus::new(test::TestRom::create_test_rom(vec![]), |_, _, _| {});
+        bus.set_access_hook(0x2000..=0x2007, |access| accesses.borrow_mut().push(access));
+        bus.ppu.status.set_vblank_status(true);
+
+        bus.mem_write(0x2000, 0x80);
+        bus.mem_write(0x0010, 0x01);
+        bus.inspect(0x2002);
+        let status = bus.mem_read(0x2002);
+        // A mirror of $2002, reported once as $2002
+        let mirrored = bus.mem_read(0x3ffa);
+        drop(bus);
+
+        assert_eq!(
+            accesses.into_inner(),
+            vec![
+                Access {
+                    addr: 0x2000,
+                    value: 0x80,
+                    kind: AccessKind::Write
+                },
+                Access {
+                    addr: 0x2002,
+                    value: status,
+                    kind: AccessKind::Read
+                },
+                Access {
+                    addr: 0x2002,
+                    value: mirrored,
+                    kind: AccessKind::Read
+                },
+            ]
+        );
+        assert_eq!(status & 0x80, 0x80);
+    }
+
+    #[test]
+    fn test_input_poll_at_scanline() {
+        let mut bus = Bus::new(test::TestRom::create_test_rom(vec![]), |_, _, _| {});
+        bus.set_input_poll(30, |joypad| {
+            joypad.set_button_pressed_status(JoypadButton::BUTTON_A, true);
+        });
+        let read_button_a = |bus: &mut Bus| {
+            bus.mem_write(0x4016, 1);
+            bus.mem_write(0x4016, 0);
+            bus.mem_read(0x4016) & 1
+        };
+
+        while bus.get_ppu_position().0 < 29 {
+            bus.tick(1);
+        }
+        assert_eq!(read_button_a(&mut bus), 0);
+
+        while bus.get_ppu_position().0 < 30 {
+            bus.tick(1);
+        }
+        assert_eq!(read_button_a(&mut bus), 1);
+    }
+
+    #[test]
+    fn test_freeze() {
+        // LDA #$01; STA $72; LDX $72; STA $0872; LDY $0872
+        let program = vec![
+            0xa9, 0x01, 0x85, 0x72, 0xa6, 0x72, 0x8d, 0x72, 0x08, 0xac, 0x72, 0x08, 0x00,
+        ];
+        let mut cpu = CPU::new(Bus::new(
+            test::TestRom::create_test_rom(program),
+            |_, _, _| {},
+        ));
+        cpu.bus.freeze(0x0072, 0x99);
+        cpu.reset();
+        cpu.run();
+
+        assert_eq!(cpu.register_x, 0x99);
+        assert_eq!(cpu.register_y, 0x99);
+        assert_eq!(cpu.mem_read(0x0072), 0x99);
+
+        cpu.power_cycle();
+        assert_eq!(cpu.mem_read(0x0072), 0x99);
+
+        cpu.bus.unfreeze(0x0872);
+        cpu.mem_write(0x0072, 0x01);
+        assert_eq!(cpu.mem_read(0x0072), 0x01);
+    }
+
+    #[test]
+    fn test_read_write_only_ppu_register() {
+        let mut bus = Bus::new(test::TestRom::create_test_rom(vec![]), |_, _, _| {});
+        bus.mem_write(0x2001, 0x1e);
+        assert_eq!(bus.mem_read(0x2005), 0x1e);
+        assert_eq!(bus.mem_read(0x200b), 0x1e); // mirror of $2003
+    }
+
+    #[test]
+    fn test_pal_dot_ratio() {
+        let mut rom = test::TestRom::create_test_rom(vec![]);
+        rom.region = Region::Pal;
+        let mut bus = Bus::new(rom, |_, _, _| {});
+
+        bus.tick(2);
+        assert_eq!(bus.ppu().position(), (0, 6));
+        bus.tick(3);
+        assert_eq!(bus.ppu().position(), (0, 16));
+    }
+
+    #[test]
+    fn test_save_and_load_sram() {
+        let mut bus = Bus::new(test::TestRom::create_test_rom(vec![]), |_, _, _| {});
+        bus.mem_write(0x6000, 0x12);
+        bus.mem_write(0x7fff, 0x34);
+
+        let save = bus.save_sram();
+        assert_eq!(save.len(), 0x2000);
+
+        let mut bus = Bus::new(test::TestRom::create_test_rom(vec![]), |_, _, _| {});
+        bus.load_sram(&save).unwrap();
+        assert_eq!(bus.mem_read(0x6000), 0x12);
+        assert_eq!(bus.mem_read(0x7fff), 0x34);
+
+        bus.load_sram(&save[..0x1000])
+            .expect_err("a save of the wrong size should be rejected");
+        assert_eq!(bus.mem_read(0x6000), 0x12);
+    }
 }
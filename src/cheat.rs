@@ -0,0 +1,128 @@
+//! Game Genie codes.
+//!
+//! A code patches what the CPU reads from one ROM address ($8000-$FFFF). Six letter codes
+//! always substitute the value; eight letter codes only do so while the ROM holds the compare
+//! value, which keeps them from hitting the wrong bank on bank-switched games.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+// Each letter is a 4 bit nibble, in this order
+const LETTERS: &str = "APZLGITYEOXUKSVN";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Cheat {
+    pub addr: u16,
+    pub value: u8,
+    pub compare: Option<u8>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CheatError {
+    /// Codes are 6 or 8 letters long.
+    InvalidLength(usize),
+    InvalidLetter(char),
+}
+
+impl fmt::Display for CheatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheatError::InvalidLength(len) => {
+                write!(f, "Game Genie codes have 6 or 8 letters, got {}.", len)
+            }
+            CheatError::InvalidLetter(c) => write!(f, "'{}' is not a Game Genie letter.", c),
+        }
+    }
+}
+
+impl std::error::Error for CheatError {}
+
+impl Cheat {
+    /// Decodes a Game Genie code such as "SXIOPO". Letters are case insensitive.
+    pub fn decode(code: &str) -> Result<Self, CheatError> {
+        let n = code
+            .chars()
+            .map(|c| {
+                LETTERS
+                    .find(c.to_ascii_uppercase())
+                    .map(|i| i as u16)
+                    .ok_or(CheatError::InvalidLetter(c))
+            })
+            .collect::<Result<Vec<u16>, _>>()?;
+        if n.len() != 6 && n.len() != 8 {
+            return Err(CheatError::InvalidLength(n.len()));
+        }
+
+        let addr = 0x8000
+            | ((n[3] & 7) << 12)
+            | ((n[5] & 7) << 8)
+            | ((n[4] & 8) << 8)
+            | ((n[2] & 7) << 4)
+            | ((n[1] & 8) << 4)
+            | (n[4] & 7)
+            | (n[3] & 8);
+        let value = ((n[1] & 7) << 4) | ((n[0] & 8) << 4) | (n[0] & 7);
+
+        let cheat = if n.len() == 6 {
+            Cheat {
+                addr,
+                value: (value | (n[5] & 8)) as u8,
+                compare: None,
+            }
+        } else {
+            let compare = ((n[7] & 7) << 4) | ((n[6] & 8) << 4) | (n[6] & 7) | (n[5] & 8);
+            Cheat {
+                addr,
+                value: (value | (n[7] & 8)) as u8,
+                compare: Some(compare as u8),
+            }
+        };
+        Ok(cheat)
+    }
+
+    /// What the CPU sees at `addr` when the ROM holds `original` there.
+    pub fn apply(&self, addr: u16, original: u8) -> u8 {
+        if addr == self.addr && self.compare.is_none_or(|compare| compare == original) {
+            self.value
+        } else {
+            original
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        // Super Mario Bros.: infinite lives
+        assert_eq!(
+            Cheat::decode("SXIOPO"),
+            Ok(Cheat {
+                addr: 0x91d9,
+                value: 0xad,
+                compare: None
+            })
+        );
+        assert_eq!(
+            Cheat::decode("zexpygla"),
+            Ok(Cheat {
+                addr: 0x94a7,
+                value: 0x02,
+                compare: Some(0x03)
+            })
+        );
+        assert_eq!(Cheat::decode("SXIOP"), Err(CheatError::InvalidLength(5)));
+        assert_eq!(Cheat::decode("SXIOPB"), Err(CheatError::InvalidLetter('B')));
+    }
+
+    #[test]
+    fn test_apply_with_compare() {
+        let cheat = Cheat::decode("ZEXPYGLA").unwrap();
+        assert_eq!(cheat.apply(0x94a7, 0x03), 0x02);
+        assert_eq!(cheat.apply(0x94a7, 0x04), 0x04);
+        assert_eq!(cheat.apply(0x94a8, 0x03), 0x03);
+    }
+}
@@ -0,0 +1,130 @@
+//! Short animated GIF clips of gameplay.
+//!
+//! Every NES color comes from the 64 entry system palette, so that palette is the GIF's global
+//! color table and frames are stored as indices into it. Colors off the palette (emphasis,
+//! greyscale or frame blending) are mapped to the nearest entry.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::Path;
+
+use crate::render::{frame::Frame, palette::SYSTEM_PALLETE};
+
+const WIDTH: u16 = 256;
+const HEIGHT: u16 = 240;
+// Keep one frame in three: 60fps in, 20fps out
+const FRAME_STEP: usize = 3;
+// GIF delays are in hundredths of a second
+const FRAME_DELAY: u16 = 5;
+
+#[derive(Default)]
+pub struct ClipRecorder {
+    // Palette indices, one byte per pixel
+    frames: Vec<Vec<u8>>,
+    frames_seen: usize,
+    colors: HashMap<(u8, u8, u8), u8>,
+}
+
+impl ClipRecorder {
+    pub fn new() -> Self {
+        ClipRecorder::default()
+    }
+
+    /// Call with every emulated frame. Only every third one is kept.
+    pub fn push(&mut self, frame: &Frame) {
+        let keep = self.frames_seen.is_multiple_of(FRAME_STEP);
+        self.frames_seen += 1;
+        if !keep {
+            return;
+        }
+
+        let indices = frame
+            .rgb_bytes()
+            .chunks(3)
+            .map(|rgb| self.palette_index((rgb[0], rgb[1], rgb[2])))
+            .collect();
+        self.frames.push(indices);
+    }
+
+    /// Number of frames that will end up in the GIF.
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Writes the recorded frames as a looping GIF.
+    pub fn write_gif(&self, path: &Path) -> io::Result<()> {
+        let palette: Vec<u8> = SYSTEM_PALLETE
+            .iter()
+            .flat_map(|&(r, g, b)| [r, g, b])
+            .collect();
+        let file = BufWriter::new(File::create(path)?);
+        let mut encoder =
+            gif::Encoder::new(file, WIDTH, HEIGHT, &palette).map_err(io::Error::other)?;
+        encoder
+            .set_repeat(gif::Repeat::Infinite)
+            .map_err(io::Error::other)?;
+
+        for indices in &self.frames {
+            let frame = gif::Frame {
+                width: WIDTH,
+                height: HEIGHT,
+                delay: FRAME_DELAY,
+                buffer: indices.into(),
+                ..gif::Frame::default()
+            };
+            encoder.write_frame(&frame).map_err(io::Error::other)?;
+        }
+        Ok(())
+    }
+
+    fn palette_index(&mut self, rgb: (u8, u8, u8)) -> u8 {
+        *self.colors.entry(rgb).or_insert_with(|| {
+            let distance = |color: &(u8, u8, u8)| {
+                let d = |a: u8, b: u8| (a as i32 - b as i32).pow(2);
+                d(color.0, rgb.0) + d(color.1, rgb.1) + d(color.2, rgb.2)
+            };
+            (0..SYSTEM_PALLETE.len())
+                .min_by_key(|&i| distance(&SYSTEM_PALLETE[i]))
+                .unwrap() as u8
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_write_gif() {
+        let mut recorder = ClipRecorder::new();
+        for i in 0..9 {
+            let mut frame = Frame::new();
+            frame.set_pixel(i, 0, SYSTEM_PALLETE[0x16]);
+            recorder.push(&frame);
+        }
+        assert_eq!(recorder.len(), 3);
+
+        let path = std::env::temp_dir().join(format!("nes-clip-{}.gif", std::process::id()));
+        recorder.write_gif(&path).unwrap();
+
+        let mut options = gif::DecodeOptions::new();
+        options.set_color_output(gif::ColorOutput::Indexed);
+        let mut decoder = options.read_info(File::open(&path).unwrap()).unwrap();
+        assert_eq!((decoder.width(), decoder.height()), (WIDTH, HEIGHT));
+
+        let mut frames = 0;
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            // Frames 0, 3 and 6 were kept
+            assert_eq!(frame.buffer[frames * 3], 0x16);
+            assert_eq!(frame.delay, FRAME_DELAY);
+            frames += 1;
+        }
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(frames, 3);
+    }
+}
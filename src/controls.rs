@@ -0,0 +1,193 @@
+//! Keyboard bindings for the two joypads, loaded from a TOML file such as:
+//!
+//! ```toml
+//! [player1]
+//! Z = "A"
+//! X = "B"
+//! RShift = "Select"
+//! Return = "Start"
+//!
+//! [player2]
+//! Kp8 = "Up"
+//! ```
+//!
+//! Keys use SDL's keycode names (`Keycode`'s variants, like `Up`, `Space` or `Num1`) and
+//! buttons are A, B, Select, Start, Up, Down, Left and Right, both matched case-insensitively.
+//! A player without a section keeps the default bindings.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use lazy_static::lazy_static;
+use sdl2::keyboard::Keycode::{self, *};
+
+use crate::joypad::JoypadButton;
+
+pub type KeyMap = HashMap<Keycode, JoypadButton>;
+
+pub struct Controls {
+    pub player1: KeyMap,
+    pub player2: KeyMap,
+}
+
+impl Default for Controls {
+    fn default() -> Self {
+        Controls {
+            player1: HashMap::from([
+                (Keycode::Down, JoypadButton::DOWN),
+                (Keycode::Up, JoypadButton::UP),
+                (Keycode::Right, JoypadButton::RIGHT),
+                (Keycode::Left, JoypadButton::LEFT),
+                (Keycode::Space, JoypadButton::SELECT),
+                (Keycode::Return, JoypadButton::START),
+                (Keycode::A, JoypadButton::BUTTON_A),
+                (Keycode::S, JoypadButton::BUTTON_B),
+            ]),
+            player2: HashMap::from([
+                (Keycode::K, JoypadButton::DOWN),
+                (Keycode::I, JoypadButton::UP),
+                (Keycode::L, JoypadButton::RIGHT),
+                (Keycode::J, JoypadButton::LEFT),
+                (Keycode::U, JoypadButton::SELECT),
+                (Keycode::O, JoypadButton::START),
+                (Keycode::N, JoypadButton::BUTTON_A),
+                (Keycode::M, JoypadButton::BUTTON_B),
+            ]),
+        }
+    }
+}
+
+// Keys that can be bound. Function keys and Escape are left to the frontend
+#[rustfmt::skip]
+const BINDABLE_KEYS: &[Keycode] = &[
+    A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P, Q, R, S, T, U, V, W, X, Y, Z,
+    Num0, Num1, Num2, Num3, Num4, Num5, Num6, Num7, Num8, Num9,
+    Up, Down, Left, Right, Space, Return, Tab, Backspace,
+    LShift, RShift, LCtrl, RCtrl, LAlt, RAlt,
+    Comma, Period, Slash, Semicolon, Quote, LeftBracket, RightBracket, Backslash, Minus,
+    Equals, Backquote, Insert, Delete, Home, End, PageUp, PageDown,
+    Kp0, Kp1, Kp2, Kp3, Kp4, Kp5, Kp6, Kp7, Kp8, Kp9,
+    KpEnter, KpPlus, KpMinus, KpMultiply, KpDivide, KpPeriod,
+];
+
+lazy_static! {
+    // SDL can look keys up by name, but only once it is initialized
+    static ref KEYCODES_BY_NAME: HashMap<String, Keycode> = BINDABLE_KEYS
+        .iter()
+        .map(|&keycode| (format!("{:?}", keycode).to_lowercase(), keycode))
+        .collect();
+}
+
+/// Parses one `key = "button"` line.
+pub fn parse_binding(key: &str, button: &str) -> Result<(Keycode, JoypadButton), String> {
+    let keycode = *KEYCODES_BY_NAME
+        .get(&key.to_lowercase())
+        .ok_or_else(|| format!("Unknown key {:?}.", key))?;
+    let button = match button.to_lowercase().as_str() {
+        "a" => JoypadButton::BUTTON_A,
+        "b" => JoypadButton::BUTTON_B,
+        "select" => JoypadButton::SELECT,
+        "start" => JoypadButton::START,
+        "up" => JoypadButton::UP,
+        "down" => JoypadButton::DOWN,
+        "left" => JoypadButton::LEFT,
+        "right" => JoypadButton::RIGHT,
+        _ => return Err(format!("Unknown button {:?} for key {:?}.", button, key)),
+    };
+    Ok((keycode, button))
+}
+
+/// Parses a controls file. Bindings that can't be parsed are left out and reported in the
+/// returned list; only a file that isn't valid TOML is an error.
+pub fn parse_controls(text: &str) -> Result<(Controls, Vec<String>), String> {
+    let table: toml::Table = text.parse().map_err(|e| format!("{}", e))?;
+    let mut controls = Controls::default();
+    let mut problems = Vec::new();
+
+    for (section, bindings) in table {
+        let key_map = match section.as_str() {
+            "player1" => &mut controls.player1,
+            "player2" => &mut controls.player2,
+            _ => {
+                problems.push(format!("Unknown section [{}].", section));
+                continue;
+            }
+        };
+        let Some(bindings) = bindings.as_table() else {
+            problems.push(format!("{} should be a section.", section));
+            continue;
+        };
+
+        key_map.clear();
+        for (key, button) in bindings {
+            let parsed = match button.as_str() {
+                Some(button) => parse_binding(key, button),
+                None => Err(format!("Button for key {:?} should be a string.", key)),
+            };
+            match parsed {
+                Ok((keycode, button)) => {
+                    key_map.insert(keycode, button);
+                }
+                Err(e) => problems.push(e),
+            }
+        }
+    }
+    Ok((controls, problems))
+}
+
+/// Reads the controls file at `path`, with the default bindings if there is none.
+pub fn load_controls(path: &Path) -> Result<(Controls, Vec<String>), String> {
+    match std::fs::read_to_string(path) {
+        Ok(text) => parse_controls(&text),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok((Controls::default(), Vec::new())),
+        Err(e) => Err(format!("Failed to read {}: {}", path.display(), e)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_binding() {
+        assert_eq!(
+            parse_binding("Z", "A"),
+            Ok((Keycode::Z, JoypadButton::BUTTON_A))
+        );
+        assert_eq!(
+            parse_binding("rshift", "SELECT"),
+            Ok((Keycode::RShift, JoypadButton::SELECT))
+        );
+        assert_eq!(
+            parse_binding("Up", "up"),
+            Ok((Keycode::Up, JoypadButton::UP))
+        );
+        assert!(parse_binding("NoSuchKey", "A").is_err());
+        assert!(parse_binding("Z", "C").is_err());
+    }
+
+    #[test]
+    fn test_parse_controls() {
+        let (controls, problems) = parse_controls(
+            r#"
+            [player1]
+            Z = "A"
+            X = "turbo"
+
+            [player3]
+            Q = "B"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            controls.player1,
+            HashMap::from([(Keycode::Z, JoypadButton::BUTTON_A)])
+        );
+        // No section: the defaults
+        assert_eq!(controls.player2, Controls::default().player2);
+        assert_eq!(problems.len(), 2);
+
+        assert!(parse_controls("[player1").is_err());
+    }
+}
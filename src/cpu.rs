@@ -1,6 +1,14 @@
-use crate::{bus::Bus, opcodes};
+use crate::{
+    bus::Bus,
+    opcodes,
+    render::{self, frame::Frame},
+    rewind::Rewind,
+    trace::{self, Divergence},
+};
 use core::panic;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufRead};
 
 /// # Status Register (P) http://wiki.nesdev.com/w/index.php/Status_flags
 /// # unused flag(5) is always 1 because it's hardwired so.
@@ -14,7 +22,7 @@ use std::collections::HashMap;
 ///  | |   +----------- Break Command
 ///  | +--------------- Overflow Flag
 ///  +----------------- Negative Flag
-#[derive(Copy, Clone, Debug, Default)]
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
 pub struct Status {
     pub carry_flag: bool,
     pub zero_flag: bool,
@@ -54,9 +62,38 @@ impl Status {
     }
 }
 
+/// Why `CPU::run_until_break` returned.
+#[derive(Debug, PartialEq)]
+pub enum Stop {
+    /// About to execute the instruction at a breakpoint
+    Break(u16),
+    /// The last instruction accessed a watched address, see `Bus::add_read_watchpoint`
+    Watch(u16),
+    /// The program hit BRK
+    Brk,
+}
+
+pub use interrupt::InterruptType;
+
+/// What one `CPU::step_instruction` ran. Like the 6502 itself, an NMI or IRQ is taken as a BRK
+/// forced in place of the next instruction: `interrupt` is set, `opcode` is 0x00 and nothing
+/// was fetched from `addr`, which is the return address pushed for the handler.
+#[derive(Debug, PartialEq)]
+pub struct RetiredInstruction {
+    pub addr: u16,
+    pub opcode: u8,
+    /// 0 to 2 bytes following the opcode.
+    pub operand: Vec<u8>,
+    pub cycles: usize,
+    pub interrupt: Option<InterruptType>,
+}
+
 const STACK_BASE: u16 = 0x0100;
 const STACK_RESET: u8 = 0xfd;
+// What ANE ORs into A first. Real chips vary ($00, $EE, $FF...); $EE is the usual choice
+const ANE_MAGIC: u8 = 0xee;
 
+#[derive(Serialize, Deserialize)]
 pub struct CPU<'a> {
     pub register_a: u8,
     pub register_x: u8,
@@ -65,7 +102,20 @@ pub struct CPU<'a> {
     pub stack_pointer: u8,
     pub program_counter: u16,
     pub bus: Bus<'a>,
-    log: String,
+    effective_address: Option<u16>,
+    jammed: bool,
+    // CLI, SEI and PLP change the I flag only after the next instruction: the value IRQ polling
+    // still sees until then
+    irq_disable_pending: Option<bool>,
+    // BCD arithmetic when the D flag is set. Off on the NES, whose 2A03 lacks it
+    decimal_mode: bool,
+    #[serde(skip)]
+    breakpoints: HashSet<u16>,
+    #[serde(skip)]
+    rewind: Rewind,
+    // Rendered by `step_frame`
+    #[serde(skip, default = "Frame::new")]
+    frame: Frame,
 }
 
 pub trait Mem {
@@ -87,6 +137,19 @@ pub trait Mem {
     }
 }
 
+/// Reads memory the way a debugger would: no PPU latches are cleared, no open bus is updated
+/// and no cycles pass.
+pub trait Inspector {
+    fn inspect(&self, addr: u16) -> u8;
+
+    /// `len` bytes from `start`, e.g. for a hex dump. Addresses past $FFFF wrap to $0000.
+    fn inspect_range(&self, start: u16, len: usize) -> Vec<u8> {
+        (0..len)
+            .map(|i| self.inspect(start.wrapping_add(i as u16)))
+            .collect()
+    }
+}
+
 impl Mem for CPU<'_> {
     fn mem_read(&mut self, addr: u16) -> u8 {
         self.bus.mem_read(addr)
@@ -106,9 +169,11 @@ impl Mem for CPU<'_> {
 }
 
 mod interrupt {
-    #[derive(PartialEq, Eq)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    #[allow(clippy::upper_case_acronyms)]
     pub enum InterruptType {
         NMI,
+        IRQ,
     }
 
     #[derive(PartialEq, Eq)]
@@ -125,6 +190,13 @@ mod interrupt {
         b_flag_mask: 0b0010_0000,
         cpu_cycles: 2,
     };
+
+    pub(super) const IRQ: Interrupt = Interrupt {
+        itype: InterruptType::IRQ,
+        vector_addr: 0xfffe,
+        b_flag_mask: 0b0010_0000,
+        cpu_cycles: 2,
+    };
 }
 
 impl<'a> CPU<'a> {
@@ -137,10 +209,23 @@ impl<'a> CPU<'a> {
             stack_pointer: 0,
             program_counter: 0,
             bus,
-            log: String::new(),
+            effective_address: None,
+            jammed: false,
+            irq_disable_pending: None,
+            decimal_mode: false,
+            breakpoints: HashSet::new(),
+            rewind: Rewind::default(),
+            frame: Frame::new(),
         }
     }
 
+    /// Makes ADC and SBC honor the D flag like an NMOS 6502, for using this core outside the
+    /// NES. Off by default.
+    pub fn with_decimal_mode(mut self, enabled: bool) -> Self {
+        self.decimal_mode = enabled;
+        self
+    }
+
     fn lda(&mut self, mode: &AddressingMode) -> u8 {
         let (addr, extra_cycle) = self.get_operand_address(mode);
         let value = self.mem_read(addr);
@@ -228,6 +313,7 @@ impl<'a> CPU<'a> {
     }
 
     fn plp(&mut self) {
+        self.irq_disable_pending = Some(self.status.interrupt_disable_flag);
         self.status = Status::from_u8(self.stack_pop());
         self.status.break_command = false;
     }
@@ -279,6 +365,10 @@ impl<'a> CPU<'a> {
         let (addr, extra_cycle) = self.get_operand_address(mode);
 
         let rhs = self.mem_read(addr);
+        if self.decimal_mode && self.status.decimal_mode_flag {
+            self.adc_decimal(rhs);
+            return extra_cycle as u8;
+        }
         let lhs = self.register_a;
         let carry_in = self.status.carry_flag as u8;
 
@@ -304,6 +394,10 @@ impl<'a> CPU<'a> {
     fn sbc(&mut self, mode: &AddressingMode) -> u8 {
         let (addr, extra_cycle) = self.get_operand_address(mode);
         let rhs = self.mem_read(addr);
+        if self.decimal_mode && self.status.decimal_mode_flag {
+            self.sbc_decimal(rhs);
+            return extra_cycle as u8;
+        }
         let lhs = self.register_a;
         let carry_in = self.status.carry_flag as u8;
 
@@ -325,6 +419,52 @@ impl<'a> CPU<'a> {
         extra_cycle as u8
     }
 
+    // NMOS 6502 BCD addition. Z comes from the binary sum, N and V from the sum with only the
+    // low digit adjusted, C and the result from the fully adjusted sum.
+    fn adc_decimal(&mut self, rhs: u8) {
+        let lhs = self.register_a;
+        let carry_in = self.status.carry_flag as u16;
+        let binary = lhs as u16 + rhs as u16 + carry_in;
+
+        let mut low = (lhs & 0x0f) as u16 + (rhs & 0x0f) as u16 + carry_in;
+        if low >= 0x0a {
+            low = ((low + 0x06) & 0x0f) + 0x10;
+        }
+        let mut sum = (lhs & 0xf0) as u16 + (rhs & 0xf0) as u16 + low;
+        let signed_sum = (lhs & 0xf0) as i8 as i16 + (rhs & 0xf0) as i8 as i16 + low as i16;
+
+        self.status.zero_flag = binary & 0xff == 0;
+        self.status.negative_flag = sum & 0x80 != 0;
+        self.status.overflow_flag = !(-128..=127).contains(&signed_sum);
+        if sum >= 0xa0 {
+            sum += 0x60;
+        }
+        self.status.carry_flag = sum >= 0x100;
+        self.register_a = sum as u8;
+    }
+
+    // NMOS 6502 BCD subtraction. The flags are the same as for binary SBC.
+    fn sbc_decimal(&mut self, rhs: u8) {
+        let lhs = self.register_a;
+        let carry_in = self.status.carry_flag as i16;
+        let binary = lhs as i16 - rhs as i16 - (1 - carry_in);
+
+        let mut low = (lhs & 0x0f) as i16 - (rhs & 0x0f) as i16 + carry_in - 1;
+        if low < 0 {
+            low = ((low - 0x06) & 0x0f) - 0x10;
+        }
+        let mut difference = (lhs & 0xf0) as i16 - (rhs & 0xf0) as i16 + low;
+        if difference < 0 {
+            difference -= 0x60;
+        }
+
+        let result = binary as u8;
+        self.status.carry_flag = binary >= 0;
+        self.status.overflow_flag = (lhs ^ rhs) & (lhs ^ result) & 0x80 != 0;
+        self.update_zero_and_negative_flags(result);
+        self.register_a = difference as u8;
+    }
+
     fn compare(&mut self, mode: &AddressingMode, with: u8) -> u8 {
         let (addr, extra_cycle) = self.get_operand_address(mode);
         let value = self.mem_read(addr);
@@ -447,17 +587,9 @@ impl<'a> CPU<'a> {
         self.update_zero_and_negative_flags(result);
     }
 
-    fn jmp(&mut self, mode: &AddressingMode) -> u8 {
-        let mut extra_cycles = 0;
+    fn jmp(&mut self, mode: &AddressingMode) {
         let (addr, _) = self.get_operand_address(mode);
-
-        if self.program_counter & 0xFF00 != addr & 0xFF00 {
-            extra_cycles += 2;
-        }
-
         self.program_counter = addr;
-
-        return extra_cycles;
     }
 
     fn jsr(&mut self, mode: &AddressingMode) {
@@ -476,13 +608,11 @@ impl<'a> CPU<'a> {
         if condition {
             addnl_cycles += 1;
             let offset = self.mem_read(self.program_counter) as i8;
-            let jump_addr = self
-                .program_counter
-                .wrapping_add(1)
-                .wrapping_add(offset as u16);
+            let next_addr = self.program_counter.wrapping_add(1);
+            let jump_addr = next_addr.wrapping_add(offset as u16);
 
-            if self.program_counter & 0xFF00 != jump_addr & 0xFF00 {
-                addnl_cycles += 2;
+            if page_crossed(next_addr, jump_addr) {
+                addnl_cycles += 1;
             }
 
             self.program_counter = jump_addr;
@@ -498,6 +628,56 @@ impl<'a> CPU<'a> {
         self.program_counter = self.stack_pop_u16();
     }
 
+    fn kil(&mut self) {
+        // Stay on the opcode so every further step executes it again
+        self.jammed = true;
+        self.program_counter -= 1;
+    }
+
+    fn ane(&mut self, mode: &AddressingMode) {
+        let (addr, _) = self.get_operand_address(mode);
+        let value = self.mem_read(addr);
+
+        self.register_a = (self.register_a | ANE_MAGIC) & self.register_x & value;
+        self.update_zero_and_negative_flags(self.register_a);
+    }
+
+    // SHA and TAS AND the stored value with the high byte of the address plus one. When the
+    // index crosses a page, that value also replaces the high byte of the address written to
+    fn store_and_high_byte(&mut self, mode: &AddressingMode, value: u8) {
+        let (addr, page_crossed) = self.get_operand_address(mode);
+        let base = addr.wrapping_sub(self.register_y as u16);
+        let value = value & ((base >> 8) as u8).wrapping_add(1);
+
+        let addr = if page_crossed {
+            (value as u16) << 8 | addr & 0x00ff
+        } else {
+            addr
+        };
+        self.effective_address = Some(addr);
+        self.mem_write(addr, value);
+    }
+
+    fn sha(&mut self, mode: &AddressingMode) {
+        self.store_and_high_byte(mode, self.register_a & self.register_x);
+    }
+
+    fn tas(&mut self, mode: &AddressingMode) {
+        self.stack_pointer = self.register_a & self.register_x;
+        self.store_and_high_byte(mode, self.stack_pointer);
+    }
+
+    fn las(&mut self, mode: &AddressingMode) -> u8 {
+        let (addr, extra_cycle) = self.get_operand_address(mode);
+        let value = self.mem_read(addr) & self.stack_pointer;
+
+        self.register_a = value;
+        self.register_x = value;
+        self.stack_pointer = value;
+        self.update_zero_and_negative_flags(value);
+        extra_cycle as u8
+    }
+
     fn update_zero_and_negative_flags(&mut self, result: u8) {
         if result == 0 {
             self.status.zero_flag = true;
@@ -543,6 +723,75 @@ impl<'a> CPU<'a> {
         self.status = Status::from_u8(0b0010_0100);
         self.stack_pointer = STACK_RESET;
         self.program_counter = self.mem_read_u16(0xFFFC);
+        self.jammed = false;
+        self.irq_disable_pending = None;
+    }
+
+    /// `reset`, but starting at `pc` instead of the reset vector, for test ROMs such as
+    /// nestest that are run from a fixed address. `Bus::seed_ram` sets the initial RAM.
+    pub fn reset_to(&mut self, pc: u16) {
+        self.reset();
+        self.program_counter = pc;
+    }
+
+    /// Hard reset: rebuilds the bus to its power-on state (see `Bus::power_cycle`), clears
+    /// the registers and then runs the reset sequence. `reset` alone is the soft reset,
+    /// which keeps RAM and PPU state.
+    pub fn power_cycle(&mut self) {
+        self.bus.power_cycle();
+        self.register_y = 0;
+        self.reset();
+    }
+
+    /// Snapshot of the whole machine (CPU, RAM, PPU, controllers and cartridge state) for
+    /// save states. The gameloop callback, input poll and cartridge ROM are not part of it;
+    /// the ROM's CRC-32 goes first so the state can only be loaded back into the same game.
+    pub fn save_state(&self) -> Vec<u8> {
+        bincode::serialize(&(self.bus.rom_crc(), self, self.bus.mapper_state())).unwrap()
+    }
+
+    /// Restores a snapshot from `save_state`, keeping this CPU's callbacks, rewind buffer
+    /// and cartridge ROM. States saved with a different ROM are rejected. On error nothing
+    /// is changed.
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), String> {
+        let (rom_crc, mut cpu, mapper_state): (u32, CPU, Vec<u8>) =
+            bincode::deserialize(state).map_err(|e| format!("Invalid save state: {}", e))?;
+        if rom_crc != self.bus.rom_crc() {
+            return Err(format!(
+                "Save state is for a ROM with CRC {:08x} but the loaded ROM is {:08x}.",
+                rom_crc,
+                self.bus.rom_crc()
+            ));
+        }
+        self.bus.load_mapper_state(&mapper_state)?;
+        cpu.bus.reconnect(&mut self.bus);
+        cpu.rewind = std::mem::take(&mut self.rewind);
+        cpu.breakpoints = std::mem::take(&mut self.breakpoints);
+        *self = cpu;
+        Ok(())
+    }
+
+    /// Keeps the last `frames` snapshots pushed with `push_rewind_frame`.
+    pub fn enable_rewind(&mut self, frames: usize) {
+        self.rewind = Rewind::new(frames);
+    }
+
+    /// Records the current state in the rewind buffer. Call once per frame.
+    pub fn push_rewind_frame(&mut self) {
+        let state = self.save_state();
+        self.rewind.push(&state);
+    }
+
+    /// Goes back to the most recent state in the rewind buffer and drops it from the buffer.
+    /// Returns `false` when there is nothing left to rewind to.
+    pub fn rewind_one(&mut self) -> bool {
+        match self.rewind.pop() {
+            Some(state) => {
+                self.load_state(&state).unwrap();
+                true
+            }
+            None => false,
+        }
     }
 
     pub fn run(&mut self) {
@@ -550,279 +799,573 @@ impl<'a> CPU<'a> {
     }
 
     pub fn run_with_callback(&mut self, mut callback: impl FnMut(&mut CPU)) {
-        let ref opcode_table: HashMap<u8, &'static opcodes::OpCode> = *opcodes::OPCODES_MAP;
+        loop {
+            self.poll_interrupts();
+
+            callback(self);
+
+            if !self.execute_instruction() {
+                return;
+            }
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.insert(addr);
+    }
 
+    pub fn remove_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.remove(&addr);
+    }
+
+    /// Runs until the PC reaches a breakpoint, an instruction accesses a watched address or
+    /// the program hits BRK. A breakpoint stops before its instruction executes; calling this
+    /// again from there executes it instead of stopping at once.
+    pub fn run_until_break(&mut self) -> Stop {
+        let mut resume_at = Some(self.program_counter);
         loop {
-            if let Some(_nmi) = self.bus.poll_nmi_status() {
-                self.interrupt(interrupt::NMI);
+            self.poll_interrupts();
+
+            if resume_at.take() != Some(self.program_counter)
+                && self.breakpoints.contains(&self.program_counter)
+            {
+                return Stop::Break(self.program_counter);
             }
 
-            callback(self);
+            self.bus.take_watch_hit();
+            if !self.execute_instruction() {
+                return Stop::Brk;
+            }
+            if let Some(addr) = self.bus.take_watch_hit() {
+                return Stop::Watch(addr);
+            }
+        }
+    }
 
-            let code = self.mem_read(self.program_counter);
-            let opcode = opcode_table
-                .get(&code)
-                .expect(&format!("OpCode {:x} is not recognized", code));
-            let mut extra_cycles = 0;
+    /// Runs like `run`, tracing every instruction and comparing it with the next line of a
+    /// reference log such as nestest.log. Stops at the first line that differs and returns
+    /// it; `None` means the whole log matched. Columns the log doesn't have (like FR) are
+    /// ignored, so a nestest.log line matches as long as everything up to CYC agrees.
+    pub fn run_compare(&mut self, expected: impl BufRead) -> io::Result<Option<Divergence>> {
+        let mut stopped = false;
+        for (i, expected) in expected.lines().enumerate() {
+            let expected = expected?;
+            let actual = if stopped {
+                String::new()
+            } else {
+                self.poll_interrupts();
+                trace::trace(self)
+            };
+
+            if stopped || !trace::trace_matches(&actual, &expected) {
+                return Ok(Some(Divergence {
+                    line: i + 1,
+                    expected,
+                    actual,
+                }));
+            }
+            stopped = !self.execute_instruction();
+        }
+        Ok(None)
+    }
 
-            self.log
-                .push_str(&format!("{}({:x}) ", opcode.mnemonic, &opcode.code));
+    /// Memory address the last executed instruction read from, wrote to or jumped to, e.g.
+    /// $0305 for `STA $0300,X` with X = 5. `None` for implied, accumulator, immediate and
+    /// relative (branch) operands.
+    pub fn current_effective_address(&self) -> Option<u16> {
+        self.effective_address
+    }
 
-            self.program_counter += 1;
-            let last_program_counter = self.program_counter;
+    /// Services a pending interrupt and executes one instruction.
+    /// Returns `false` when the instruction was BRK, where `run` would stop.
+    pub fn step(&mut self) -> bool {
+        self.poll_interrupts();
+        self.execute_instruction()
+    }
 
-            match code {
-                // LDA
-                0xA9 | 0xa5 | 0xb5 | 0xad | 0xbd | 0xb9 | 0xa1 | 0xb1 => {
-                    extra_cycles = self.lda(&opcode.mode);
-                }
+    /// Runs until the PPU finishes the current frame and returns it rendered. This is the
+    /// headless way to drive the emulator, without a gameloop callback: poll input and
+    /// present the frame between calls. Returns `None` if the program hits BRK first.
+    pub fn step_frame(&mut self) -> Option<&Frame> {
+        if !self.run_one_frame() {
+            return None;
+        }
+        render::render(self.bus.ppu(), &mut self.frame);
+        Some(&self.frame)
+    }
+
+    /// Runs exactly one instruction, or takes a pending interrupt instead, and reports what
+    /// ran. The building block for a step debugger.
+    pub fn step_instruction(&mut self) -> RetiredInstruction {
+        let cycles_before = self.bus.cycles;
+        let addr = self.program_counter;
+        if let Some(interrupt) = self.poll_interrupts() {
+            return RetiredInstruction {
+                addr,
+                opcode: 0x00,
+                operand: Vec::new(),
+                cycles: self.bus.cycles - cycles_before,
+                interrupt: Some(interrupt),
+            };
+        }
 
-                // LDX
-                0xa2 | 0xa6 | 0xb6 | 0xae | 0xbe => {
-                    extra_cycles = self.ldx(&opcode.mode);
-                }
+        let opcode = self.bus.inspect(addr);
+        let len = opcodes::OPCODES_MAP.get(&opcode).map_or(1, |op| op.len);
+        let operand = (1..len as u16)
+            .map(|i| self.bus.inspect(addr.wrapping_add(i)))
+            .collect();
+        self.execute_instruction();
+        RetiredInstruction {
+            addr,
+            opcode,
+            operand,
+            cycles: self.bus.cycles - cycles_before,
+            interrupt: None,
+        }
+    }
 
-                // LDY
-                0xa0 | 0xa4 | 0xb4 | 0xac | 0xbc => {
-                    extra_cycles = self.ldy(&opcode.mode);
-                }
+    /// Runs whole instructions until the PPU next enters vblank, i.e. advances one frame. For
+    /// frame-by-frame stepping while paused; the CPU is always left between instructions.
+    /// Returns false if the program hits BRK first.
+    pub fn run_one_frame(&mut self) -> bool {
+        // A frame finished by plain `step` calls since the last frame step doesn't count
+        self.bus.take_frame_ready();
+        while !self.bus.take_frame_ready() {
+            if !self.step() {
+                return false;
+            }
+        }
+        true
+    }
 
-                // STA
-                0x85 | 0x95 | 0x8d | 0x9d | 0x99 | 0x81 | 0x91 => {
-                    self.sta(&opcode.mode);
-                }
+    /// Like `step`, but a JSR is executed together with the whole subroutine: runs until the
+    /// stack pointer is back to its value before the call, which skips over nested calls and
+    /// interrupts taken inside the subroutine. A pending interrupt is taken first, as in
+    /// `step`, so the instruction looked at is the first one of its handler.
+    pub fn step_over(&mut self) -> bool {
+        self.poll_interrupts();
+        if self.bus.inspect(self.program_counter) != 0x20 {
+            return self.execute_instruction();
+        }
 
-                // STX
-                0x86 | 0x96 | 0x8e => {
-                    self.stx(&opcode.mode);
-                }
+        let stack_pointer = self.stack_pointer;
+        if !self.execute_instruction() {
+            return false;
+        }
+        // A KIL in the subroutine means it never returns
+        while self.stack_pointer != stack_pointer && !self.jammed {
+            if !self.step() {
+                return false;
+            }
+        }
+        true
+    }
 
-                // STY
-                0x84 | 0x94 | 0x8c => {
-                    self.sty(&opcode.mode);
-                }
+    /// Runs until the current subroutine returns, i.e. until an RTS pops the stack above
+    /// where it is now. The PC is then right after the JSR that called it. Stops early if a
+    /// KIL jams the CPU, see `is_jammed`.
+    pub fn step_out(&mut self) -> bool {
+        let stack_pointer = self.stack_pointer;
+        loop {
+            self.poll_interrupts();
+            let is_rts = self.bus.inspect(self.program_counter) == 0x60;
+            if !self.execute_instruction() {
+                return false;
+            }
+            if self.jammed || (is_rts && self.stack_pointer > stack_pointer) {
+                return true;
+            }
+        }
+    }
 
-                // TAX
-                0xAA => self.tax(),
-                // TAY
-                0xa8 => self.tay(),
-                // TXA
-                0x8a => self.txa(),
-                // TYA
-                0x98 => self.tya(),
-
-                // TSX
-                0xba => self.tsx(),
-                // TXS
-                0x9a => self.txs(),
-                // PHA
-                0x48 => self.pha(),
-                // PHP
-                0x08 => self.php(),
-                // PLA
-                0x68 => self.pla(),
-                // PLP
-                0x28 => self.plp(),
-
-                // AND
-                0x29 | 0x25 | 0x35 | 0x2D | 0x3D | 0x39 | 0x21 | 0x31 => {
-                    extra_cycles = self.and(&opcode.mode);
-                }
+    /// CPU cycles run since the CPU was created, including OAM DMA stalls. Neither `reset`,
+    /// `power_cycle` nor loading a state moves it back, so it can time benchmarks.
+    pub fn total_cycles(&self) -> u64 {
+        self.bus.total_cycles()
+    }
 
-                // EOR
-                0x49 | 0x45 | 0x55 | 0x4D | 0x5D | 0x59 | 0x41 | 0x51 => {
-                    extra_cycles = self.eor(&opcode.mode);
-                }
+    /// Whether a KIL opcode has locked up the CPU. Only `reset` gets it going again.
+    pub fn is_jammed(&self) -> bool {
+        self.jammed
+    }
 
-                // ORA
-                0x09 | 0x05 | 0x15 | 0x0D | 0x1D | 0x19 | 0x01 | 0x11 => {
-                    extra_cycles = self.ora(&opcode.mode);
-                }
+    // The interrupt taken, if any
+    fn poll_interrupts(&mut self) -> Option<InterruptType> {
+        // A jammed CPU doesn't respond to interrupts either
+        if self.jammed {
+            return None;
+        }
+        // RTI restores the flag immediately, so only CLI, SEI and PLP leave a pending value
+        let irq_disabled = self
+            .irq_disable_pending
+            .take()
+            .unwrap_or(self.status.interrupt_disable_flag);
+        let taken = if self.bus.poll_nmi_status().is_some() {
+            interrupt::NMI
+        } else if self.bus.poll_irq_status() && !irq_disabled {
+            interrupt::IRQ
+        } else {
+            return None;
+        };
+        let itype = taken.itype;
+        self.interrupt(taken);
+        Some(itype)
+    }
 
-                // BIT
-                0x24 | 0x2C => {
-                    self.bit(&opcode.mode);
-                }
+    fn execute_instruction(&mut self) -> bool {
+        let ref opcode_table: HashMap<u8, &'static opcodes::OpCode> = *opcodes::OPCODES_MAP;
 
-                // ADC
-                0x69 | 0x65 | 0x75 | 0x6D | 0x7D | 0x79 | 0x61 | 0x71 => {
-                    extra_cycles = self.adc(&opcode.mode);
-                }
+        let code = self.mem_read(self.program_counter);
+        let opcode = opcode_table
+            .get(&code)
+            .expect(&format!("OpCode {:x} is not recognized", code));
+        let mut extra_cycles = 0;
+        self.effective_address = None;
 
-                // SBC
-                0xE9 | 0xE5 | 0xF5 | 0xED | 0xFD | 0xF9 | 0xE1 | 0xF1 => {
-                    extra_cycles = self.sbc(&opcode.mode);
-                }
+        #[cfg(feature = "cycle-check")]
+        let (expected_cycles, cycles_before) = (self.expected_cycles(opcode), self.bus.cycles);
 
-                // CMP
-                0xc9 | 0xc5 | 0xd5 | 0xcd | 0xdd | 0xd9 | 0xc1 | 0xd1 => {
-                    extra_cycles = self.compare(&opcode.mode, self.register_a);
-                }
+        self.program_counter += 1;
+        let last_program_counter = self.program_counter;
 
-                // CPX
-                0xe0 | 0xe4 | 0xec => {
-                    extra_cycles = self.compare(&opcode.mode, self.register_x);
-                }
+        match code {
+            // LDA
+            0xA9 | 0xa5 | 0xb5 | 0xad | 0xbd | 0xb9 | 0xa1 | 0xb1 => {
+                extra_cycles = self.lda(&opcode.mode);
+            }
 
-                // CPY
-                0xc0 | 0xc4 | 0xcc => {
-                    extra_cycles = self.compare(&opcode.mode, self.register_y);
-                }
+            // LDX
+            0xa2 | 0xa6 | 0xb6 | 0xae | 0xbe => {
+                extra_cycles = self.ldx(&opcode.mode);
+            }
 
-                // INC
-                0xe6 | 0xf6 | 0xee | 0xfe => {
-                    self.inc(&opcode.mode);
-                }
+            // LDY
+            0xa0 | 0xa4 | 0xb4 | 0xac | 0xbc => {
+                extra_cycles = self.ldy(&opcode.mode);
+            }
 
-                // INX
-                0xE8 => self.inx(),
+            // STA
+            0x85 | 0x95 | 0x8d | 0x9d | 0x99 | 0x81 | 0x91 => {
+                self.sta(&opcode.mode);
+            }
 
-                // INY
-                0xC8 => self.iny(),
+            // STX
+            0x86 | 0x96 | 0x8e => {
+                self.stx(&opcode.mode);
+            }
 
-                // DEC
-                0xc6 | 0xd6 | 0xce | 0xde => {
-                    self.dec(&opcode.mode);
-                }
+            // STY
+            0x84 | 0x94 | 0x8c => {
+                self.sty(&opcode.mode);
+            }
 
-                // DEX
-                0xca => self.dex(),
+            // TAX
+            0xAA => self.tax(),
+            // TAY
+            0xa8 => self.tay(),
+            // TXA
+            0x8a => self.txa(),
+            // TYA
+            0x98 => self.tya(),
+
+            // TSX
+            0xba => self.tsx(),
+            // TXS
+            0x9a => self.txs(),
+            // PHA
+            0x48 => self.pha(),
+            // PHP
+            0x08 => self.php(),
+            // PLA
+            0x68 => self.pla(),
+            // PLP
+            0x28 => self.plp(),
+
+            // AND
+            0x29 | 0x25 | 0x35 | 0x2D | 0x3D | 0x39 | 0x21 | 0x31 => {
+                extra_cycles = self.and(&opcode.mode);
+            }
 
-                // DEY
-                0x88 => self.dey(),
+            // EOR
+            0x49 | 0x45 | 0x55 | 0x4D | 0x5D | 0x59 | 0x41 | 0x51 => {
+                extra_cycles = self.eor(&opcode.mode);
+            }
 
-                // ASL accumulator
-                0x0A => {
-                    self.asl_accumulator();
-                }
-                // ASL
-                0x06 | 0x16 | 0x0E | 0x1E => {
-                    self.asl(&opcode.mode);
-                }
+            // ORA
+            0x09 | 0x05 | 0x15 | 0x0D | 0x1D | 0x19 | 0x01 | 0x11 => {
+                extra_cycles = self.ora(&opcode.mode);
+            }
 
-                // LSR accumulator
-                0x4a => {
-                    self.lsr_accumulator();
-                }
-                // LSR
-                0x46 | 0x56 | 0x4e | 0x5e => {
-                    self.lsr(&opcode.mode);
-                }
+            // BIT
+            0x24 | 0x2C => {
+                self.bit(&opcode.mode);
+            }
 
-                // ROL accumulator
-                0x2a => self.rol_accumulator(),
-                // ROL
-                0x26 | 0x36 | 0x2e | 0x3e | 0x22 | 0x32 => {
-                    self.rol(&opcode.mode);
-                }
+            // ADC
+            0x69 | 0x65 | 0x75 | 0x6D | 0x7D | 0x79 | 0x61 | 0x71 => {
+                extra_cycles = self.adc(&opcode.mode);
+            }
 
-                // ROR accumulator
-                0x6a => self.ror_accumulator(),
-                // ROR
-                0x66 | 0x76 | 0x6e | 0x7e | 0x62 | 0x72 => {
-                    self.ror(&opcode.mode);
-                }
+            // SBC
+            0xE9 | 0xE5 | 0xF5 | 0xED | 0xFD | 0xF9 | 0xE1 | 0xF1 => {
+                extra_cycles = self.sbc(&opcode.mode);
+            }
 
-                // JMP
-                0x4c | 0x6c => {
-                    extra_cycles = self.jmp(&opcode.mode);
-                }
+            // CMP
+            0xc9 | 0xc5 | 0xd5 | 0xcd | 0xdd | 0xd9 | 0xc1 | 0xd1 => {
+                extra_cycles = self.compare(&opcode.mode, self.register_a);
+            }
 
-                // JSR
-                0x20 => {
-                    self.jsr(&opcode.mode);
-                }
+            // CPX
+            0xe0 | 0xe4 | 0xec => {
+                extra_cycles = self.compare(&opcode.mode, self.register_x);
+            }
 
-                // RTS
-                0x60 => {
-                    self.rts();
-                }
+            // CPY
+            0xc0 | 0xc4 | 0xcc => {
+                extra_cycles = self.compare(&opcode.mode, self.register_y);
+            }
 
-                // BCC
-                0x90 => {
-                    extra_cycles = self.branch(!self.status.carry_flag);
-                }
-                // BCS
-                0xB0 => {
-                    extra_cycles = self.branch(self.status.carry_flag);
-                }
-                // BEQ
-                0xF0 => {
-                    extra_cycles = self.branch(self.status.zero_flag);
-                }
-                // BMI
-                0x30 => {
-                    extra_cycles = self.branch(self.status.negative_flag);
-                }
-                // BNE
-                0xd0 => {
-                    extra_cycles = self.branch(!self.status.zero_flag);
-                }
-                // BPL
-                0x10 => {
-                    extra_cycles = self.branch(!self.status.negative_flag);
-                }
-                // BVC
-                0x50 => {
-                    extra_cycles = self.branch(!self.status.overflow_flag);
-                }
-                // BVS
-                0x70 => {
-                    extra_cycles = self.branch(self.status.overflow_flag);
-                }
+            // INC
+            0xe6 | 0xf6 | 0xee | 0xfe => {
+                self.inc(&opcode.mode);
+            }
 
-                // CLC
-                0x18 => {
-                    self.status.carry_flag = false;
-                }
-                0xd8 => {
-                    self.status.decimal_mode_flag = false;
-                }
-                // CLI
-                0x58 => {
-                    self.status.interrupt_disable_flag = false;
-                }
-                // CLV
-                0xB8 => {
-                    self.status.overflow_flag = false;
-                }
+            // INX
+            0xE8 => self.inx(),
 
-                // SEC
-                0x38 => {
-                    self.status.carry_flag = true;
-                }
-                // SED
-                0xF8 => {
-                    self.status.decimal_mode_flag = true;
-                }
-                // SEI
-                0x78 => {
-                    self.status.interrupt_disable_flag = true;
-                }
+            // INY
+            0xC8 => self.iny(),
+
+            // DEC
+            0xc6 | 0xd6 | 0xce | 0xde => {
+                self.dec(&opcode.mode);
+            }
+
+            // DEX
+            0xca => self.dex(),
+
+            // DEY
+            0x88 => self.dey(),
+
+            // ASL accumulator
+            0x0A => {
+                self.asl_accumulator();
+            }
+            // ASL
+            0x06 | 0x16 | 0x0E | 0x1E => {
+                self.asl(&opcode.mode);
+            }
+
+            // LSR accumulator
+            0x4a => {
+                self.lsr_accumulator();
+            }
+            // LSR
+            0x46 | 0x56 | 0x4e | 0x5e => {
+                self.lsr(&opcode.mode);
+            }
+
+            // ROL accumulator
+            0x2a => self.rol_accumulator(),
+            // ROL
+            0x26 | 0x36 | 0x2e | 0x3e => {
+                self.rol(&opcode.mode);
+            }
+
+            // ROR accumulator
+            0x6a => self.ror_accumulator(),
+            // ROR
+            0x66 | 0x76 | 0x6e | 0x7e => {
+                self.ror(&opcode.mode);
+            }
+
+            // JMP
+            0x4c | 0x6c => {
+                self.jmp(&opcode.mode);
+            }
+
+            // JSR
+            0x20 => {
+                self.jsr(&opcode.mode);
+            }
+
+            // RTS
+            0x60 => {
+                self.rts();
+            }
+
+            // BCC
+            0x90 => {
+                extra_cycles = self.branch(!self.status.carry_flag);
+            }
+            // BCS
+            0xB0 => {
+                extra_cycles = self.branch(self.status.carry_flag);
+            }
+            // BEQ
+            0xF0 => {
+                extra_cycles = self.branch(self.status.zero_flag);
+            }
+            // BMI
+            0x30 => {
+                extra_cycles = self.branch(self.status.negative_flag);
+            }
+            // BNE
+            0xd0 => {
+                extra_cycles = self.branch(!self.status.zero_flag);
+            }
+            // BPL
+            0x10 => {
+                extra_cycles = self.branch(!self.status.negative_flag);
+            }
+            // BVC
+            0x50 => {
+                extra_cycles = self.branch(!self.status.overflow_flag);
+            }
+            // BVS
+            0x70 => {
+                extra_cycles = self.branch(self.status.overflow_flag);
+            }
 
-                // BRK
-                0x00 => return,
-                // NOP
-                0xea => (),
-                // RTI
-                0x40 => self.rti(),
+            // CLC
+            0x18 => {
+                self.status.carry_flag = false;
+            }
+            0xd8 => {
+                self.status.decimal_mode_flag = false;
+            }
+            // CLI
+            0x58 => {
+                self.irq_disable_pending = Some(self.status.interrupt_disable_flag);
+                self.status.interrupt_disable_flag = false;
+            }
+            // CLV
+            0xB8 => {
+                self.status.overflow_flag = false;
+            }
 
-                _ => todo!(),
+            // SEC
+            0x38 => {
+                self.status.carry_flag = true;
+            }
+            // SED
+            0xF8 => {
+                self.status.decimal_mode_flag = true;
+            }
+            // SEI
+            0x78 => {
+                self.irq_disable_pending = Some(self.status.interrupt_disable_flag);
+                self.status.interrupt_disable_flag = true;
             }
 
-            self.bus.tick(opcode.cycles + extra_cycles);
+            // BRK
+            0x00 => return false,
+            // NOP
+            0xea => (),
+            // RTI
+            0x40 => self.rti(),
 
-            // If not jump or branch occured
-            if last_program_counter == self.program_counter {
-                self.program_counter += (opcode.len - 1) as u16;
+            // KIL
+            0x02 | 0x12 | 0x22 | 0x32 | 0x42 | 0x52 | 0x62 | 0x72 | 0x92 | 0xb2 | 0xd2 | 0xf2 => {
+                self.kil()
+            }
+            // ANE
+            0x8b => self.ane(&opcode.mode),
+            // SHA
+            0x9f | 0x93 => self.sha(&opcode.mode),
+            // TAS
+            0x9b => self.tas(&opcode.mode),
+            // LAS
+            0xbb => {
+                extra_cycles = self.las(&opcode.mode);
             }
+
+            _ => todo!(),
         }
+
+        self.bus.tick(opcode.cycles + extra_cycles);
+
+        #[cfg(feature = "cycle-check")]
+        assert_eq!(
+            self.bus.cycles - cycles_before,
+            expected_cycles as usize,
+            "cycle count mismatch for {} ({:02x})",
+            opcode.mnemonic,
+            opcode.code
+        );
+
+        // A write to $4014 halts the CPU only after the instruction is done
+        self.bus.run_oam_dma();
+
+        // If not jump or branch occured
+        if last_program_counter == self.program_counter {
+            self.program_counter += (opcode.len - 1) as u16;
+        }
+
+        true
+    }
+
+    /// Cycles the instruction at PC should take: the opcode table value plus the documented
+    /// penalties (+1 for a page-crossing indexed read, +1 for a taken branch, +1 more if the
+    /// branch crosses a page). Computed independently of the instruction implementations so
+    /// the `cycle-check` feature can compare the two. Call before the instruction executes.
+    /// Operands are read with `inspect`, so the check has no side effects on the bus.
+    #[cfg(any(test, feature = "cycle-check"))]
+    fn expected_cycles(&self, opcode: &opcodes::OpCode) -> u8 {
+        let operand = self.program_counter.wrapping_add(1);
+
+        let penalty = match opcode.mnemonic {
+            "LDA" | "LDX" | "LDY" | "AND" | "EOR" | "ORA" | "ADC" | "SBC" | "CMP" | "LAS" => {
+                let base = match opcode.mode {
+                    AddressingMode::Absolute_X | AddressingMode::Absolute_Y => {
+                        let lo = self.bus.inspect(operand) as u16;
+                        let hi = self.bus.inspect(operand.wrapping_add(1)) as u16;
+                        hi << 8 | lo
+                    }
+                    AddressingMode::Indirect_Y => {
+                        let ptr = self.bus.inspect(operand);
+                        let lo = self.bus.inspect(ptr as u16) as u16;
+                        let hi = self.bus.inspect(ptr.wrapping_add(1) as u16) as u16;
+                        hi << 8 | lo
+                    }
+                    _ => return opcode.cycles,
+                };
+                let index = match opcode.mode {
+                    AddressingMode::Absolute_X => self.register_x,
+                    _ => self.register_y,
+                };
+                page_crossed(base, base.wrapping_add(index as u16)) as u8
+            }
+            "BCC" | "BCS" | "BEQ" | "BMI" | "BNE" | "BPL" | "BVC" | "BVS" => {
+                let taken = match opcode.mnemonic {
+                    "BCC" => !self.status.carry_flag,
+                    "BCS" => self.status.carry_flag,
+                    "BEQ" => self.status.zero_flag,
+                    "BNE" => !self.status.zero_flag,
+                    "BMI" => self.status.negative_flag,
+                    "BPL" => !self.status.negative_flag,
+                    "BVC" => !self.status.overflow_flag,
+                    _ => self.status.overflow_flag,
+                };
+                if taken {
+                    let offset = self.bus.inspect(operand) as i8;
+                    let next_addr = operand.wrapping_add(1);
+                    1 + page_crossed(next_addr, next_addr.wrapping_add(offset as u16)) as u8
+                } else {
+                    0
+                }
+            }
+            _ => 0,
+        };
+
+        opcode.cycles + penalty
     }
 
     fn interrupt(&mut self, interrupt: interrupt::Interrupt) {
         self.stack_push_u16(self.program_counter);
         let mut flag = self.status.clone();
-        flag.break_command = interrupt.b_flag_mask & 0b0001_0000 != 1;
+        flag.break_command = interrupt.b_flag_mask & 0b0001_0000 != 0;
 
         self.stack_push(flag.to_u8());
         self.status.interrupt_disable_flag = true;
@@ -833,7 +1376,7 @@ impl<'a> CPU<'a> {
 
     /// (Address, Whether page crossed)
     fn get_operand_address(&mut self, mode: &AddressingMode) -> (u16, bool) {
-        match mode {
+        let result = match mode {
             AddressingMode::Immediate => (self.program_counter, false),
             AddressingMode::ZeroPage => (self.mem_read(self.program_counter) as u16, false),
             AddressingMode::Absolute => (self.mem_read_u16(self.program_counter), false),
@@ -894,7 +1437,12 @@ impl<'a> CPU<'a> {
             AddressingMode::NoneAddressing => {
                 panic!("mode {:?} is not supported", mode);
             }
+        };
+
+        if !matches!(mode, AddressingMode::Immediate) {
+            self.effective_address = Some(result.0);
         }
+        result
     }
 }
 
@@ -902,7 +1450,7 @@ fn page_crossed(addr1: u16, addr2: u16) -> bool {
     addr1 & 0xFF00 != addr2 & 0xFF00
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 #[allow(non_camel_case_types)]
 pub enum AddressingMode {
     Immediate,
@@ -922,14 +1470,14 @@ pub enum AddressingMode {
 mod test {
     use std::vec;
 
-    use crate::rom::test::TestRom;
+    use crate::rom::{test::TestRom, Rom};
 
     use super::*;
 
     #[test]
     fn test_0xa9_lda_immediate_load_data() {
         let test_rom = TestRom::create_test_rom(vec![0xa9, 0x05, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.run();
         assert_eq!(cpu.register_a, 0x05);
@@ -940,7 +1488,7 @@ mod test {
     #[test]
     fn test_0xa9_lda_zero_frag() {
         let test_rom = TestRom::create_test_rom(vec![0xa9, 0x00, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.run();
         assert!(cpu.status.to_u8() & 0b000_0010 == 0b10);
@@ -949,7 +1497,7 @@ mod test {
     #[test]
     fn test_0xaa_tax_move_a_to_x() {
         let test_rom = TestRom::create_test_rom(vec![0xaa, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.register_a = 10;
         cpu.run();
@@ -960,7 +1508,7 @@ mod test {
     #[test]
     fn test_inx_overflow() {
         let test_rom = TestRom::create_test_rom(vec![0xe8, 0xe8, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.register_x = 0xff;
         cpu.run();
@@ -968,10 +1516,482 @@ mod test {
         assert_eq!(cpu.register_x, 1)
     }
 
+    #[test]
+    fn test_cycles_match_opcode_table() {
+        let mut test_rom = TestRom::create_test_rom(vec![
+            0xa2, 0xff, // LDX #$ff
+            0xbd, 0x01, 0x02, // LDA $0201,X (page crossed)
+            0xbd, 0x00, 0x02, // LDA $0200,X
+            0xa0, 0x10, // LDY #$10
+            0xa9, 0xf8, // LDA #$f8
+            0x85, 0x10, // STA $10
+            0xa9, 0x02, // LDA #$02
+            0x85, 0x11, // STA $11
+            0xb1, 0x10, // LDA ($10),Y (page crossed)
+            0xe8, // INX
+            0xd0, 0x02, // BNE (not taken)
+            0xf0, 0x00, // BEQ (taken)
+            0x4c, 0xf0, 0x80, // JMP $80f0
+        ]);
+        test_rom.prg_rom[0xf0] = 0xf0; // BEQ to $8112 (taken, page crossed)
+        test_rom.prg_rom[0xf1] = 0x20;
+
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
+        cpu.reset();
+
+        let mut executed = 0;
+        while cpu.mem_read(cpu.program_counter) != 0x00 {
+            let opcode = opcodes::OPCODES_MAP[&cpu.mem_read(cpu.program_counter)];
+            let expected = cpu.expected_cycles(opcode);
+            let cycles_before = cpu.bus.cycles;
+            cpu.step();
+
+            assert_eq!(
+                cpu.bus.cycles - cycles_before,
+                expected as usize,
+                "{} ({:02x})",
+                opcode.mnemonic,
+                opcode.code
+            );
+            executed += 1;
+        }
+
+        assert_eq!(executed, 14);
+        assert_eq!(cpu.program_counter, 0x8112);
+    }
+
+    #[test]
+    fn test_current_effective_address() {
+        let test_rom = TestRom::create_test_rom(vec![
+            0xa2, 0x05, // LDX #$05
+            0x9d, 0x00, 0x03, // STA $0300,X
+            0xe8, // INX
+            0x00,
+        ]);
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
+        cpu.reset();
+
+        cpu.step();
+        assert_eq!(cpu.current_effective_address(), None);
+        cpu.step();
+        assert_eq!(cpu.current_effective_address(), Some(0x0305));
+        cpu.step();
+        assert_eq!(cpu.current_effective_address(), None);
+    }
+
+    fn subroutine_test_rom() -> Rom {
+        let mut test_rom = TestRom::create_test_rom(vec![
+            0x20, 0x10, 0x80, // JSR $8010
+            0xe8, // INX
+            0x00,
+        ]);
+        let sub1 = [
+            0xa0, 0x01, // $8010: LDY #$01
+            0x20, 0x20, 0x80, // JSR $8020
+            0xc8, // INY
+            0x60, // RTS
+        ];
+        let sub2 = [
+            0xa9, 0x07, // $8020: LDA #$07
+            0x60, // RTS
+        ];
+        test_rom.prg_rom[0x10..0x10 + sub1.len()].copy_from_slice(&sub1);
+        test_rom.prg_rom[0x20..0x20 + sub2.len()].copy_from_slice(&sub2);
+        test_rom
+    }
+
+    #[test]
+    fn test_step_over() {
+        let mut cpu = CPU::new(Bus::new(subroutine_test_rom(), |_, _, _| {}));
+        cpu.reset();
+
+        assert!(cpu.step_over());
+        assert_eq!(cpu.program_counter, 0x8003);
+        assert_eq!(cpu.register_a, 0x07);
+        assert_eq!(cpu.register_y, 0x02);
+        assert_eq!(cpu.stack_pointer, STACK_RESET);
+
+        // not a JSR: a single step
+        assert!(cpu.step_over());
+        assert_eq!(cpu.program_counter, 0x8004);
+        assert_eq!(cpu.register_x, 0x01);
+    }
+
+    #[test]
+    fn test_step_instruction() {
+        let mut cpu = CPU::new(Bus::new(
+            TestRom::create_test_rom(vec![
+                0xa2, 0x05, // LDX #$05
+                0xca, // DEX
+                0x88, // DEY
+                0x8e, 0x00, 0x02, // STX $0200
+            ]),
+            |_, _, _| {},
+        ));
+        cpu.reset();
+
+        let steps: Vec<(u16, u8, Vec<u8>, usize)> = (0..4)
+            .map(|_| {
+                let retired = cpu.step_instruction();
+                assert_eq!(retired.interrupt, None);
+                (
+                    retired.addr,
+                    retired.opcode,
+                    retired.operand,
+                    retired.cycles,
+                )
+            })
+            .collect();
+        assert_eq!(
+            steps,
+            vec![
+                (0x8000, 0xa2, vec![0x05], 2),
+                (0x8002, 0xca, vec![], 2),
+                (0x8003, 0x88, vec![], 2),
+                (0x8004, 0x8e, vec![0x00, 0x02], 4),
+            ]
+        );
+        assert_eq!(cpu.register_x, 4);
+        assert_eq!(cpu.register_y, 0xff);
+    }
+
+    #[test]
+    fn test_step_over_and_out_stop_on_kil() {
+        let mut test_rom = TestRom::create_test_rom(vec![
+            0x20, 0x10, 0x80, // JSR $8010
+            0x00,
+        ]);
+        test_rom.prg_rom[0x10] = 0xe8; // $8010: INX
+        test_rom.prg_rom[0x11] = 0x02; // KIL
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
+        cpu.reset();
+
+        assert!(cpu.step_over());
+        assert!(cpu.is_jammed());
+        assert_eq!(cpu.program_counter, 0x8011);
+        assert_eq!(cpu.register_x, 1);
+
+        cpu.reset();
+        cpu.step();
+        assert!(cpu.step_out());
+        assert!(cpu.is_jammed());
+        assert_eq!(cpu.program_counter, 0x8011);
+    }
+
+    #[test]
+    fn test_step_over_takes_pending_interrupt() {
+        let mut cpu = cpu_with_pending_irq(&[
+            0x58, // CLI
+            0xe8, // INX
+            0x20, 0x30, 0xe0, // JSR $E030
+        ]);
+        cpu.step();
+        cpu.step();
+
+        // The IRQ comes before the JSR, so only the first instruction of the handler runs
+        assert!(cpu.step_over());
+        assert_eq!(cpu.program_counter, 0xe080);
+        assert_eq!(cpu.stack_pointer, STACK_RESET - 3);
+    }
+
+    #[test]
+    fn test_step_instruction_reports_interrupt() {
+        let mut cpu = cpu_with_pending_irq(&[
+            0x58, // CLI
+            0xe8, // INX
+        ]);
+
+        assert_eq!(cpu.step_instruction().opcode, 0x58);
+        assert_eq!(cpu.step_instruction().opcode, 0xe8);
+        let retired = cpu.step_instruction();
+        assert_eq!(retired.interrupt, Some(InterruptType::IRQ));
+        assert_eq!((retired.addr, retired.opcode), (0xe015, 0x00));
+        assert_eq!(cpu.program_counter, 0xe080);
+    }
+
+    #[test]
+    fn test_total_cycles() {
+        let mut cpu = CPU::new(Bus::new(
+            TestRom::create_test_rom(vec![
+                0xa9, 0x05, // LDA #$05 (2 cycles)
+                0x8d, 0x00, 0x02, // STA $0200 (4 cycles)
+                0xe8, // INX (2 cycles)
+            ]),
+            |_, _, _| {},
+        ));
+        cpu.reset();
+        for _ in 0..3 {
+            cpu.step();
+        }
+        assert_eq!(cpu.total_cycles(), 8);
+
+        // Keeps counting where the bus counter starts over
+        let state = cpu.save_state();
+        cpu.power_cycle();
+        assert_eq!(cpu.bus.cycles, 0);
+        cpu.step();
+        assert_eq!(cpu.total_cycles(), 10);
+        cpu.load_state(&state).unwrap();
+        assert_eq!(cpu.total_cycles(), 10);
+    }
+
+    #[test]
+    fn test_run_one_frame() {
+        let mut cpu = CPU::new(Bus::new(
+            TestRom::create_test_rom(vec![
+                0x4c, 0x00, 0x80, // JMP $8000
+            ]),
+            |_, _, _| {},
+        ));
+        cpu.reset();
+
+        assert!(cpu.run_one_frame());
+        let frame = cpu.bus.ppu_frame();
+        for _ in 0..10 {
+            assert!(cpu.run_one_frame());
+            // Stopped just after vblank started
+            assert_eq!(cpu.bus.get_ppu_position().0, 241);
+        }
+        assert_eq!(cpu.bus.ppu_frame(), frame + 10);
+    }
+
+    #[test]
+    fn test_run_until_break() {
+        let mut cpu = CPU::new(Bus::new(
+            TestRom::create_test_rom(vec![
+                0xa9, 0x05, // LDA #$05
+                0xa2, 0x07, // LDX #$07
+                0x8d, 0x00, 0x02, // STA $0200
+                0xe8, // INX
+                0x00, // BRK
+            ]),
+            |_, _, _| {},
+        ));
+        cpu.reset();
+        cpu.add_breakpoint(0x8004);
+        cpu.bus.add_write_watchpoint(0x0200);
+
+        assert_eq!(cpu.run_until_break(), Stop::Break(0x8004));
+        assert_eq!(cpu.program_counter, 0x8004);
+        assert_eq!(cpu.register_a, 0x05);
+        assert_eq!(cpu.register_x, 0x07);
+
+        assert_eq!(cpu.run_until_break(), Stop::Watch(0x0200));
+        assert_eq!(cpu.program_counter, 0x8007);
+        assert_eq!(cpu.mem_read(0x0200), 0x05);
+
+        assert_eq!(cpu.run_until_break(), Stop::Brk);
+        assert_eq!(cpu.register_x, 0x08);
+    }
+
+    #[test]
+    fn test_step_out() {
+        let mut cpu = CPU::new(Bus::new(subroutine_test_rom(), |_, _, _| {}));
+        cpu.reset();
+        cpu.step(); // JSR $8010
+        cpu.step(); // LDY #$01
+        cpu.step(); // JSR $8020
+        assert_eq!(cpu.program_counter, 0x8020);
+
+        assert!(cpu.step_out());
+        assert_eq!(cpu.program_counter, 0x8015);
+        assert_eq!(cpu.register_a, 0x07);
+        assert_eq!(cpu.register_y, 0x01);
+
+        assert!(cpu.step_out());
+        assert_eq!(cpu.program_counter, 0x8003);
+        assert_eq!(cpu.register_y, 0x02);
+        assert_eq!(cpu.stack_pointer, STACK_RESET);
+    }
+
+    #[test]
+    fn test_kil_jams_instead_of_rotating() {
+        let test_rom = TestRom::create_test_rom(vec![
+            0xa9, 0x81, // LDA #$81
+            0x85, 0x10, // STA $10
+            0x22, // KIL
+            0xe8, // INX
+            0x00,
+        ]);
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
+        cpu.reset();
+        cpu.step();
+        cpu.step();
+
+        for _ in 0..3 {
+            assert!(cpu.step());
+            assert!(cpu.is_jammed());
+            assert_eq!(cpu.program_counter, 0x8004);
+        }
+        assert_eq!(cpu.register_x, 0);
+        assert_eq!(cpu.mem_read(0x10), 0x81);
+        assert!(!cpu.status.carry_flag);
+
+        cpu.reset();
+        assert!(!cpu.is_jammed());
+    }
+
+    #[test]
+    fn test_all_kil_opcodes_jam() {
+        for code in [0x02, 0x12, 0x42, 0x52, 0x92, 0xb2, 0xd2, 0xf2] {
+            let test_rom = TestRom::create_test_rom(vec![code, 0xe8, 0x00]);
+            let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
+            cpu.reset();
+
+            for _ in 0..3 {
+                assert!(cpu.step());
+                assert!(cpu.is_jammed());
+                assert_eq!(cpu.program_counter, 0x8000);
+            }
+            assert_eq!(cpu.register_x, 0);
+        }
+    }
+
+    #[test]
+    fn test_unstable_opcodes() {
+        let test_rom = TestRom::create_test_rom(vec![
+            0xa9, 0x51, // LDA #$51
+            0xa2, 0x3c, // LDX #$3c
+            0x8b, 0xf3, // ANE #$f3
+            0xa9, 0xff, // LDA #$ff
+            0xa2, 0xf6, // LDX #$f6
+            0xa0, 0x02, // LDY #$02
+            0x9f, 0xf0, 0x06, // SHA $06f0,Y
+            0x93, 0x10, // SHA ($10),Y
+            0x9f, 0xff, 0x06, // SHA $06ff,Y (page crossed)
+            0x9b, 0xe0, 0x06, // TAS $06e0,Y
+            0xbb, 0xfe, 0x06, // LAS $06fe,Y
+            0x00,
+        ]);
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
+        cpu.reset();
+        cpu.mem_write_u16(0x10, 0x0650);
+        cpu.mem_write(0x0700, 0x9f);
+
+        for _ in 0..3 {
+            cpu.step();
+        }
+        // ($51 | $ee) & $3c & $f3
+        assert_eq!(cpu.register_a, 0x30);
+        assert!(!cpu.status.zero_flag);
+
+        cpu.run();
+        // $ff & $f6 & ($06 + 1)
+        assert_eq!(cpu.mem_read(0x06f2), 0x06);
+        assert_eq!(cpu.mem_read(0x0652), 0x06);
+        // Crossing into $0701 writes to $0601 instead
+        assert_eq!(cpu.mem_read(0x0601), 0x06);
+        assert_eq!(cpu.mem_read(0x0701), 0x00);
+        assert_eq!(cpu.mem_read(0x06e2), 0x06);
+        // TAS left SP at $f6, then LAS loaded $9f & $f6
+        assert_eq!(cpu.register_a, 0x96);
+        assert_eq!(cpu.register_x, 0x96);
+        assert_eq!(cpu.stack_pointer, 0x96);
+        assert!(cpu.status.negative_flag);
+    }
+
+    #[test]
+    fn test_reset_to() {
+        let mut bus = Bus::new(TestRom::create_test_rom(vec![]), |_, _, _| {});
+        bus.seed_ram(&[0xaa, 0x55]);
+        let mut cpu = CPU::new(bus);
+        cpu.register_a = 0x12;
+
+        cpu.reset_to(0xc000);
+        assert_eq!(cpu.program_counter, 0xc000);
+        assert_eq!(cpu.register_a, 0);
+        assert_eq!(cpu.stack_pointer, STACK_RESET);
+        assert_eq!(cpu.status.to_u8(), 0b0010_0100);
+        assert_eq!(cpu.mem_read(0x0000), 0xaa);
+        assert_eq!(cpu.mem_read(0x07ff), 0x55);
+    }
+
+    // MMC3 CPU with its IRQ line asserted and I still set from reset, about to run `program`
+    // at $E013. The IRQ handler at $E080 spins.
+    fn cpu_with_pending_irq(program: &[u8]) -> CPU<'static> {
+        let mut rom = crate::mapper_test_utils::build_rom(4, 2, 1);
+        let setup = [
+            0xa9, 0x00, // LDA #0
+            0x8d, 0x00, 0xc0, // STA $C000 (latch)
+            0x8d, 0x01, 0xc0, // STA $C001 (reload)
+            0x8d, 0x01, 0xe0, // STA $E001 (enable)
+            0xa9, 0x08, // LDA #$08
+            0x8d, 0x01, 0x20, // STA $2001 (show background)
+            0x4c, 0x10, 0xe0, // JMP $E010
+        ];
+        rom.prg_rom[0x6000..0x6013].copy_from_slice(&setup);
+        rom.prg_rom[0x6013..0x6013 + program.len()].copy_from_slice(program);
+        rom.prg_rom[0x6080..0x6083].copy_from_slice(&[0x4c, 0x80, 0xe0]);
+        rom.prg_rom[0x7ffc..].copy_from_slice(&[0x00, 0xe0, 0x80, 0xe0]);
+
+        let mut cpu = CPU::new(Bus::new(rom, |_, _, _| {}));
+        cpu.reset();
+        while !cpu.bus.poll_irq_status() {
+            cpu.step();
+        }
+        cpu.program_counter = 0xe013;
+        cpu
+    }
+
+    #[test]
+    fn test_cli_delays_irq() {
+        let mut cpu = cpu_with_pending_irq(&[
+            0x58, // CLI
+            0xe8, // INX
+            0xe8, // INX
+        ]);
+
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.register_x, 1);
+        cpu.step();
+        assert_eq!(cpu.program_counter, 0xe080);
+        assert_eq!(cpu.register_x, 1);
+    }
+
+    #[test]
+    fn test_irq_fires_after_sei() {
+        let mut cpu = cpu_with_pending_irq(&[
+            0x58, // CLI
+            0x78, // SEI
+            0xe8, // INX
+        ]);
+
+        cpu.step();
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.program_counter, 0xe080);
+        assert_eq!(cpu.register_x, 0);
+        // Taken one instruction late, returning to the INX after SEI
+        assert_eq!(
+            cpu.mem_read_u16(0x0100 + cpu.stack_pointer as u16 + 2),
+            0xe015
+        );
+    }
+
+    #[test]
+    fn test_power_cycle() {
+        let test_rom = TestRom::create_test_rom(vec![0xa9, 0x42, 0x85, 0x10, 0xa8, 0x00]);
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
+        cpu.reset();
+        cpu.run();
+        assert_eq!(cpu.mem_read(0x10), 0x42);
+
+        cpu.power_cycle();
+
+        assert_eq!(cpu.mem_read(0x10), 0x00);
+        assert_eq!(cpu.register_a, 0);
+        assert_eq!(cpu.register_y, 0);
+        assert_eq!(cpu.program_counter, 0x8000);
+
+        cpu.run();
+        assert_eq!(cpu.mem_read(0x10), 0x42);
+    }
+
     #[test]
     fn test_5_ops_working_togather() {
         let test_rom = TestRom::create_test_rom(vec![0xa9, 0xc0, 0xaa, 0xe8, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.run();
 
@@ -981,7 +2001,7 @@ mod test {
     #[test]
     fn test_lda_from_memory() {
         let test_rom = TestRom::create_test_rom(vec![0xa5, 0x10, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.mem_write(0x10, 0x55);
         cpu.run();
@@ -1005,7 +2025,7 @@ mod test {
     #[test]
     fn test_adc() {
         let test_rom = TestRom::create_test_rom(vec![0xa9, 0xc0, 0x69, 0xc4, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.run();
 
@@ -1018,7 +2038,7 @@ mod test {
     #[test]
     fn test_adc2() {
         let test_rom = TestRom::create_test_rom(vec![0xa9, 0x50, 0x69, 0x50, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.run();
 
@@ -1031,7 +2051,7 @@ mod test {
     #[test]
     fn test_adc3() {
         let test_rom = TestRom::create_test_rom(vec![0xa9, 0xd0, 0x69, 0x90, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.run();
 
@@ -1044,7 +2064,7 @@ mod test {
     #[test]
     fn test_adc_ff() {
         let test_rom = TestRom::create_test_rom(vec![0xa9, 0x7f, 0x69, 0x7f, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.status.carry_flag = true;
         cpu.run();
@@ -1058,7 +2078,7 @@ mod test {
     #[test]
     fn test_adc_carry_in() {
         let test_rom = TestRom::create_test_rom(vec![0xa9, 0x50, 0x69, 0x10, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.status.carry_flag = true;
 
@@ -1070,10 +2090,78 @@ mod test {
         assert_eq!(cpu.status.carry_flag, false);
     }
 
+    // Runs `LDA #a; <opcode> #b` with the D flag set and decimal mode enabled
+    fn run_decimal(opcode: u8, a: u8, b: u8, carry: bool) -> CPU<'static> {
+        let test_rom = TestRom::create_test_rom(vec![0xa9, a, opcode, b, 0x00]);
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {})).with_decimal_mode(true);
+        cpu.reset();
+        cpu.status.decimal_mode_flag = true;
+        cpu.status.carry_flag = carry;
+        cpu.run();
+        cpu
+    }
+
+    #[test]
+    fn test_adc_decimal() {
+        // (a, b, carry in) => (result, carry out)
+        for (a, b, carry, result, carry_out) in [
+            (0x12, 0x34, false, 0x46, false),
+            (0x15, 0x26, false, 0x41, false),
+            (0x58, 0x46, true, 0x05, true),
+            (0x81, 0x92, false, 0x73, true),
+        ] {
+            let cpu = run_decimal(0x69, a, b, carry);
+            assert_eq!(cpu.register_a, result, "{:02x} + {:02x}", a, b);
+            assert_eq!(cpu.status.carry_flag, carry_out, "{:02x} + {:02x}", a, b);
+        }
+
+        // NMOS quirks: Z follows the binary sum, N and V the half-adjusted one
+        let cpu = run_decimal(0x69, 0x99, 0x01, false);
+        assert_eq!(cpu.register_a, 0x00);
+        assert!(cpu.status.carry_flag);
+        assert!(!cpu.status.zero_flag);
+        assert!(cpu.status.negative_flag);
+        assert!(!cpu.status.overflow_flag);
+
+        let cpu = run_decimal(0x69, 0x81, 0x92, false);
+        assert!(cpu.status.overflow_flag);
+        assert!(!cpu.status.negative_flag);
+    }
+
+    #[test]
+    fn test_sbc_decimal() {
+        for (a, b, carry, result, carry_out) in [
+            (0x46, 0x12, true, 0x34, true),
+            (0x40, 0x13, true, 0x27, true),
+            (0x32, 0x02, false, 0x29, true),
+            (0x21, 0x34, true, 0x87, false),
+        ] {
+            let cpu = run_decimal(0xe9, a, b, carry);
+            assert_eq!(cpu.register_a, result, "{:02x} - {:02x}", a, b);
+            assert_eq!(cpu.status.carry_flag, carry_out, "{:02x} - {:02x}", a, b);
+        }
+
+        // Flags are the binary ones: $21 - $34 = $ED
+        let cpu = run_decimal(0xe9, 0x21, 0x34, true);
+        assert!(cpu.status.negative_flag);
+        assert!(!cpu.status.zero_flag);
+    }
+
+    #[test]
+    fn test_decimal_flag_ignored_by_default() {
+        let test_rom = TestRom::create_test_rom(vec![0xf8, 0xa9, 0x58, 0x69, 0x46, 0x00]);
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
+        cpu.reset();
+        cpu.run();
+
+        assert!(cpu.status.decimal_mode_flag);
+        assert_eq!(cpu.register_a, 0x9e);
+    }
+
     #[test]
     fn test_sbc() {
         let test_rom = TestRom::create_test_rom(vec![0xa9, 0x50, 0xE9, 0xf0, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.run();
 
@@ -1086,7 +2174,7 @@ mod test {
     #[test]
     fn test_sbc2() {
         let test_rom = TestRom::create_test_rom(vec![0xa9, 0x50, 0xe9, 0xb0, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.run();
 
@@ -1099,7 +2187,7 @@ mod test {
     #[test]
     fn test_sbc3() {
         let test_rom = TestRom::create_test_rom(vec![0xa9, 0xd0, 0xe9, 0x70, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.run();
 
@@ -1112,14 +2200,14 @@ mod test {
     #[test]
     fn test_and() {
         let test_rom = TestRom::create_test_rom(vec![0xa9, 0b1010_1010, 0x29, 0b0101_0101, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.run();
 
         assert_eq!(cpu.register_a, 0);
 
         let test_rom = TestRom::create_test_rom(vec![0xa9, 0b1010_1010, 0x29, 0b0101_1010, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.run();
 
@@ -1129,7 +2217,7 @@ mod test {
     #[test]
     fn test_asl_accumulator() {
         let test_rom = TestRom::create_test_rom(vec![0xa9, 0x50, 0x0a, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.run();
 
@@ -1139,7 +2227,7 @@ mod test {
         assert_eq!(cpu.status.carry_flag, false);
 
         let test_rom = TestRom::create_test_rom(vec![0xa9, 0xf0, 0x0a, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.run();
 
@@ -1153,7 +2241,7 @@ mod test {
     fn test_asl() {
         let test_rom =
             TestRom::create_test_rom(vec![0xa9, 0b1010_1010, 0x85, 0xc0, 0x06, 0xc0, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.run();
 
@@ -1166,7 +2254,7 @@ mod test {
     #[test]
     fn test_bcc() {
         let test_rom = TestRom::create_test_rom(vec![0x90, 0x01, 0x00, 0xa9, 0x51, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.run();
 
@@ -1177,7 +2265,7 @@ mod test {
     fn test_bcc2() {
         let test_rom =
             TestRom::create_test_rom(vec![0x90, 0x04, 0x00, 0xa9, 0x51, 0x00, 0x90, 0xFB, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.run();
 
@@ -1187,7 +2275,7 @@ mod test {
     #[test]
     fn test_beq() {
         let test_rom = TestRom::create_test_rom(vec![0xf0, 0x01, 0x00, 0xa9, 0x51, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.status.zero_flag = true;
         cpu.run();
@@ -1198,7 +2286,7 @@ mod test {
     #[test]
     fn test_bmi() {
         let test_rom = TestRom::create_test_rom(vec![0x30, 0x01, 0x00, 0xa9, 0x51, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.status.negative_flag = true;
         cpu.run();
@@ -1219,7 +2307,7 @@ mod test {
             0xc0,
             0x00,
         ]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.run();
 
@@ -1241,7 +2329,7 @@ mod test {
             0xc0,
             0x00,
         ]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.run();
 
@@ -1253,7 +2341,7 @@ mod test {
     #[test]
     fn test_bne() {
         let test_rom = TestRom::create_test_rom(vec![0xd0, 0x01, 0x00, 0xa9, 0x51, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.status.zero_flag = false;
         cpu.run();
@@ -1264,7 +2352,7 @@ mod test {
     #[test]
     fn test_bpl() {
         let test_rom = TestRom::create_test_rom(vec![0x10, 0x01, 0x00, 0xa9, 0x51, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.status.negative_flag = false;
         cpu.run();
@@ -1275,7 +2363,7 @@ mod test {
     #[test]
     fn test_bvc() {
         let test_rom = TestRom::create_test_rom(vec![0x50, 0x01, 0x00, 0xa9, 0x51, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.status.overflow_flag = false;
         cpu.run();
@@ -1286,7 +2374,7 @@ mod test {
     #[test]
     fn test_bvs() {
         let test_rom = TestRom::create_test_rom(vec![0x70, 0x01, 0x00, 0xa9, 0x51, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.status.overflow_flag = true;
         cpu.run();
@@ -1297,7 +2385,7 @@ mod test {
     #[test]
     fn test_cmp() {
         let test_rom = TestRom::create_test_rom(vec![0xc9, 0x51, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.register_a = 0x51;
         cpu.run();
@@ -1310,7 +2398,7 @@ mod test {
     #[test]
     fn test_cpx() {
         let test_rom = TestRom::create_test_rom(vec![0xe0, 0x51, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.register_x = 0x51;
         cpu.run();
@@ -1323,7 +2411,7 @@ mod test {
     #[test]
     fn test_cpy() {
         let test_rom = TestRom::create_test_rom(vec![0xc0, 0x51, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.register_y = 0x51;
         cpu.run();
@@ -1336,7 +2424,7 @@ mod test {
     #[test]
     fn test_dec() {
         let test_rom = TestRom::create_test_rom(vec![0xa9, 0x51, 0x85, 0xc0, 0xc6, 0xc0, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.run();
 
@@ -1348,7 +2436,7 @@ mod test {
     #[test]
     fn test_dex() {
         let test_rom = TestRom::create_test_rom(vec![0xca, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.register_x = 0x51;
         cpu.run();
@@ -1361,7 +2449,7 @@ mod test {
     #[test]
     fn test_dey() {
         let test_rom = TestRom::create_test_rom(vec![0x88, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.register_y = 0x51;
         cpu.run();
@@ -1374,7 +2462,7 @@ mod test {
     #[test]
     fn test_eor() {
         let test_rom = TestRom::create_test_rom(vec![0x49, 0x51, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.register_a = 0x51;
         cpu.run();
@@ -1387,7 +2475,7 @@ mod test {
     #[test]
     fn test_inc() {
         let test_rom = TestRom::create_test_rom(vec![0xa9, 0x51, 0x85, 0xc0, 0xe6, 0xc0, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.run();
 
@@ -1399,7 +2487,7 @@ mod test {
     #[test]
     fn test_iny() {
         let test_rom = TestRom::create_test_rom(vec![0xc8, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.register_y = 0x51;
         cpu.run();
@@ -1414,7 +2502,7 @@ mod test {
         let test_rom = TestRom::create_test_rom(vec![
             0xa9, 0x01, 0x85, 0xf0, 0xa9, 0xcc, 0x85, 0xf1, 0x6c, 0xf0, 0x00,
         ]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.run();
 
@@ -1424,7 +2512,7 @@ mod test {
     #[test]
     fn test_jsr() {
         let test_rom = TestRom::create_test_rom(vec![0x20, 0x03, 0x80, 0xa9, 0x51, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.run();
 
@@ -1440,7 +2528,7 @@ mod test {
     fn test_rts() {
         let test_rom =
             TestRom::create_test_rom(vec![0x20, 0x04, 0x80, 0x00, 0xa9, 0x51, 0x60, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.run();
 
@@ -1451,7 +2539,7 @@ mod test {
     #[test]
     fn test_ldx() {
         let test_rom = TestRom::create_test_rom(vec![0xa2, 0x51, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.run();
 
@@ -1461,7 +2549,7 @@ mod test {
     #[test]
     fn test_ldy() {
         let test_rom = TestRom::create_test_rom(vec![0xa0, 0x51, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.run();
 
@@ -1471,7 +2559,7 @@ mod test {
     #[test]
     fn test_lsr_accumulator() {
         let test_rom = TestRom::create_test_rom(vec![0x4a, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.register_a = 0b0101_0101;
         cpu.run();
@@ -1483,7 +2571,7 @@ mod test {
     #[test]
     fn test_ora() {
         let test_rom = TestRom::create_test_rom(vec![0x09, 0b0101_0101, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.register_a = 0b1010_1010;
         cpu.run();
@@ -1494,7 +2582,7 @@ mod test {
     #[test]
     fn test_pha() {
         let test_rom = TestRom::create_test_rom(vec![0x48, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.register_a = 0x51;
         cpu.run();
@@ -1505,7 +2593,7 @@ mod test {
     #[test]
     fn test_php() {
         let test_rom = TestRom::create_test_rom(vec![0x08, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.status.carry_flag = true;
         cpu.run();
@@ -1516,7 +2604,7 @@ mod test {
     #[test]
     fn test_pla() {
         let test_rom = TestRom::create_test_rom(vec![0xa9, 0x51, 0x48, 0xa9, 0x50, 0x68, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.run();
 
@@ -1526,7 +2614,7 @@ mod test {
     #[test]
     fn test_plp() {
         let test_rom = TestRom::create_test_rom(vec![0x08, 0x38, 0x28, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.run();
 
@@ -1536,7 +2624,7 @@ mod test {
     #[test]
     fn test_rol_accumulator() {
         let test_rom = TestRom::create_test_rom(vec![0xa9, 0b1010_1010, 0x2a, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.status.carry_flag = true;
         cpu.run();
@@ -1551,7 +2639,7 @@ mod test {
     fn test_rol() {
         let test_rom =
             TestRom::create_test_rom(vec![0xa9, 0b1010_1010, 0x85, 0xc0, 0x26, 0xc0, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.status.carry_flag = true;
         cpu.run();
@@ -1565,7 +2653,7 @@ mod test {
     #[test]
     fn test_ror_accumulator() {
         let test_rom = TestRom::create_test_rom(vec![0xa9, 0b1010_1010, 0x6a, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.status.carry_flag = true;
         cpu.run();
@@ -1580,7 +2668,7 @@ mod test {
     fn test_ror() {
         let test_rom =
             TestRom::create_test_rom(vec![0xa9, 0b1010_1010, 0x85, 0xc0, 0x66, 0xc0, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.status.carry_flag = true;
         cpu.run();
@@ -1594,7 +2682,7 @@ mod test {
     #[test]
     fn test_stx() {
         let test_rom = TestRom::create_test_rom(vec![0xa9, 0x51, 0xaa, 0x86, 0xc0, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.run();
 
@@ -1604,7 +2692,7 @@ mod test {
     #[test]
     fn test_sty() {
         let test_rom = TestRom::create_test_rom(vec![0xa9, 0x51, 0xa8, 0x84, 0xc0, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.run();
 
@@ -1614,7 +2702,7 @@ mod test {
     #[test]
     fn test_tay() {
         let test_rom = TestRom::create_test_rom(vec![0xa9, 0x51, 0xa8, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.run();
 
@@ -1624,10 +2712,187 @@ mod test {
     #[test]
     fn test_tsx() {
         let test_rom = TestRom::create_test_rom(vec![0xba, 0x00]);
-        let mut cpu = CPU::new(Bus::new(test_rom, |_, _| {}));
+        let mut cpu = CPU::new(Bus::new(test_rom, |_, _, _| {}));
         cpu.reset();
         cpu.run();
 
         assert_eq!(cpu.register_x, 0xfd);
     }
+
+    #[test]
+    fn test_load_state_keeps_breakpoints_and_watchpoints() {
+        let mut cpu = CPU::new(Bus::new(
+            TestRom::create_test_rom(vec![
+                0xa9, 0x05, // LDA #$05
+                0x85, 0x10, // STA $10
+                0xe8, // INX
+                0x00, // BRK
+            ]),
+            |_, _, _| {},
+        ));
+        cpu.reset();
+        let state = cpu.save_state();
+        cpu.add_breakpoint(0x8002);
+        cpu.bus.add_write_watchpoint(0x10);
+
+        cpu.load_state(&state).unwrap();
+        assert_eq!(cpu.run_until_break(), Stop::Break(0x8002));
+        assert_eq!(cpu.run_until_break(), Stop::Watch(0x10));
+    }
+
+    #[test]
+    fn test_load_state_keeps_cheats() {
+        let mut rom = TestRom::create_test_rom(vec![]);
+        rom.prg_rom[0x11d9] = 0xde; // $91D9
+        let mut cpu = CPU::new(Bus::new(rom, |_, _, _| {}));
+        cpu.reset();
+        let state = cpu.save_state();
+        cpu.bus.add_cheat("SXIOPO").unwrap();
+        cpu.bus.freeze(0x0072, 0x99);
+
+        cpu.load_state(&state).unwrap();
+        assert_eq!(cpu.mem_read(0x91d9), 0xad);
+        assert_eq!(cpu.mem_read(0x0072), 0x99);
+    }
+
+    #[test]
+    fn test_save_state_round_trip() {
+        let mut rom = TestRom::create_test_rom(vec![
+            0xa9, 0x80, // LDA #$80
+            0x8d, 0x00, 0x20, // STA $2000 (enable NMI)
+            0xe6, 0x10, // INC $10
+            0xa5, 0x10, // LDA $10
+            0x65, 0x11, // ADC $11
+            0x85, 0x11, // STA $11
+            0x4c, 0x05, 0x80, // JMP $8005
+            0xe6, 0x12, // INC $12 (NMI handler at $8010)
+            0x40, // RTI
+        ]);
+        rom.prg_rom[0x7ffa] = 0x10;
+        rom.prg_rom[0x7ffb] = 0x80;
+
+        // PC, A, SP, P, PPUSTATUS, CPU cycles, PPU position and the RAM the program touches
+        type Snapshot = (u16, u8, u8, u8, u8, usize, (u16, usize), [u8; 3]);
+
+        fn snapshot(cpu: &mut CPU) -> Snapshot {
+            (
+                cpu.program_counter,
+                cpu.register_a,
+                cpu.stack_pointer,
+                cpu.status.to_u8(),
+                cpu.bus.ppu().status.bits(),
+                cpu.bus.cycles,
+                cpu.bus.ppu().position(),
+                [cpu.mem_read(0x10), cpu.mem_read(0x11), cpu.mem_read(0x12)],
+            )
+        }
+
+        let mut cpu = CPU::new(Bus::new(rom, |_, _, _| {}));
+        cpu.reset();
+        for _ in 0..5000 {
+            cpu.step();
+        }
+
+        let state = cpu.save_state();
+        let mut expected = Vec::new();
+        for _ in 0..10000 {
+            cpu.step();
+            expected.push(snapshot(&mut cpu));
+        }
+        assert!(expected.last().unwrap().7[2] > 0, "no NMI was taken");
+
+        cpu.load_state(&state).unwrap();
+        for snap in expected {
+            cpu.step();
+            assert_eq!(snapshot(&mut cpu), snap);
+        }
+
+        cpu.load_state(&state[..10])
+            .expect_err("a truncated state should be rejected");
+    }
+
+    #[test]
+    fn test_oam_dma_after_sta() {
+        let mut cpu = CPU::new(Bus::new(
+            TestRom::create_test_rom(vec![
+                0xa9, 0x02, // LDA #$02
+                0x8d, 0x14, 0x40, // STA $4014
+                0xea, // NOP
+                0x8d, 0x14, 0x40, // STA $4014
+            ]),
+            |_, _, _| {},
+        ));
+        cpu.reset();
+        for i in 0..=0xff {
+            cpu.mem_write(0x0200 + i, i as u8);
+        }
+        cpu.step();
+        let start = cpu.bus.cycles;
+
+        // The STA's 4 cycles, then the DMA. It starts on cycle 6, even, so there is no
+        // alignment cycle
+        cpu.step();
+        assert_eq!(cpu.bus.cycles, start + 4 + 513);
+        assert_eq!(cpu.bus.ppu().oam_data[0x80], 0x80);
+
+        // The second one starts on an odd cycle
+        cpu.step();
+        let start = cpu.bus.cycles;
+        cpu.step();
+        assert_eq!(cpu.bus.cycles, start + 4 + 514);
+    }
+
+    #[test]
+    fn test_save_state_leaves_out_rom() {
+        // MMC1 with 128KB of PRG-ROM and 8KB of CHR-RAM
+        let mut cpu = CPU::new(Bus::new(
+            crate::mapper_test_utils::build_rom(1, 8, 0),
+            |_, _, _| {},
+        ));
+        cpu.reset();
+        let write_chr_ram = |cpu: &mut CPU, value: u8| {
+            cpu.mem_write(0x2006, 0x00);
+            cpu.mem_write(0x2006, 0x10);
+            cpu.mem_write(0x2007, value);
+        };
+        write_chr_ram(&mut cpu, 0x5a);
+
+        let state = cpu.save_state();
+        assert!(state.len() < 0x20000, "state has the PRG-ROM in it");
+
+        write_chr_ram(&mut cpu, 0x00);
+        cpu.load_state(&state).unwrap();
+        // The ROM is still there and CHR-RAM came back from the state
+        assert_eq!(cpu.mem_read(0xfff0), 15);
+        cpu.mem_read(0x2002);
+        cpu.mem_write(0x2006, 0x00);
+        cpu.mem_write(0x2006, 0x10);
+        cpu.mem_read(0x2007);
+        assert_eq!(cpu.mem_read(0x2007), 0x5a);
+    }
+
+    #[test]
+    fn test_load_state_rejects_other_rom() {
+        let mut cpu_a = CPU::new(Bus::new(
+            TestRom::create_test_rom(vec![0xe8, 0x00]),
+            |_, _, _| {},
+        ));
+        cpu_a.reset();
+        cpu_a.run();
+        let state = cpu_a.save_state();
+
+        let mut cpu_b = CPU::new(Bus::new(
+            TestRom::create_test_rom(vec![0xc8, 0x00]),
+            |_, _, _| {},
+        ));
+        cpu_b.reset();
+        cpu_b.run();
+        let err = cpu_b.load_state(&state).unwrap_err();
+        assert!(err.contains("CRC"), "{}", err);
+        assert_eq!(cpu_b.register_x, 0);
+        assert_eq!(cpu_b.register_y, 1);
+
+        cpu_a.load_state(&state).unwrap();
+        assert_eq!(cpu_a.register_x, 1);
+    }
 }
@@ -0,0 +1,113 @@
+//! Golden frames for regression baselines: runs a ROM headlessly up to a given frame and
+//! records what it shows, as a PNG for people to look at and a hash for tests to compare.
+//!
+//! Baselines are regenerated on purpose with the `golden_capture` example, never by tests.
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::{
+    bus::Bus,
+    cpu::CPU,
+    render::{self, frame::Frame},
+    rom::{Rom, RomError},
+};
+
+pub const SCREEN_PIXELS: usize = 256 * 240;
+
+/// Runs `rom` from reset until the PPU has completed `frame` frames and renders the screen.
+/// Stops early if the program hits BRK.
+pub fn run_to_frame(rom: Rom, frame: u64) -> Frame {
+    let mut cpu = CPU::new(Bus::new(rom, |_, _, _| {}));
+    cpu.reset();
+    while cpu.bus.ppu_frame() < frame && cpu.step() {}
+
+    let mut screen = Frame::new();
+    render::render(cpu.bus.ppu(), &mut screen);
+    screen
+}
+
+/// Runs the iNES image `rom_bytes` for `frames` frames with `CPU::step_frame` and returns
+/// the last frame's `pixel_buffer`: the system palette index ($00-$3F) of every pixel, row
+/// by row. Unlike RGB output this doesn't change with the palette, so it pins down only
+/// what the PPU picked. If the program hits BRK first, the last frame it finished is
+/// returned.
+pub fn run_rom_frames(rom_bytes: &[u8], frames: usize) -> Result<[u8; SCREEN_PIXELS], RomError> {
+    let mut cpu = CPU::new(Bus::new(Rom::new(rom_bytes)?, |_, _, _| {}));
+    cpu.reset();
+    let mut indices = [0; SCREEN_PIXELS];
+    for _ in 0..frames {
+        match cpu.step_frame() {
+            Some(frame) => indices.copy_from_slice(frame.pixel_buffer()),
+            None => break,
+        }
+    }
+    Ok(indices)
+}
+
+/// 64-bit FNV-1a of `bytes`. Stable across platforms and Rust versions, unlike
+/// `DefaultHasher`, so it can be checked in.
+pub fn buffer_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// `buffer_hash` of the frame's pixels.
+pub fn frame_hash(frame: &Frame) -> u64 {
+    buffer_hash(frame.rgb_bytes())
+}
+
+pub fn write_png(path: &Path, frame: &Frame) -> io::Result<()> {
+    frame.to_png(path)
+}
+
+/// Writes `<name>.png` and `<name>.hash` (the `frame_hash` in hex) into `dir`, creating it
+/// if needed. Returns the paths of both files.
+pub fn write_baseline(dir: &Path, name: &str, frame: &Frame) -> io::Result<(PathBuf, PathBuf)> {
+    std::fs::create_dir_all(dir)?;
+
+    let png_path = dir.join(format!("{}.png", name));
+    write_png(&png_path, frame)?;
+
+    let hash_path = dir.join(format!("{}.hash", name));
+    let mut hash_file = File::create(&hash_path)?;
+    writeln!(hash_file, "{:016x}", frame_hash(frame))?;
+
+    Ok((png_path, hash_path))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rom::test::TestRom;
+
+    #[test]
+    fn test_write_baseline() {
+        let rom = || {
+            TestRom::create_test_rom(vec![
+                0xa9, 0x3f, // LDA #$3F
+                0x8d, 0x06, 0x20, // STA $2006
+                0xa9, 0x00, // LDA #$00
+                0x8d, 0x06, 0x20, // STA $2006
+                0xa9, 0x21, // LDA #$21
+                0x8d, 0x07, 0x20, // STA $2007 (backdrop color)
+                0x4c, 0x0f, 0x80, // JMP $800F
+            ])
+        };
+        let frame = run_to_frame(rom(), 2);
+        assert_eq!(frame_hash(&frame), frame_hash(&run_to_frame(rom(), 2)));
+        assert_ne!(frame_hash(&frame), frame_hash(&Frame::new()));
+
+        let dir = std::env::temp_dir().join(format!("nes-golden-{}", std::process::id()));
+        let (png_path, hash_path) = write_baseline(&dir, "test", &frame).unwrap();
+
+        let png = std::fs::read(&png_path).unwrap();
+        assert_eq!(&png[0..8], b"\x89PNG\r\n\x1a\n");
+        let hash = std::fs::read_to_string(&hash_path).unwrap();
+        assert_eq!(hash, format!("{:016x}\n", frame_hash(&frame)));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
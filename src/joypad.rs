@@ -1,7 +1,8 @@
 use bitflags::bitflags;
+use serde::{Deserialize, Serialize};
 
 bitflags! {
-    #[derive(Clone, Copy)]
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
     pub struct JoypadButton: u8 {
         const RIGHT         = 0b1000_0000;
         const LEFT          = 0b0100_0000;
@@ -14,10 +15,27 @@ bitflags! {
     }
 }
 
+// Turbo buttons flip every frame by default, which is 30 presses a second on NTSC
+const DEFAULT_TURBO_RATE: u8 = 1;
+
+fn default_turbo_rate() -> u8 {
+    DEFAULT_TURBO_RATE
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct Joypad {
     strobe: bool,
     button_index: u8,
     button_status: JoypadButton,
+    // Buttons that autofire while held. Frontend settings, not saved with the state
+    #[serde(skip, default = "JoypadButton::empty")]
+    turbo: JoypadButton,
+    // Frames each turbo half-period lasts
+    #[serde(skip, default = "default_turbo_rate")]
+    turbo_rate: u8,
+    turbo_frames: u8,
+    // Turbo buttons read as released during the off half-period
+    turbo_off: bool,
 }
 
 impl Joypad {
@@ -26,6 +44,10 @@ impl Joypad {
             strobe: false,
             button_index: 0,
             button_status: JoypadButton::from_bits_truncate(0),
+            turbo: JoypadButton::empty(),
+            turbo_rate: DEFAULT_TURBO_RATE,
+            turbo_frames: 0,
+            turbo_off: false,
         }
     }
 
@@ -41,7 +63,11 @@ impl Joypad {
             return 1;
         }
 
-        let response = (self.button_status.bits() & (1 << self.button_index)) >> self.button_index;
+        let mut buttons = self.button_status;
+        if self.turbo_off {
+            buttons.remove(self.turbo);
+        }
+        let response = (buttons.bits() & (1 << self.button_index)) >> self.button_index;
         if !self.strobe && self.button_index <= 7 {
             self.button_index += 1;
         }
@@ -51,4 +77,196 @@ impl Joypad {
     pub fn set_button_pressed_status(&mut self, button: JoypadButton, pressed: bool) {
         self.button_status.set(button, pressed);
     }
+
+    pub fn set_turbo(&mut self, button: JoypadButton, enabled: bool) {
+        self.turbo.set(button, enabled);
+    }
+
+    /// How many frames turbo buttons stay pressed, and then released, while held.
+    pub fn set_turbo_rate(&mut self, frames: u8) {
+        self.turbo_rate = frames.max(1);
+    }
+
+    /// Copies the turbo buttons and rate from `other`, which this joypad is replacing.
+    pub(crate) fn keep_turbo(&mut self, other: &Joypad) {
+        self.turbo = other.turbo;
+        self.turbo_rate = other.turbo_rate;
+    }
+
+    /// Advances the turbo phase. Call once per frame from the gameloop.
+    pub fn tick_turbo(&mut self) {
+        self.turbo_frames += 1;
+        if self.turbo_frames >= self.turbo_rate {
+            self.turbo_frames = 0;
+            self.turbo_off = !self.turbo_off;
+        }
+    }
+
+    /// (strobe, index of the button reported by the next read, latched button bits)
+    pub fn debug_state(&self) -> (bool, u8, u8) {
+        (self.strobe, self.button_index, self.button_status.bits())
+    }
+}
+
+impl Default for Joypad {
+    fn default() -> Self {
+        Joypad::new()
+    }
+}
+
+// Four Score (4 player adapter)
+// Each port reports 24 bits after a strobe:
+//   $4016: player 1 (1-8), player 3 (9-16), signature (17-24)
+//   $4017: player 2 (1-8), player 4 (9-16), signature (17-24)
+// and 1 for every read after that.
+const FOUR_SCORE_SIGNATURE: [[u8; 8]; 2] = [[0, 0, 0, 1, 0, 0, 0, 0], [0, 0, 1, 0, 0, 0, 0, 0]];
+
+#[derive(Serialize, Deserialize)]
+pub struct FourScore {
+    strobe: bool,
+    read_index: [u8; 2],
+    players: [Joypad; 2], // player 3, 4
+}
+
+impl FourScore {
+    pub fn new() -> Self {
+        FourScore {
+            strobe: false,
+            read_index: [0, 0],
+            players: [Joypad::new(), Joypad::new()],
+        }
+    }
+
+    /// `player` is 3 or 4. Players 1 and 2 are the joypads owned by the bus.
+    pub fn joypad_mut(&mut self, player: usize) -> &mut Joypad {
+        assert!(
+            (3..=4).contains(&player),
+            "invalid four score player {}",
+            player
+        );
+        &mut self.players[player - 3]
+    }
+
+    pub fn write(&mut self, data: u8) {
+        self.strobe = data & 1 == 1;
+        if self.strobe {
+            self.read_index = [0, 0];
+        }
+        for joypad in self.players.iter_mut() {
+            joypad.write(data);
+        }
+    }
+
+    /// `port` is 0 for $4016 and 1 for $4017.
+    pub fn read(&mut self, port: usize, player1: &mut Joypad, player2: &mut Joypad) -> u8 {
+        let index = self.read_index[port];
+        let response = match (port, index) {
+            (0, 0..=7) => player1.read(),
+            (0, 8..=15) => self.players[0].read(),
+            (1, 0..=7) => player2.read(),
+            (1, 8..=15) => self.players[1].read(),
+            (_, 16..=23) => FOUR_SCORE_SIGNATURE[port][(index - 16) as usize],
+            _ => 1,
+        };
+
+        if !self.strobe && index < 24 {
+            self.read_index[port] += 1;
+        }
+        response
+    }
+}
+
+impl Default for FourScore {
+    fn default() -> Self {
+        FourScore::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn read_port(
+        four_score: &mut FourScore,
+        player1: &mut Joypad,
+        player2: &mut Joypad,
+        port: usize,
+    ) -> Vec<u8> {
+        (0..24)
+            .map(|_| four_score.read(port, player1, player2))
+            .collect()
+    }
+
+    #[test]
+    fn test_debug_state() {
+        let mut joypad = Joypad::new();
+        joypad.set_button_pressed_status(JoypadButton::START, true);
+
+        joypad.write(1);
+        assert_eq!(joypad.debug_state(), (true, 0, 0b0000_1000));
+        joypad.read();
+        assert_eq!(joypad.debug_state(), (true, 0, 0b0000_1000));
+
+        joypad.write(0);
+        joypad.read();
+        joypad.read();
+        assert_eq!(joypad.debug_state(), (false, 2, 0b0000_1000));
+    }
+
+    #[test]
+    fn test_turbo_a() {
+        let mut joypad = Joypad::new();
+        joypad.set_turbo(JoypadButton::BUTTON_A, true);
+        joypad.set_button_pressed_status(JoypadButton::BUTTON_A, true);
+        joypad.set_button_pressed_status(JoypadButton::BUTTON_B, true);
+
+        let mut releases = 0;
+        let mut last_a = 0;
+        for _ in 0..10 {
+            joypad.write(1);
+            joypad.write(0);
+            let a = joypad.read();
+            assert_eq!(joypad.read(), 1); // B has no turbo
+            if last_a == 1 && a == 0 {
+                releases += 1;
+            }
+            last_a = a;
+            joypad.tick_turbo();
+        }
+        assert_eq!(releases, 5);
+    }
+
+    #[test]
+    fn test_four_score_serial_stream() {
+        let mut player1 = Joypad::new();
+        let mut player2 = Joypad::new();
+        let mut four_score = FourScore::new();
+        player1.set_button_pressed_status(JoypadButton::BUTTON_A, true);
+        player2.set_button_pressed_status(JoypadButton::BUTTON_B, true);
+        four_score
+            .joypad_mut(3)
+            .set_button_pressed_status(JoypadButton::START, true);
+        four_score
+            .joypad_mut(4)
+            .set_button_pressed_status(JoypadButton::RIGHT, true);
+
+        for strobe in [1, 0] {
+            player1.write(strobe);
+            player2.write(strobe);
+            four_score.write(strobe);
+        }
+
+        let port0 = read_port(&mut four_score, &mut player1, &mut player2, 0);
+        assert_eq!(&port0[0..8], &[1, 0, 0, 0, 0, 0, 0, 0]); // player 1: A
+        assert_eq!(&port0[8..16], &[0, 0, 0, 1, 0, 0, 0, 0]); // player 3: START
+        assert_eq!(&port0[16..24], &[0, 0, 0, 1, 0, 0, 0, 0]); // signature
+
+        let port1 = read_port(&mut four_score, &mut player1, &mut player2, 1);
+        assert_eq!(&port1[0..8], &[0, 1, 0, 0, 0, 0, 0, 0]); // player 2: B
+        assert_eq!(&port1[8..16], &[0, 0, 0, 0, 0, 0, 0, 1]); // player 4: RIGHT
+        assert_eq!(&port1[16..24], &[0, 0, 1, 0, 0, 0, 0, 0]); // signature
+
+        assert_eq!(four_score.read(0, &mut player1, &mut player2), 1);
+        assert_eq!(four_score.read(1, &mut player1, &mut player2), 1);
+    }
 }
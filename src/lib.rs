@@ -0,0 +1,21 @@
+pub mod bus;
+pub mod cheat;
+pub mod clip;
+pub mod controls;
+pub mod cpu;
+pub mod golden;
+pub mod joypad;
+pub mod limiter;
+pub mod mapper;
+#[cfg(test)]
+mod mapper_test_utils;
+pub mod opcodes;
+pub mod ppu;
+pub mod prelude;
+pub mod render;
+pub mod rewind;
+pub mod rom;
+mod serde_array;
+pub mod trace;
+pub mod worker;
+pub mod zapper;
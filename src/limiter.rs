@@ -0,0 +1,228 @@
+//! Paces emulation to the NES frame rate when vsync can't do it, e.g. with vsync off or on
+//! a display that doesn't run at 60Hz. `SpeedControl` builds slow motion and fast-forward on
+//! top of it.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// NTSC frame rate: 39375000 / 655171 Hz.
+pub const NTSC_FRAME_RATE: f64 = 60.0988;
+/// PAL frame rate.
+pub const PAL_FRAME_RATE: f64 = 50.007;
+
+pub struct FrameLimiter {
+    frame_time: Duration,
+    frame_start: Instant,
+}
+
+impl FrameLimiter {
+    pub fn new(frame_rate: f64) -> Self {
+        FrameLimiter {
+            frame_time: Duration::from_secs_f64(1.0 / frame_rate),
+            frame_start: Instant::now(),
+        }
+    }
+
+    /// Sleeps until the current frame's time is up. Call once per emulated frame.
+    pub fn wait(&mut self) {
+        let elapsed = self.frame_start.elapsed();
+        thread::sleep(sleep_duration(self.frame_time, elapsed));
+
+        // Frames start exactly one frame time apart so sleep overshoot doesn't add up. After
+        // falling behind by a whole frame (a pause, a slow frame), start over from now instead
+        // of running fast to catch up.
+        if elapsed > self.frame_time * 2 {
+            self.frame_start = Instant::now();
+        } else {
+            self.frame_start += self.frame_time;
+        }
+    }
+}
+
+/// Emulation speed relative to the console.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Speed {
+    Quarter,
+    Half,
+    Normal,
+    Double,
+    Quadruple,
+    /// As fast as the host can go.
+    Uncapped,
+}
+
+// Slowest to fastest, the order `SpeedControl::faster` and `slower` step through
+const SPEEDS: [Speed; 6] = [
+    Speed::Quarter,
+    Speed::Half,
+    Speed::Normal,
+    Speed::Double,
+    Speed::Quadruple,
+    Speed::Uncapped,
+];
+
+// Uncapped still shows some frames so there's something to look at
+const UNCAPPED_FRAMES_PER_PRESENT: u32 = 8;
+
+impl Speed {
+    /// How many emulated frames make up one displayed frame. Fast-forward emulates every frame
+    /// but only shows some of them.
+    pub fn frames_per_present(self) -> u32 {
+        match self {
+            Speed::Quarter | Speed::Half | Speed::Normal => 1,
+            Speed::Double => 2,
+            Speed::Quadruple => 4,
+            Speed::Uncapped => UNCAPPED_FRAMES_PER_PRESENT,
+        }
+    }
+
+    /// Wall-clock time of one emulated frame at `frame_rate`, or `None` when uncapped.
+    pub fn frame_time(self, frame_rate: f64) -> Option<Duration> {
+        let factor = match self {
+            Speed::Quarter => 0.25,
+            Speed::Half => 0.5,
+            Speed::Normal => 1.0,
+            Speed::Double => 2.0,
+            Speed::Quadruple => 4.0,
+            Speed::Uncapped => return None,
+        };
+        Some(Duration::from_secs_f64(1.0 / (frame_rate * factor)))
+    }
+}
+
+/// Decides which emulated frames get presented and how long to sleep after each. Call
+/// `should_present` and then `wait` once per emulated frame.
+///
+/// With vsync, presenting already blocks for one display frame, so fast-forward only needs to
+/// skip presents and the limiter is used just to slow down.
+pub struct SpeedControl {
+    frame_rate: f64,
+    vsync: bool,
+    speed: Speed,
+    limiter: FrameLimiter,
+    frame: u32,
+}
+
+impl SpeedControl {
+    pub fn new(frame_rate: f64, vsync: bool) -> Self {
+        SpeedControl {
+            frame_rate,
+            vsync,
+            speed: Speed::Normal,
+            limiter: FrameLimiter::new(frame_rate),
+            frame: 0,
+        }
+    }
+
+    pub fn speed(&self) -> Speed {
+        self.speed
+    }
+
+    pub fn set_speed(&mut self, speed: Speed) {
+        self.speed = speed;
+        self.frame = 0;
+        if let Some(frame_time) = speed.frame_time(self.frame_rate) {
+            self.limiter = FrameLimiter::new(1.0 / frame_time.as_secs_f64());
+        }
+    }
+
+    /// Steps up to the next speed, stopping at `Speed::Uncapped`.
+    pub fn faster(&mut self) {
+        let i = SPEEDS.iter().position(|&s| s == self.speed).unwrap();
+        self.set_speed(SPEEDS[(i + 1).min(SPEEDS.len() - 1)]);
+    }
+
+    /// Steps down to the previous speed, stopping at `Speed::Quarter`.
+    pub fn slower(&mut self) {
+        let i = SPEEDS.iter().position(|&s| s == self.speed).unwrap();
+        self.set_speed(SPEEDS[i.saturating_sub(1)]);
+    }
+
+    /// Whether the frame just emulated should be shown.
+    pub fn should_present(&mut self) -> bool {
+        self.frame = (self.frame + 1) % self.speed.frames_per_present();
+        self.frame == 0
+    }
+
+    /// Sleeps for whatever is left of the current emulated frame's time.
+    pub fn wait(&mut self) {
+        let slowed = matches!(self.speed, Speed::Quarter | Speed::Half);
+        if self.speed != Speed::Uncapped && (!self.vsync || slowed) {
+            self.limiter.wait();
+        }
+    }
+}
+
+/// How long to sleep for a frame of `frame_time` of which `elapsed` has been spent emulating.
+fn sleep_duration(frame_time: Duration, elapsed: Duration) -> Duration {
+    frame_time.saturating_sub(elapsed)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_speed_frame_budget() {
+        let ntsc = Duration::from_secs_f64(1.0 / NTSC_FRAME_RATE);
+        assert_eq!(Speed::Normal.frame_time(NTSC_FRAME_RATE), Some(ntsc));
+        assert_eq!(
+            Speed::Quarter
+                .frame_time(NTSC_FRAME_RATE)
+                .unwrap()
+                .as_micros(),
+            66557
+        );
+        assert_eq!(
+            Speed::Double
+                .frame_time(NTSC_FRAME_RATE)
+                .unwrap()
+                .as_micros(),
+            8319
+        );
+        assert_eq!(Speed::Uncapped.frame_time(NTSC_FRAME_RATE), None);
+
+        let mut speed = SpeedControl::new(NTSC_FRAME_RATE, true);
+        assert!((0..4).all(|_| speed.should_present()));
+
+        speed.faster();
+        speed.faster();
+        assert_eq!(speed.speed(), Speed::Quadruple);
+        let presented: Vec<bool> = (0..8).map(|_| speed.should_present()).collect();
+        assert_eq!(
+            presented,
+            [false, false, false, true, false, false, false, true]
+        );
+
+        speed.faster();
+        speed.faster();
+        assert_eq!(speed.speed(), Speed::Uncapped);
+        for _ in 0..6 {
+            speed.slower();
+        }
+        assert_eq!(speed.speed(), Speed::Quarter);
+    }
+
+    #[test]
+    fn test_sleep_duration() {
+        let ntsc = Duration::from_secs_f64(1.0 / NTSC_FRAME_RATE);
+        let pal = Duration::from_secs_f64(1.0 / PAL_FRAME_RATE);
+        assert_eq!(ntsc.as_micros(), 16639);
+        assert_eq!(pal.as_micros(), 19997);
+
+        assert_eq!(sleep_duration(ntsc, Duration::ZERO), ntsc);
+        assert_eq!(
+            sleep_duration(ntsc, Duration::from_millis(10)).as_micros(),
+            6639
+        );
+        assert_eq!(
+            sleep_duration(pal, Duration::from_millis(10)).as_micros(),
+            9997
+        );
+        // Already late: no sleep
+        assert_eq!(
+            sleep_duration(ntsc, Duration::from_millis(20)),
+            Duration::ZERO
+        );
+    }
+}
@@ -1,32 +1,42 @@
-mod bus;
-mod cpu;
-mod joypad;
-mod opcodes;
-mod ppu;
-mod render;
-mod rom;
-mod trace;
-
-use std::collections::HashMap;
+use std::cell::{Cell, RefCell};
 use std::fs::File;
 use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use bus::Bus;
-use cpu::Mem;
-use cpu::CPU;
-use joypad::Joypad;
-use joypad::JoypadButton;
-use ppu::NesPPU;
+use nes::bus::Bus;
+use nes::clip::ClipRecorder;
+use nes::controls::{self, Controls};
+use nes::cpu::Mem;
+use nes::cpu::CPU;
+use nes::joypad::Joypad;
+use nes::joypad::JoypadButton;
+use nes::limiter::{Speed, SpeedControl, NTSC_FRAME_RATE, PAL_FRAME_RATE};
+use nes::ppu::NesPPU;
+use nes::render;
+use nes::render::frame::show_tile;
+use nes::render::frame::Frame;
+use nes::render::palette::{self, NTSC_2C02, SYSTEM_PALLETE};
+use nes::render::viewport::{self, letterbox, HEIGHT, SCALES, WIDTH};
+use nes::rom::{Region, Rom};
+use nes::trace::trace;
+use nes::zapper::Zapper;
 use rand::Rng;
-use render::frame::show_tile;
-use render::frame::Frame;
-use rom::Rom;
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
+use sdl2::keyboard::Mod;
+use sdl2::mouse::MouseButton;
 use sdl2::pixels::Color;
 use sdl2::pixels::PixelFormatEnum;
+use sdl2::video::FullscreenType;
 use sdl2::EventPump;
-use trace::trace;
+
+// With vsync off, frames are paced by SpeedControl instead
+const VSYNC: bool = true;
+// How far back Backspace can rewind
+const REWIND_SECONDS: f64 = 10.0;
 
 fn color(byte: u8) -> Color {
     match byte {
@@ -59,6 +69,16 @@ fn read_screen_state(cpu: &mut CPU, frame: &mut [u8; 32 * 3 * 32]) -> bool {
     update
 }
 
+// Screenshots (F12) and clips (F10) go to the working directory, named by the time they
+// were taken
+fn capture_path(kind: &str, extension: &str) -> PathBuf {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    PathBuf::from(format!("{}-{}.{}", kind, secs, extension))
+}
+
 fn handle_user_input(cpu: &mut CPU, event_pump: &mut EventPump) {
     for event in event_pump.poll_iter() {
         match event {
@@ -96,81 +116,387 @@ fn handle_user_input(cpu: &mut CPU, event_pump: &mut EventPump) {
     }
 }
 
+// `--overscan` hides the 8 rows at the top and bottom that most TVs cut off, and
+// `--overscan=top,bottom,left,right` crops any edges. Returns the crop in that order
+fn overscan_arg() -> (usize, usize, usize, usize) {
+    for arg in std::env::args().skip(1) {
+        if arg == "--overscan" {
+            return (8, 8, 0, 0);
+        }
+        if let Some(edges) = arg.strip_prefix("--overscan=") {
+            let edges: Vec<usize> = edges.split(',').filter_map(|e| e.parse().ok()).collect();
+            match edges[..] {
+                [top, bottom, left, right] if top + bottom < 240 && left + right < 256 => {
+                    return (top, bottom, left, right)
+                }
+                _ => eprintln!("Ignoring {}: expected four edges that leave a picture", arg),
+            }
+        }
+    }
+    (0, 0, 0, 0)
+}
+
 fn main() {
+    let (top, bottom, left, right) = overscan_arg();
+    let frame_size = (
+        WIDTH - (left + right) as u32,
+        HEIGHT - (top + bottom) as u32,
+    );
+
     // init sdl2
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
+    // F9 cycles the window scale and F11 toggles borderless fullscreen
+    let mut scale = 3;
+    let mut fullscreen = false;
     let window = video_subsystem
-        .window("NES Emulator", (256.0 * 3.0) as u32, (240.0 * 3.0) as u32)
+        .window("NES Emulator", frame_size.0 * scale, frame_size.1 * scale)
         .position_centered()
         .build()
         .unwrap();
 
-    let mut canvas = window.into_canvas().present_vsync().build().unwrap();
+    let mut canvas_builder = window.into_canvas();
+    if VSYNC {
+        canvas_builder = canvas_builder.present_vsync();
+    }
+    let mut canvas = canvas_builder.build().unwrap();
     let mut event_pump = sdl_context.event_pump().unwrap();
-    canvas.set_scale(3.0, 3.0).unwrap();
 
     let creator = canvas.texture_creator();
     let mut texture = creator
-        .create_texture_target(PixelFormatEnum::RGB24, 256, 240)
+        .create_texture_target(PixelFormatEnum::RGB24, frame_size.0, frame_size.1)
         .unwrap();
 
     // init joypad
-    let mut key_map = HashMap::new();
-    key_map.insert(Keycode::Down, JoypadButton::DOWN);
-    key_map.insert(Keycode::Up, JoypadButton::UP);
-    key_map.insert(Keycode::Right, JoypadButton::RIGHT);
-    key_map.insert(Keycode::Left, JoypadButton::LEFT);
-    key_map.insert(Keycode::Space, JoypadButton::SELECT);
-    key_map.insert(Keycode::Return, JoypadButton::START);
-    key_map.insert(Keycode::A, JoypadButton::BUTTON_A);
-    key_map.insert(Keycode::S, JoypadButton::BUTTON_B);
+    let controls_path = Path::new("controls.toml");
+    let controls = match controls::load_controls(controls_path) {
+        Ok((controls, problems)) => {
+            for problem in problems {
+                eprintln!("{}: {}", controls_path.display(), problem);
+            }
+            controls
+        }
+        Err(e) => {
+            eprintln!("{}: {}", controls_path.display(), e);
+            Controls::default()
+        }
+    };
+    let key_map = controls.player1;
+    let key_map2 = controls.player2;
 
     //load the game
-    let raw = std::fs::read("mario.nes").unwrap();
-    let rom = Rom::new(&raw).unwrap();
+    let rom_path = Path::new("mario.nes");
+    let raw = std::fs::read(rom_path).unwrap();
+    let mut rom = match Rom::new(&raw) {
+        Ok(rom) => rom,
+        Err(e) => {
+            eprintln!("{}: {}", rom_path.display(), e);
+            std::process::exit(1);
+        }
+    };
+    // The header's TV system can be overridden with --pal or --ntsc.
+    // --zapper plugs a light gun into port 2, aimed with the mouse.
+    // --palette=<file.pal> adds a palette in front of the built-in ones C cycles through
+    let mut zapper = None;
+    let mut palettes = vec![
+        SYSTEM_PALLETE,
+        NTSC_2C02,
+        palette::greyscale(&SYSTEM_PALLETE),
+    ];
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--pal" => rom.region = Region::Pal,
+            "--ntsc" => rom.region = Region::Ntsc,
+            "--zapper" => zapper = Some(Rc::new(RefCell::new(Zapper::new()))),
+            _ => {
+                if let Some(path) = arg.strip_prefix("--palette=") {
+                    match palette::load_pal(Path::new(path)) {
+                        Ok(loaded) => palettes.insert(0, loaded),
+                        Err(e) => eprintln!("Ignoring {}: {}", path, e),
+                    }
+                }
+            }
+        }
+    }
+    let zapper_mouse = zapper.clone();
+    // Battery-backed PRG-RAM lives next to the ROM as a .sav file
+    let save_path = rom.battery.then(|| rom_path.with_extension("sav"));
 
     let mut frame = Frame::new();
+    let mut palette_idx = 0;
+    frame.set_palette(palettes[palette_idx]);
+    // Frame blending (toggled with B) averages each frame with the previous one to hide sprite flicker
+    let mut blend_frames = false;
+    let mut previous_frame = Frame::new();
+    let mut blended_frame = Frame::new();
+    // G toggles the attribute grid overlay for background palette debugging
+    let mut attribute_overlay = false;
+    // Shift+R power-cycles. The bus callback can't reach the CPU, so it only raises a flag
+    let power_cycle_requested = Rc::new(Cell::new(false));
+    let power_cycle_key = power_cycle_requested.clone();
+    // Quitting also goes through a flag, so main returns normally and destructors run
+    let quit_requested = Rc::new(Cell::new(false));
+    let quit_key = quit_requested.clone();
+    // F10 starts and stops recording a GIF clip
+    let mut clip: Option<ClipRecorder> = None;
+    // T toggles autofire on player 1's A and B
+    let mut turbo = false;
+    // P pauses; while paused, Space advances one frame instead of pressing Select
+    let mut paused = false;
+    // Holding Backspace rewinds, one frame per frame
+    let rewind_held = Rc::new(Cell::new(false));
+    let rewind_key = rewind_held.clone();
+    let frame_rate = match rom.region {
+        Region::Ntsc => NTSC_FRAME_RATE,
+        Region::Pal => PAL_FRAME_RATE,
+    };
+    // - slows down and = speeds up, down to 0.25x and up to uncapped. 0 goes back to 1x
+    let mut speed = SpeedControl::new(frame_rate, VSYNC);
+
+    let bus = Bus::new(
+        rom,
+        move |ppu: &NesPPU, joypad: &mut Joypad, joypad2: &mut Joypad| {
+            render::render(ppu, &mut frame);
+            if let Some(zapper) = &zapper_mouse {
+                zapper.borrow_mut().update(&frame);
+            }
+            if attribute_overlay {
+                render::render_attribute_overlay(ppu, &mut frame);
+            }
+            let shown = if blend_frames {
+                blended_frame.blend(&frame, &previous_frame);
+                &blended_frame
+            } else {
+                &frame
+            };
+            texture
+                .update(
+                    None,
+                    &shown.cropped_view(top, bottom, left, right),
+                    frame_size.0 as usize * 3,
+                )
+                .unwrap();
+            previous_frame.data.copy_from_slice(&frame.data);
+            if let Some(clip) = &mut clip {
+                clip.push(&frame);
+            }
+
+            if speed.should_present() {
+                let (width, height) = canvas.output_size().unwrap();
+                canvas.set_draw_color(Color::BLACK);
+                canvas.clear();
+                canvas
+                    .copy(&texture, None, letterbox((width, height), frame_size))
+                    .unwrap();
+                canvas.present();
+            }
+            speed.wait();
+
+            joypad.tick_turbo();
+            joypad2.tick_turbo();
+            // While paused, the frame is held here until P resumes or Space steps one frame
+            let mut step = false;
+            loop {
+                for event in event_pump.poll_iter() {
+                    match event {
+                        Event::Quit { .. }
+                        | Event::KeyDown {
+                            keycode: Some(Keycode::Escape),
+                            ..
+                        } => quit_key.set(true),
+
+                        Event::KeyDown {
+                            keycode: Some(Keycode::B),
+                            ..
+                        } => blend_frames = !blend_frames,
+
+                        Event::KeyDown {
+                            keycode: Some(Keycode::G),
+                            ..
+                        } => attribute_overlay = !attribute_overlay,
+
+                        Event::KeyDown {
+                            keycode: Some(Keycode::P),
+                            ..
+                        } => paused = !paused,
+                        Event::KeyDown {
+                            keycode: Some(Keycode::Space),
+                            ..
+                        } if paused => step = true,
+
+                        Event::KeyDown {
+                            keycode: Some(Keycode::C),
+                            ..
+                        } => {
+                            palette_idx = (palette_idx + 1) % palettes.len();
+                            frame.set_palette(palettes[palette_idx]);
+                        }
+
+                        Event::KeyDown {
+                            keycode: Some(Keycode::Minus),
+                            ..
+                        } => speed.slower(),
+                        Event::KeyDown {
+                            keycode: Some(Keycode::Equals),
+                            ..
+                        } => speed.faster(),
+                        Event::KeyDown {
+                            keycode: Some(Keycode::Num0),
+                            ..
+                        } => speed.set_speed(Speed::Normal),
 
-    let bus = Bus::new(rom, move |ppu: &NesPPU, joypad: &mut Joypad| {
-        render::render(ppu, &mut frame);
-        texture.update(None, &frame.data, 256 * 3).unwrap();
+                        Event::KeyDown {
+                            keycode: Some(Keycode::F12),
+                            ..
+                        } => {
+                            let path = capture_path("screenshot", "png");
+                            if let Err(e) = frame.to_png(&path) {
+                                eprintln!("Failed to write {}: {}", path.display(), e);
+                            }
+                        }
 
-        canvas.copy(&texture, None, None).unwrap();
-        canvas.present();
+                        Event::KeyDown {
+                            keycode: Some(Keycode::F10),
+                            ..
+                        } => match clip.take() {
+                            None => clip = Some(ClipRecorder::new()),
+                            Some(recorded) => {
+                                let path = capture_path("clip", "gif");
+                                if let Err(e) = recorded.write_gif(&path) {
+                                    eprintln!("Failed to write {}: {}", path.display(), e);
+                                }
+                            }
+                        },
 
-        for event in event_pump.poll_iter() {
-            match event {
-                Event::Quit { .. }
-                | Event::KeyDown {
-                    keycode: Some(Keycode::Escape),
-                    ..
-                } => std::process::exit(0),
+                        Event::KeyDown {
+                            keycode: Some(Keycode::F11),
+                            ..
+                        } => {
+                            fullscreen = !fullscreen;
+                            let mode = if fullscreen {
+                                FullscreenType::Desktop
+                            } else {
+                                FullscreenType::Off
+                            };
+                            canvas.window_mut().set_fullscreen(mode).unwrap();
+                        }
+                        Event::KeyDown {
+                            keycode: Some(Keycode::F9),
+                            ..
+                        } => {
+                            scale = scale % SCALES.end() + 1;
+                            if !fullscreen {
+                                canvas
+                                    .window_mut()
+                                    .set_size(frame_size.0 * scale, frame_size.1 * scale)
+                                    .unwrap();
+                            }
+                        }
 
-                Event::KeyDown { keycode, .. } => {
-                    if let Some(key) = key_map.get(&keycode.unwrap_or(Keycode::Ampersand)) {
-                        joypad.set_button_pressed_status(*key, true);
+                        Event::KeyDown {
+                            keycode: Some(Keycode::T),
+                            ..
+                        } => {
+                            turbo = !turbo;
+                            joypad
+                                .set_turbo(JoypadButton::BUTTON_A | JoypadButton::BUTTON_B, turbo);
+                        }
+
+                        Event::KeyDown {
+                            keycode: Some(Keycode::R),
+                            keymod,
+                            ..
+                        } if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) => {
+                            power_cycle_key.set(true)
+                        }
+
+                        Event::KeyDown {
+                            keycode: Some(Keycode::Backspace),
+                            ..
+                        } => rewind_key.set(true),
+                        Event::KeyUp {
+                            keycode: Some(Keycode::Backspace),
+                            ..
+                        } => rewind_key.set(false),
+
+                        Event::KeyDown { keycode, .. } => {
+                            let keycode = keycode.unwrap_or(Keycode::Ampersand);
+                            if let Some(key) = key_map.get(&keycode) {
+                                joypad.set_button_pressed_status(*key, true);
+                            }
+                            if let Some(key) = key_map2.get(&keycode) {
+                                joypad2.set_button_pressed_status(*key, true);
+                            }
+                        }
+                        Event::KeyUp { keycode, .. } => {
+                            let keycode = keycode.unwrap_or(Keycode::Ampersand);
+                            if let Some(key) = key_map.get(&keycode) {
+                                joypad.set_button_pressed_status(*key, false);
+                            }
+                            if let Some(key) = key_map2.get(&keycode) {
+                                joypad2.set_button_pressed_status(*key, false);
+                            }
+                        }
+
+                        Event::MouseMotion { x, y, .. } => {
+                            if let Some(zapper) = &zapper_mouse {
+                                let (width, height) = canvas.window().size();
+                                let rect = letterbox((width, height), frame_size);
+                                let (x, y) = viewport::window_to_frame(rect, frame_size, x, y);
+                                zapper.borrow_mut().set_position(x + left, y + top);
+                            }
+                        }
+                        Event::MouseButtonDown {
+                            mouse_btn: MouseButton::Left,
+                            ..
+                        } => {
+                            if let Some(zapper) = &zapper_mouse {
+                                zapper.borrow_mut().pull_trigger();
+                            }
+                        }
+
+                        _ => { /* do nothing */ }
                     }
                 }
-                Event::KeyUp { keycode, .. } => {
-                    if let Some(key) = key_map.get(&keycode.unwrap_or(Keycode::Ampersand)) {
-                        joypad.set_button_pressed_status(*key, false);
-                    }
+                if !paused || step || quit_key.get() {
+                    break;
                 }
-
-                _ => { /* do nothing */ }
+                thread::sleep(Duration::from_millis(10));
             }
-        }
-    });
+        },
+    );
 
     let mut cpu = CPU::new(bus);
+    cpu.bus.set_zapper(zapper);
+    if let Some(save_path) = &save_path {
+        if let Ok(save) = std::fs::read(save_path) {
+            if let Err(e) = cpu.bus.load_sram(&save) {
+                eprintln!("Ignoring {}: {}", save_path.display(), e);
+            }
+        }
+    }
+    cpu.enable_rewind((REWIND_SECONDS * frame_rate) as usize);
     cpu.reset();
-    cpu.run();
+    while !quit_requested.get() && cpu.run_one_frame() {
+        if power_cycle_requested.replace(false) {
+            cpu.power_cycle();
+        }
+        if rewind_held.get() {
+            cpu.rewind_one();
+        } else {
+            cpu.push_rewind_frame();
+        }
+    }
+
+    if let Some(save_path) = &save_path {
+        if let Err(e) = std::fs::write(save_path, cpu.bus.save_sram()) {
+            eprintln!("Failed to write {}: {}", save_path.display(), e);
+        }
+    }
 
     /*
     let mut cpu = CPU::new(Bus::new(rom));
-    cpu.reset();
-    cpu.program_counter = 0xc000;
+    cpu.reset_to(0xc000);
 
     let mut screen_state = [0 as u8; 32 * 3 * 32];
     let mut rng = rand::thread_rng();
@@ -202,8 +528,7 @@ fn main() {
     let bus = Bus::new(rom, |_| {});
 
     let mut cpu = CPU::new(bus);
-    cpu.reset();
-    cpu.program_counter = 0xc000;
+    cpu.reset_to(0xc000);
 
     cpu.run_with_callback(move |cpu| {
         let log = trace(cpu) + "\n";
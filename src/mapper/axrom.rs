@@ -0,0 +1,106 @@
+use serde::{Deserialize, Serialize};
+
+use crate::rom::Mirroring;
+
+use super::{chr_memory, CartridgeRom, Chr, Mapper};
+
+const PRG_BANK_SIZE: usize = 0x8000;
+
+/// Mapper 7 (AxROM). A write to $8000-$FFFF selects the 32KB PRG bank with bits 0-2 and the
+/// single-screen nametable page with bit 4. CHR is 8KB RAM.
+#[derive(Serialize, Deserialize)]
+pub struct AxRom {
+    #[serde(skip)]
+    prg_rom: Vec<u8>,
+    chr: Chr,
+    prg_bank: u8,
+    mirroring: Mirroring,
+}
+
+impl AxRom {
+    pub fn new(prg_rom: Vec<u8>, chr_rom: Vec<u8>) -> Self {
+        let chr = chr_memory(chr_rom);
+        AxRom {
+            prg_rom,
+            chr,
+            prg_bank: 0,
+            mirroring: Mirroring::SingleScreenLower,
+        }
+    }
+}
+
+impl CartridgeRom for AxRom {
+    fn take_rom(&mut self, running: &mut Self) {
+        self.prg_rom = std::mem::take(&mut running.prg_rom);
+        self.chr.take_rom(&mut running.chr);
+    }
+}
+
+impl Mapper for AxRom {
+    fn cpu_read(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x8000..=0xffff => {
+                let banks = self.prg_rom.len() / PRG_BANK_SIZE;
+                let bank = self.prg_bank as usize % banks;
+                self.prg_rom[bank * PRG_BANK_SIZE + (addr - 0x8000) as usize]
+            }
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        if let 0x8000..=0xffff = addr {
+            self.prg_bank = data & 0b0111;
+            self.mirroring = if data & 0b1_0000 == 0 {
+                Mirroring::SingleScreenLower
+            } else {
+                Mirroring::SingleScreenUpper
+            };
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr[addr as usize]
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        if self.chr.is_ram {
+            self.chr[addr as usize] = data;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn reset(&mut self) {
+        self.prg_bank = 0;
+        self.mirroring = Mirroring::SingleScreenLower;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::mapper_test_utils::*;
+    use crate::rom::Mirroring;
+
+    #[test]
+    fn test_prg_bank_and_single_screen() {
+        // 128KB PRG: 32KB banks 0-3, 8KB tags 0-15
+        let mut bus = test_bus(7, 8, 0);
+        assert_prg_bank(&mut bus, 0x8000, 0);
+        assert_eq!(bus.ppu().mirroring(), Mirroring::SingleScreenLower);
+
+        write_register(&mut bus, 0x8000, 0b1_0010);
+        assert_prg_bank(&mut bus, 0x8000, 8);
+        assert_prg_bank(&mut bus, 0xe000, 11);
+        assert_eq!(bus.ppu().mirroring(), Mirroring::SingleScreenUpper);
+        for nametable in [0x2000, 0x2400, 0x2800, 0x2c00] {
+            assert_eq!(bus.ppu().mirror_vram_addr(nametable + 0x123), 0x523);
+        }
+
+        write_register(&mut bus, 0x8000, 0b0_0001);
+        assert_prg_bank(&mut bus, 0x8000, 4);
+        assert_eq!(bus.ppu().mirror_vram_addr(0x2c00), 0x000);
+    }
+}
@@ -0,0 +1,98 @@
+use serde::{Deserialize, Serialize};
+
+use crate::rom::Mirroring;
+
+use super::{CartridgeRom, Mapper};
+
+const CHR_BANK_SIZE: usize = 0x2000;
+
+/// Mapper 3 (CNROM). PRG is fixed as on NROM; a write to $8000-$FFFF selects the 8KB CHR
+/// bank. The board has bus conflicts: the ROM drives the data bus during the write too, so
+/// the bank number is ANDed with the ROM byte at the written address. Games write to a
+/// table holding the value itself to avoid that.
+#[derive(Serialize, Deserialize)]
+pub struct Cnrom {
+    #[serde(skip)]
+    prg_rom: Vec<u8>,
+    #[serde(skip)]
+    chr_rom: Vec<u8>,
+    mirroring: Mirroring,
+    chr_bank: u8,
+}
+
+impl Cnrom {
+    pub fn new(prg_rom: Vec<u8>, chr_rom: Vec<u8>, mirroring: Mirroring) -> Self {
+        Cnrom {
+            prg_rom,
+            chr_rom,
+            mirroring,
+            chr_bank: 0,
+        }
+    }
+
+    fn read_prg_rom(&self, addr: u16) -> u8 {
+        self.prg_rom[(addr - 0x8000) as usize % self.prg_rom.len()]
+    }
+}
+
+impl CartridgeRom for Cnrom {
+    fn take_rom(&mut self, running: &mut Self) {
+        self.prg_rom = std::mem::take(&mut running.prg_rom);
+        self.chr_rom = std::mem::take(&mut running.chr_rom);
+    }
+}
+
+impl Mapper for Cnrom {
+    fn cpu_read(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x8000..=0xffff => self.read_prg_rom(addr),
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        if let 0x8000..=0xffff = addr {
+            self.chr_bank = data & self.read_prg_rom(addr);
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        let banks = self.chr_rom.len() / CHR_BANK_SIZE;
+        let bank = self.chr_bank as usize % banks;
+        self.chr_rom[bank * CHR_BANK_SIZE + addr as usize]
+    }
+
+    fn ppu_write(&mut self, _addr: u16, _data: u8) {}
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn reset(&mut self) {
+        self.chr_bank = 0;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bus::Bus;
+    use crate::mapper_test_utils::*;
+
+    #[test]
+    fn test_chr_bank_switch() {
+        // 32KB CHR: 8KB banks 0-3, 1KB tags 0-31
+        let mut rom = build_rom(3, 2, 4);
+        rom.prg_rom[0x7ff0] = 0xff;
+        let mut bus = Bus::new(rom, |_, _, _| {});
+        assert_eq!(bus.ppu().chr_tile(0x0000), [0; 16]);
+
+        write_register(&mut bus, 0xfff0, 2);
+        assert_eq!(bus.ppu().chr_tile(0x0000), [16; 16]);
+        assert_eq!(bus.ppu().chr_tile(0x1ff0), [23; 16]);
+        assert_chr_bank(&mut bus, 0x0400, 17);
+
+        // bus conflict: the ROM byte at $8000 is 0
+        write_register(&mut bus, 0x8000, 3);
+        assert_eq!(bus.ppu().chr_tile(0x0000), [0; 16]);
+    }
+}
@@ -0,0 +1,269 @@
+use serde::{Deserialize, Serialize};
+
+use crate::rom::Mirroring;
+
+use super::{chr_memory, CartridgeRom, Chr, Mapper};
+
+const PRG_BANK_SIZE: usize = 0x4000;
+const CHR_BANK_SIZE: usize = 0x1000;
+
+/// Mapper 1 (MMC1, SxROM boards). Registers are loaded serially: each write to $8000-$FFFF
+/// shifts bit 0 into a 5-bit shift register, and the fifth write copies it into the register
+/// selected by address bits 13-14. A write with bit 7 set resets the shift register.
+///
+/// ```text
+/// $8000-$9FFF  control   CPPMM  C: CHR mode, PP: PRG mode, MM: mirroring
+/// $A000-$BFFF  CHR bank 0
+/// $C000-$DFFF  CHR bank 1
+/// $E000-$FFFF  PRG bank
+/// ```
+///
+/// Not emulated: the 256KB outer PRG bank of SUROM/SXROM, PRG-RAM enable bit and ignoring
+/// writes on consecutive CPU cycles.
+#[derive(Serialize, Deserialize)]
+pub struct Mmc1 {
+    #[serde(skip)]
+    prg_rom: Vec<u8>,
+    #[serde(with = "crate::serde_array")]
+    prg_ram: [u8; 0x2000],
+    chr: Chr,
+
+    shift: u8,
+    shift_count: u8,
+    control: u8,
+    chr_bank_0: u8,
+    chr_bank_1: u8,
+    prg_bank: u8,
+}
+
+impl Mmc1 {
+    pub fn new(prg_rom: Vec<u8>, chr_rom: Vec<u8>) -> Self {
+        let chr = chr_memory(chr_rom);
+        Mmc1 {
+            prg_rom,
+            prg_ram: [0; 0x2000],
+            chr,
+            shift: 0,
+            shift_count: 0,
+            // Power on with the last PRG bank fixed at $C000 so the reset vector is there
+            control: 0b0_1100,
+            chr_bank_0: 0,
+            chr_bank_1: 0,
+            prg_bank: 0,
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x8000..=0x9fff => self.control = value,
+            0xa000..=0xbfff => self.chr_bank_0 = value,
+            0xc000..=0xdfff => self.chr_bank_1 = value,
+            0xe000..=0xffff => self.prg_bank = value,
+            _ => unreachable!(),
+        }
+    }
+
+    fn prg_offset(&self, addr: u16) -> usize {
+        let banks = self.prg_rom.len() / PRG_BANK_SIZE;
+        let bank = (self.prg_bank & 0b1111) as usize;
+        let upper_half = addr >= 0xc000;
+
+        let bank = match (self.control >> 2) & 0b11 {
+            // 32KB mode: the low bit of the bank number is ignored
+            0 | 1 => (bank & !1) + upper_half as usize,
+            // first bank fixed at $8000, switch $C000
+            2 if !upper_half => 0,
+            2 => bank,
+            // switch $8000, last bank fixed at $C000
+            3 if upper_half => banks - 1,
+            3 => bank,
+            _ => unreachable!(),
+        };
+
+        (bank % banks) * PRG_BANK_SIZE + (addr as usize & (PRG_BANK_SIZE - 1))
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        let banks = self.chr.len() / CHR_BANK_SIZE;
+        let upper_half = addr >= 0x1000;
+
+        let bank = if self.control & 0b1_0000 == 0 {
+            // 8KB mode: the low bit of the bank number is ignored
+            (self.chr_bank_0 & !1) as usize + upper_half as usize
+        } else if upper_half {
+            self.chr_bank_1 as usize
+        } else {
+            self.chr_bank_0 as usize
+        };
+
+        (bank % banks) * CHR_BANK_SIZE + (addr as usize & (CHR_BANK_SIZE - 1))
+    }
+}
+
+impl CartridgeRom for Mmc1 {
+    fn take_rom(&mut self, running: &mut Self) {
+        self.prg_rom = std::mem::take(&mut running.prg_rom);
+        self.chr.take_rom(&mut running.chr);
+    }
+}
+
+impl Mapper for Mmc1 {
+    fn cpu_read(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7fff => self.prg_ram[(addr - 0x6000) as usize],
+            0x8000..=0xffff => self.prg_rom[self.prg_offset(addr)],
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7fff => self.prg_ram[(addr - 0x6000) as usize] = data,
+            0x8000..=0xffff => {
+                if data & 0b1000_0000 != 0 {
+                    self.shift = 0;
+                    self.shift_count = 0;
+                    self.control |= 0b0_1100;
+                    return;
+                }
+
+                self.shift |= (data & 1) << self.shift_count;
+                self.shift_count += 1;
+                if self.shift_count == 5 {
+                    self.write_register(addr, self.shift);
+                    self.shift = 0;
+                    self.shift_count = 0;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr[self.chr_offset(addr)]
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        if self.chr.is_ram {
+            let offset = self.chr_offset(addr);
+            self.chr[offset] = data;
+        }
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.prg_ram
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.control & 0b11 {
+            0 => Mirroring::SingleScreenLower,
+            1 => Mirroring::SingleScreenUpper,
+            2 => Mirroring::Vertical,
+            3 => Mirroring::Horizontal,
+            _ => unreachable!(),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.shift = 0;
+        self.shift_count = 0;
+        self.control = 0b0_1100;
+        self.chr_bank_0 = 0;
+        self.chr_bank_1 = 0;
+        self.prg_bank = 0;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cpu::Mem;
+    use crate::mapper_test_utils::*;
+
+    // Loads a register through the serial port, low bit first
+    fn write_serial(bus: &mut Bus, addr: u16, value: u8) {
+        for i in 0..5 {
+            write_register(bus, addr, (value >> i) & 1);
+        }
+    }
+
+    #[test]
+    fn test_prg_banks() {
+        // 128KB PRG: 16KB banks 0-7, 8KB tags 0-15
+        let mut bus = test_bus(1, 8, 2);
+
+        // power on: mode 3, last bank fixed at $C000
+        assert_prg_bank(&mut bus, 0x8000, 0);
+        assert_prg_bank(&mut bus, 0xc000, 14);
+        assert_prg_bank(&mut bus, 0xe000, 15);
+
+        write_serial(&mut bus, 0xe000, 3);
+        assert_prg_bank(&mut bus, 0x8000, 6);
+        assert_prg_bank(&mut bus, 0xa000, 7);
+        assert_prg_bank(&mut bus, 0xc000, 14);
+
+        // mode 2: first bank fixed at $8000, switch $C000
+        write_serial(&mut bus, 0x8000, 0b0_1000);
+        assert_prg_bank(&mut bus, 0x8000, 0);
+        assert_prg_bank(&mut bus, 0xc000, 6);
+
+        // mode 0: 32KB, the low bit of the bank is ignored
+        write_serial(&mut bus, 0x8000, 0b0_0000);
+        assert_prg_bank(&mut bus, 0x8000, 4);
+        assert_prg_bank(&mut bus, 0xc000, 6);
+    }
+
+    #[test]
+    fn test_chr_banks() {
+        // 16KB CHR: 4KB banks 0-3, 1KB tags 0-15
+        let mut bus = test_bus(1, 2, 2);
+
+        // two 4KB banks
+        write_serial(&mut bus, 0x8000, 0b1_1100);
+        write_serial(&mut bus, 0xa000, 1);
+        write_serial(&mut bus, 0xc000, 2);
+        assert_chr_bank(&mut bus, 0x0000, 4);
+        assert_chr_bank(&mut bus, 0x1000, 8);
+
+        // one 8KB bank, the low bit of CHR bank 0 is ignored
+        write_serial(&mut bus, 0x8000, 0b0_1100);
+        write_serial(&mut bus, 0xa000, 3);
+        assert_chr_bank(&mut bus, 0x0000, 8);
+        assert_chr_bank(&mut bus, 0x1c00, 15);
+    }
+
+    #[test]
+    fn test_mirroring_and_reset() {
+        let mut bus = test_bus(1, 2, 1);
+
+        write_serial(&mut bus, 0x8000, 0b0_1110);
+        assert_eq!(bus.ppu().mirroring(), Mirroring::Vertical);
+        write_serial(&mut bus, 0x8000, 0b0_1101);
+        assert_eq!(bus.ppu().mirroring(), Mirroring::SingleScreenUpper);
+
+        // bit 7 drops the partial value and a full write starts over
+        write_register(&mut bus, 0x8000, 1);
+        write_register(&mut bus, 0x8000, 1);
+        write_register(&mut bus, 0x8000, 0x80);
+        write_serial(&mut bus, 0x8000, 0b0_1111);
+        assert_eq!(bus.ppu().mirroring(), Mirroring::Horizontal);
+    }
+
+    #[test]
+    fn test_chr_ram_and_prg_ram() {
+        let mut bus = test_bus(1, 2, 0);
+
+        bus.mem_write(0x2006, 0x01);
+        bus.mem_write(0x2006, 0x23);
+        bus.mem_write(0x2007, 0x5a);
+        bus.mem_write(0x6000, 0xa5);
+
+        assert_chr_bank(&mut bus, 0x0123, 0x5a);
+        assert_eq!(bus.mem_read(0x6000), 0xa5);
+    }
+}
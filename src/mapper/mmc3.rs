@@ -0,0 +1,288 @@
+use serde::{Deserialize, Serialize};
+
+use crate::rom::Mirroring;
+
+use super::{chr_memory, CartridgeRom, Chr, Mapper};
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+
+/// Mapper 4 (MMC3, TxROM boards). Registers are selected by address range and A0:
+///
+/// ```text
+///             even             odd
+/// $8000-$9FFF bank select      bank data
+/// $A000-$BFFF mirroring        PRG-RAM protect
+/// $C000-$DFFF IRQ latch        IRQ reload
+/// $E000-$FFFF IRQ disable/ack  IRQ enable
+/// ```
+///
+/// The IRQ counter is clocked by rising edges of PPU A12, which with the usual setup
+/// (background at $0000, sprites at $1000) happens once per rendered scanline. When it is
+/// clocked at zero it reloads from the latch, otherwise it counts down; reaching zero with
+/// IRQs enabled asserts the IRQ line until $E000 is written.
+///
+/// Not emulated: PRG-RAM protection (RAM is always enabled and writable).
+#[derive(Serialize, Deserialize)]
+pub struct Mmc3 {
+    #[serde(skip)]
+    prg_rom: Vec<u8>,
+    #[serde(with = "crate::serde_array")]
+    prg_ram: [u8; 0x2000],
+    chr: Chr,
+    header_mirroring: Mirroring,
+
+    bank_select: u8,
+    registers: [u8; 8],
+    mirroring: Mirroring,
+
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    irq_pending: bool,
+}
+
+impl Mmc3 {
+    pub fn new(prg_rom: Vec<u8>, chr_rom: Vec<u8>, mirroring: Mirroring) -> Self {
+        let chr = chr_memory(chr_rom);
+        Mmc3 {
+            prg_rom,
+            prg_ram: [0; 0x2000],
+            chr,
+            header_mirroring: mirroring,
+            bank_select: 0,
+            registers: [0; 8],
+            mirroring,
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enabled: false,
+            irq_pending: false,
+        }
+    }
+
+    fn prg_offset(&self, addr: u16) -> usize {
+        let banks = self.prg_rom.len() / PRG_BANK_SIZE;
+        let second_last = banks - 2;
+        let r6 = (self.registers[6] & 0b0011_1111) as usize;
+        let r7 = (self.registers[7] & 0b0011_1111) as usize;
+        let swap = self.bank_select & 0b0100_0000 != 0;
+
+        let bank = match (addr, swap) {
+            (0x8000..=0x9fff, false) => r6,
+            (0x8000..=0x9fff, true) => second_last,
+            (0xa000..=0xbfff, _) => r7,
+            (0xc000..=0xdfff, false) => second_last,
+            (0xc000..=0xdfff, true) => r6,
+            _ => banks - 1,
+        };
+
+        (bank % banks) * PRG_BANK_SIZE + (addr as usize & (PRG_BANK_SIZE - 1))
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        let banks = self.chr.len() / CHR_BANK_SIZE;
+        // A12 inversion swaps the 2KB and 1KB halves
+        let addr = if self.bank_select & 0b1000_0000 != 0 {
+            addr ^ 0x1000
+        } else {
+            addr
+        };
+
+        let r = &self.registers;
+        let bank = match addr {
+            0x0000..=0x07ff => (r[0] & !1) as usize + (addr as usize >> 10 & 1),
+            0x0800..=0x0fff => (r[1] & !1) as usize + (addr as usize >> 10 & 1),
+            0x1000..=0x13ff => r[2] as usize,
+            0x1400..=0x17ff => r[3] as usize,
+            0x1800..=0x1bff => r[4] as usize,
+            _ => r[5] as usize,
+        };
+
+        (bank % banks) * CHR_BANK_SIZE + (addr as usize & (CHR_BANK_SIZE - 1))
+    }
+}
+
+impl CartridgeRom for Mmc3 {
+    fn take_rom(&mut self, running: &mut Self) {
+        self.prg_rom = std::mem::take(&mut running.prg_rom);
+        self.chr.take_rom(&mut running.chr);
+    }
+}
+
+impl Mapper for Mmc3 {
+    fn cpu_read(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7fff => self.prg_ram[(addr - 0x6000) as usize],
+            0x8000..=0xffff => self.prg_rom[self.prg_offset(addr)],
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        let even = addr & 1 == 0;
+        match addr {
+            0x6000..=0x7fff => self.prg_ram[(addr - 0x6000) as usize] = data,
+            0x8000..=0x9fff if even => self.bank_select = data,
+            0x8000..=0x9fff => self.registers[(self.bank_select & 0b111) as usize] = data,
+            0xa000..=0xbfff if even => {
+                self.mirroring = if data & 1 == 0 {
+                    Mirroring::Vertical
+                } else {
+                    Mirroring::Horizontal
+                };
+            }
+            0xa000..=0xbfff => {} // PRG-RAM protect
+            0xc000..=0xdfff if even => self.irq_latch = data,
+            0xc000..=0xdfff => {
+                self.irq_counter = 0;
+                self.irq_reload = true;
+            }
+            0xe000..=0xffff if even => {
+                self.irq_enabled = false;
+                self.irq_pending = false;
+            }
+            0xe000..=0xffff => self.irq_enabled = true,
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr[self.chr_offset(addr)]
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        if self.chr.is_ram {
+            let offset = self.chr_offset(addr);
+            self.chr[offset] = data;
+        }
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.prg_ram
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        // Boards wired for four-screen VRAM ignore the mirroring register
+        Mirroring::resolve(self.header_mirroring, Some(self.mirroring))
+    }
+
+    fn reset(&mut self) {
+        self.bank_select = 0;
+        self.registers = [0; 8];
+        self.mirroring = self.header_mirroring;
+        self.irq_latch = 0;
+        self.irq_counter = 0;
+        self.irq_reload = false;
+        self.irq_enabled = false;
+        self.irq_pending = false;
+    }
+
+    fn clock_a12(&mut self) {
+        if self.irq_counter == 0 || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+            self.irq_reload = false;
+        } else {
+            self.irq_counter -= 1;
+        }
+
+        if self.irq_counter == 0 && self.irq_enabled {
+            self.irq_pending = true;
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cpu::{Mem, CPU};
+    use crate::mapper_test_utils::*;
+
+    #[test]
+    fn test_banks() {
+        // 128KB PRG in 8KB banks 0-15, 64KB CHR in 1KB banks 0-63
+        let mut bus = test_bus(4, 8, 8);
+        for (register, bank) in [(0, 8), (1, 12), (2, 20), (5, 25), (6, 3), (7, 5)] {
+            write_register(&mut bus, 0x8000, register);
+            write_register(&mut bus, 0x8001, bank);
+        }
+
+        assert_prg_bank(&mut bus, 0x8000, 3);
+        assert_prg_bank(&mut bus, 0xa000, 5);
+        assert_prg_bank(&mut bus, 0xc000, 14);
+        assert_prg_bank(&mut bus, 0xe000, 15);
+        assert_chr_bank(&mut bus, 0x0000, 8);
+        assert_chr_bank(&mut bus, 0x0400, 9);
+        assert_chr_bank(&mut bus, 0x0c00, 13);
+        assert_chr_bank(&mut bus, 0x1000, 20);
+        assert_chr_bank(&mut bus, 0x1c00, 25);
+
+        // PRG mode 1 swaps $8000 and $C000, CHR A12 inversion swaps the pattern tables
+        write_register(&mut bus, 0x8000, 0b1100_0000);
+        assert_prg_bank(&mut bus, 0x8000, 14);
+        assert_prg_bank(&mut bus, 0xc000, 3);
+        assert_chr_bank(&mut bus, 0x1000, 8);
+        assert_chr_bank(&mut bus, 0x0000, 20);
+
+        write_register(&mut bus, 0xa000, 1);
+        assert_eq!(bus.ppu().mirroring(), Mirroring::Horizontal);
+    }
+
+    #[test]
+    fn test_four_screen_ignores_mirroring_register() {
+        let mut rom = build_rom(4, 2, 1);
+        rom.screen_mirroring = Mirroring::FourScreen;
+        let mut bus = Bus::new(rom, |_, _, _| {});
+
+        write_register(&mut bus, 0xa000, 1);
+        assert_eq!(bus.ppu().mirroring(), Mirroring::FourScreen);
+    }
+
+    #[test]
+    fn test_scanline_irqs_per_frame() {
+        let mut rom = build_rom(4, 2, 1);
+        let program = [
+            0xa9, 59, // LDA #59
+            0x8d, 0x00, 0xc0, // STA $C000 (latch)
+            0x8d, 0x01, 0xc0, // STA $C001 (reload)
+            0x8d, 0x01, 0xe0, // STA $E001 (enable)
+            0xa9, 0x08, // LDA #$08
+            0x8d, 0x01, 0x20, // STA $2001 (show background)
+            0x58, // CLI
+            0x4c, 0x11, 0xe0, // JMP $E011
+            // IRQ handler at $E014
+            0xe6, 0x10, // INC $10
+            0x8d, 0x00, 0xe0, // STA $E000 (acknowledge)
+            0x8d, 0x01, 0xe0, // STA $E001
+            0x40, // RTI
+        ];
+        // The last 8KB bank is fixed at $E000
+        rom.prg_rom[0x6000..0x6000 + program.len()].copy_from_slice(&program);
+        rom.prg_rom[0x7ffc..].copy_from_slice(&[0x00, 0xe0, 0x14, 0xe0]);
+
+        let mut cpu = CPU::new(Bus::new(rom, |_, _, _| {}));
+        cpu.reset();
+
+        // 241 clocks per frame (lines 0-239 and the pre-render line), an IRQ every 60:
+        // on lines 59, 119, 179 and 239
+        while cpu.bus.ppu_frame() < 1 {
+            cpu.step();
+        }
+        assert_eq!(cpu.mem_read(0x10), 4);
+
+        while cpu.bus.ppu_frame() < 2 {
+            cpu.step();
+        }
+        assert_eq!(cpu.mem_read(0x10), 8);
+    }
+}
@@ -0,0 +1,328 @@
+use serde::{Deserialize, Serialize};
+
+use crate::rom::Mirroring;
+
+use super::{chr_memory, CartridgeRom, Chr, Mapper};
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+const EXRAM_SIZE: usize = 0x0400;
+
+/// Mapper 5 (MMC5, ExROM boards), partially. Emulated:
+///
+/// ```text
+/// $5100       PRG mode: 32KB, 16KB+16KB, 16KB+8KB+8KB or 4x8KB
+/// $5101       CHR mode: 8KB, 4KB, 2KB or 1KB banks
+/// $5102-$5103 PRG-RAM write protect, writable only with $02 and $01
+/// $5105       nametable mapping, as far as `Mirroring` can express it
+/// $5113-$5117 PRG banks; bit 7 of $5114-$5116 picks ROM (1) or RAM (0)
+/// $5120-$512B CHR banks, sets A ($5120-$5127) and B ($5128-$512B)
+/// $5130       CHR bank upper bits
+/// $5205-$5206 unsigned 8x8 multiplier: write the factors, read the product low/high
+/// $5C00-$5FFF ExRAM as plain CPU RAM
+/// ```
+///
+/// The PPU doesn't say whether a pattern fetch is for sprites or the background, so CHR
+/// always comes from the set written last, which is what the MMC5 does with 8x8 sprites.
+///
+/// Not emulated: audio, the scanline IRQ ($5203-$5204), vertical split ($5200-$5202), ExRAM
+/// as a nametable or extended attributes ($5104) and fill mode ($5106-$5107). Their
+/// registers are accepted and ignored.
+#[derive(Serialize, Deserialize)]
+pub struct Mmc5 {
+    #[serde(skip)]
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: Chr,
+    header_mirroring: Mirroring,
+    exram: Vec<u8>,
+
+    prg_mode: u8,
+    chr_mode: u8,
+    prg_ram_protect: [u8; 2],
+    exram_mode: u8,
+    nametable_mapping: u8,
+    prg_ram_bank: u8,
+    // $5114-$5117
+    prg_banks: [u8; 4],
+    // $5120-$5127, then $5128-$512B
+    chr_banks_a: [u16; 8],
+    chr_banks_b: [u16; 4],
+    chr_upper: u8,
+    last_chr_set_b: bool,
+    multiplicand: u8,
+    multiplier: u8,
+}
+
+impl Mmc5 {
+    pub fn new(
+        prg_rom: Vec<u8>,
+        chr_rom: Vec<u8>,
+        mirroring: Mirroring,
+        prg_ram_size: usize,
+    ) -> Self {
+        let chr = chr_memory(chr_rom);
+        Mmc5 {
+            prg_rom,
+            prg_ram: vec![0; prg_ram_size.max(PRG_BANK_SIZE)],
+            chr,
+            header_mirroring: mirroring,
+            exram: vec![0; EXRAM_SIZE],
+            prg_mode: 3,
+            chr_mode: 3,
+            prg_ram_protect: [0; 2],
+            exram_mode: 0,
+            nametable_mapping: 0,
+            prg_ram_bank: 0,
+            // The last bank is at $E000 on power-up, where the reset vector is
+            prg_banks: [0xff; 4],
+            chr_banks_a: [0; 8],
+            chr_banks_b: [0; 4],
+            chr_upper: 0,
+            last_chr_set_b: false,
+            multiplicand: 0xff,
+            multiplier: 0xff,
+        }
+    }
+
+    // Bank register and 8KB bank number for the slot of `addr` in $8000-$FFFF
+    fn prg_bank(&self, addr: u16) -> (u8, usize) {
+        let slot = (addr as usize - 0x8000) / PRG_BANK_SIZE;
+        let r = &self.prg_banks;
+        let (register, offset) = match (self.prg_mode, slot) {
+            (0, _) => (r[3], slot),
+            (1, 0..=1) | (2, 0..=1) => (r[1], slot),
+            (1, _) => (r[3], slot - 2),
+            (2, 2) => (r[2], 0),
+            (2, _) => (r[3], 0),
+            _ => (r[slot], 0),
+        };
+        // 32KB and 16KB banks ignore the low bits of the bank number
+        let mask = match (self.prg_mode, slot) {
+            (0, _) => !3,
+            (1, _) | (2, 0..=1) => !1,
+            _ => !0,
+        };
+        let bank = ((register & 0x7f) as usize & mask) + offset;
+        (register, bank)
+    }
+
+    fn prg_ram_offset(&self, bank: usize, addr: u16) -> usize {
+        let banks = self.prg_ram.len() / PRG_BANK_SIZE;
+        (bank % banks) * PRG_BANK_SIZE + (addr as usize & (PRG_BANK_SIZE - 1))
+    }
+
+    fn prg_ram_writable(&self) -> bool {
+        self.prg_ram_protect == [0b10, 0b01]
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        // Set B only has four registers; they repeat over both pattern tables
+        let r: [u16; 8] = if self.last_chr_set_b {
+            let b = &self.chr_banks_b;
+            [b[0], b[1], b[2], b[3], b[0], b[1], b[2], b[3]]
+        } else {
+            self.chr_banks_a
+        };
+
+        let slot = addr as usize / CHR_BANK_SIZE;
+        let bank = match self.chr_mode {
+            0 => r[7] as usize * 8 + slot,
+            1 => r[slot / 4 * 4 + 3] as usize * 4 + slot % 4,
+            2 => r[slot / 2 * 2 + 1] as usize * 2 + slot % 2,
+            _ => r[slot] as usize,
+        };
+
+        let banks = self.chr.len() / CHR_BANK_SIZE;
+        (bank % banks) * CHR_BANK_SIZE + (addr as usize & (CHR_BANK_SIZE - 1))
+    }
+}
+
+impl CartridgeRom for Mmc5 {
+    fn take_rom(&mut self, running: &mut Self) {
+        self.prg_rom = std::mem::take(&mut running.prg_rom);
+        self.chr.take_rom(&mut running.chr);
+    }
+}
+
+impl Mapper for Mmc5 {
+    fn cpu_read(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x5205 => (self.multiplicand as u16 * self.multiplier as u16) as u8,
+            0x5206 => ((self.multiplicand as u16 * self.multiplier as u16) >> 8) as u8,
+            0x5c00..=0x5fff if self.exram_mode >= 2 => self.exram[(addr - 0x5c00) as usize],
+            0x6000..=0x7fff => self.prg_ram[self.prg_ram_offset(self.prg_ram_bank as usize, addr)],
+            0x8000..=0xffff => {
+                let (register, bank) = self.prg_bank(addr);
+                if register & 0x80 == 0 && addr < 0xe000 {
+                    self.prg_ram[self.prg_ram_offset(bank, addr)]
+                } else {
+                    let banks = self.prg_rom.len() / PRG_BANK_SIZE;
+                    self.prg_rom[(bank % banks) * PRG_BANK_SIZE + (addr as usize & 0x1fff)]
+                }
+            }
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x5100 => self.prg_mode = data & 0b11,
+            0x5101 => self.chr_mode = data & 0b11,
+            0x5102..=0x5103 => self.prg_ram_protect[(addr - 0x5102) as usize] = data & 0b11,
+            0x5104 => self.exram_mode = data & 0b11,
+            0x5105 => self.nametable_mapping = data,
+            0x5113 => self.prg_ram_bank = data & 0b0111,
+            0x5114..=0x5117 => self.prg_banks[(addr - 0x5114) as usize] = data,
+            0x5120..=0x5127 => {
+                let bank = (self.chr_upper as u16) << 8 | data as u16;
+                self.chr_banks_a[(addr - 0x5120) as usize] = bank;
+                self.last_chr_set_b = false;
+            }
+            0x5128..=0x512b => {
+                let bank = (self.chr_upper as u16) << 8 | data as u16;
+                self.chr_banks_b[(addr - 0x5128) as usize] = bank;
+                self.last_chr_set_b = true;
+            }
+            0x5130 => self.chr_upper = data & 0b11,
+            0x5205 => self.multiplicand = data,
+            0x5206 => self.multiplier = data,
+            // ExRAM mode 3 is read-only
+            0x5c00..=0x5fff if self.exram_mode != 3 => {
+                self.exram[(addr - 0x5c00) as usize] = data;
+            }
+            0x6000..=0x7fff if self.prg_ram_writable() => {
+                let offset = self.prg_ram_offset(self.prg_ram_bank as usize, addr);
+                self.prg_ram[offset] = data;
+            }
+            0x8000..=0xdfff if self.prg_ram_writable() => {
+                let (register, bank) = self.prg_bank(addr);
+                if register & 0x80 == 0 {
+                    let offset = self.prg_ram_offset(bank, addr);
+                    self.prg_ram[offset] = data;
+                }
+            }
+            // Audio, split screen, IRQ, fill mode
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr[self.chr_offset(addr)]
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        if self.chr.is_ram {
+            let offset = self.chr_offset(addr);
+            self.chr[offset] = data;
+        }
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.prg_ram
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        // Two bits per nametable picking CIRAM page 0 or 1. ExRAM and fill mode sources
+        // aren't emulated, those layouts keep the header's mirroring
+        match self.nametable_mapping {
+            0x00 => Mirroring::SingleScreenLower,
+            0x55 => Mirroring::SingleScreenUpper,
+            0x44 => Mirroring::Vertical,
+            0x50 => Mirroring::Horizontal,
+            _ => self.header_mirroring,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.prg_mode = 3;
+        self.chr_mode = 3;
+        self.prg_ram_protect = [0; 2];
+        self.exram_mode = 0;
+        self.nametable_mapping = 0;
+        self.prg_ram_bank = 0;
+        self.prg_banks = [0xff; 4];
+        self.chr_banks_a = [0; 8];
+        self.chr_banks_b = [0; 4];
+        self.chr_upper = 0;
+        self.last_chr_set_b = false;
+        self.multiplicand = 0xff;
+        self.multiplier = 0xff;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cpu::Mem;
+    use crate::mapper_test_utils::*;
+
+    #[test]
+    fn test_multiplier() {
+        let mut bus = test_bus(5, 2, 1);
+        // $FF x $FF on power-up
+        assert_eq!(bus.mem_read(0x5205), 0x01);
+        assert_eq!(bus.mem_read(0x5206), 0xfe);
+
+        write_register(&mut bus, 0x5205, 200);
+        write_register(&mut bus, 0x5206, 150);
+        // 200 * 150 = 30000 = $7530
+        assert_eq!(bus.mem_read(0x5205), 0x30);
+        assert_eq!(bus.mem_read(0x5206), 0x75);
+    }
+
+    #[test]
+    fn test_banks() {
+        // 128KB PRG in 8KB banks 0-15, 64KB CHR in 1KB banks 0-63
+        let mut bus = test_bus(5, 8, 8);
+        assert_prg_bank(&mut bus, 0xe000, 15);
+
+        write_register(&mut bus, 0x5100, 3);
+        for (register, bank) in [(0x5114, 0x83), (0x5115, 0x85), (0x5116, 0x87)] {
+            write_register(&mut bus, register, bank);
+        }
+        assert_prg_bank(&mut bus, 0x8000, 3);
+        assert_prg_bank(&mut bus, 0xa000, 5);
+        assert_prg_bank(&mut bus, 0xc000, 7);
+
+        // 16KB + 8KB + 8KB: the 16KB bank ignores bit 0
+        write_register(&mut bus, 0x5100, 2);
+        assert_prg_bank(&mut bus, 0x8000, 4);
+        assert_prg_bank(&mut bus, 0xa000, 5);
+
+        write_register(&mut bus, 0x5101, 3);
+        write_register(&mut bus, 0x5123, 9);
+        assert_chr_bank(&mut bus, 0x0c00, 9);
+        // 2KB banks use the odd registers
+        write_register(&mut bus, 0x5101, 2);
+        write_register(&mut bus, 0x5127, 6);
+        assert_chr_bank(&mut bus, 0x1800, 12);
+        assert_chr_bank(&mut bus, 0x1c00, 13);
+
+        // Writing set B switches CHR over to it, repeated in both pattern tables
+        write_register(&mut bus, 0x512b, 20);
+        assert_chr_bank(&mut bus, 0x0c00, 41);
+        assert_chr_bank(&mut bus, 0x1c00, 41);
+    }
+
+    #[test]
+    fn test_prg_ram() {
+        let mut bus = test_bus(5, 2, 1);
+        // Write protected until $5102/$5103 are set to $02/$01
+        write_register(&mut bus, 0x6000, 0x12);
+        assert_eq!(bus.mem_read(0x6000), 0x00);
+
+        write_register(&mut bus, 0x5102, 0x02);
+        write_register(&mut bus, 0x5103, 0x01);
+        write_register(&mut bus, 0x6000, 0x12);
+        assert_eq!(bus.mem_read(0x6000), 0x12);
+
+        // RAM bank 0 mapped at $8000
+        write_register(&mut bus, 0x5100, 3);
+        write_register(&mut bus, 0x5114, 0x00);
+        assert_eq!(bus.mem_read(0x8000), 0x12);
+    }
+}
@@ -0,0 +1,207 @@
+//! Cartridge hardware. The mapper sits between the CPU/PPU buses and the cartridge memory:
+//! it decides which PRG and CHR banks are visible and, on some boards, the nametable
+//! mirroring. The CPU bus and the PPU share one mapper, so it is reference counted.
+
+pub mod axrom;
+pub mod cnrom;
+pub mod mmc1;
+pub mod mmc3;
+pub mod mmc5;
+pub mod nrom;
+pub mod uxrom;
+
+use std::cell::RefCell;
+use std::ops::{Deref, DerefMut};
+use std::rc::Rc;
+
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::rom::{Mirroring, Rom};
+
+use self::{
+    axrom::AxRom, cnrom::Cnrom, mmc1::Mmc1, mmc3::Mmc3, mmc5::Mmc5, nrom::Nrom, uxrom::UxRom,
+};
+
+/// Snapshot of a mapper's banks, registers and cartridge RAM for save states. The ROM is
+/// left out: loading a snapshot keeps the ROM of the running mapper.
+/// Implemented for every mapper that derives serde's traits and implements `CartridgeRom`.
+pub trait MapperState {
+    fn save_state(&self) -> Vec<u8>;
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), String>;
+}
+
+impl<T: Serialize + DeserializeOwned + CartridgeRom> MapperState for T {
+    fn save_state(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), String> {
+        let mut loaded: T =
+            bincode::deserialize(state).map_err(|e| format!("Invalid mapper state: {}", e))?;
+        loaded.take_rom(self);
+        *self = loaded;
+        Ok(())
+    }
+}
+
+/// The ROM fields a mapper skips when serialized.
+pub trait CartridgeRom {
+    /// Moves the ROM of `running` into a mapper deserialized from a snapshot.
+    fn take_rom(&mut self, running: &mut Self);
+}
+
+pub trait Mapper: MapperState {
+    /// CPU reads from $4020-$FFFF (expansion registers, PRG-RAM and PRG-ROM).
+    fn cpu_read(&mut self, addr: u16) -> u8;
+
+    /// CPU writes to $4020-$FFFF. Writes to ROM space usually go to mapper registers.
+    fn cpu_write(&mut self, addr: u16, data: u8);
+
+    /// PPU reads from pattern table space, $0000-$1FFF.
+    fn ppu_read(&mut self, addr: u16) -> u8;
+
+    /// PPU writes to pattern table space. Only has an effect on CHR-RAM.
+    fn ppu_write(&mut self, addr: u16, data: u8);
+
+    fn mirroring(&self) -> Mirroring;
+
+    /// Puts the bank and IRQ registers back to their power-on state. PRG-RAM and CHR-RAM
+    /// keep their contents.
+    fn reset(&mut self) {}
+
+    /// PPU address line A12 went from low to high. The PPU isn't dot accurate, so it reports
+    /// the one rise per scanline that happens at the sprite fetches (dot 260) while rendering.
+    fn clock_a12(&mut self) {}
+
+    /// One CPU cycle went by, DMA included. For boards whose IRQ counter runs on the CPU
+    /// clock, like the FME-7 and VRC4.
+    fn on_cpu_cycle(&mut self) {}
+
+    /// Level of the cartridge's IRQ line.
+    fn irq(&self) -> bool {
+        false
+    }
+
+    /// PRG-RAM at $6000-$7FFF, empty on boards without it.
+    fn prg_ram(&self) -> &[u8] {
+        &[]
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut []
+    }
+}
+
+/// Stand-in for a deserialized `Bus` or `NesPPU` until the real mapper is plugged back in.
+pub(crate) fn detached() -> Rc<RefCell<dyn Mapper>> {
+    Rc::new(RefCell::new(Nrom::new(
+        Vec::new(),
+        Vec::new(),
+        Mirroring::Horizontal,
+    )))
+}
+
+/// Whether `new` can build mapper number `mapper`.
+pub fn is_supported(mapper: u16) -> bool {
+    matches!(mapper, 0 | 1 | 2 | 3 | 4 | 5 | 7)
+}
+
+/// Builds the mapper the iNES header asks for.
+pub fn new(rom: Rom) -> Rc<RefCell<dyn Mapper>> {
+    match rom.mapper {
+        0 => Rc::new(RefCell::new(Nrom::new(
+            rom.prg_rom,
+            rom.chr_rom,
+            rom.screen_mirroring,
+        ))),
+        1 => Rc::new(RefCell::new(Mmc1::new(rom.prg_rom, rom.chr_rom))),
+        2 => Rc::new(RefCell::new(UxRom::new(
+            rom.prg_rom,
+            rom.chr_rom,
+            rom.screen_mirroring,
+        ))),
+        3 => Rc::new(RefCell::new(Cnrom::new(
+            rom.prg_rom,
+            rom.chr_rom,
+            rom.screen_mirroring,
+        ))),
+        4 => Rc::new(RefCell::new(Mmc3::new(
+            rom.prg_rom,
+            rom.chr_rom,
+            rom.screen_mirroring,
+        ))),
+        5 => Rc::new(RefCell::new(Mmc5::new(
+            rom.prg_rom,
+            rom.chr_rom,
+            rom.screen_mirroring,
+            rom.prg_ram_size,
+        ))),
+        7 => Rc::new(RefCell::new(AxRom::new(rom.prg_rom, rom.chr_rom))),
+        mapper => panic!("Mapper {} is not supported", mapper),
+    }
+}
+
+// A cartridge without CHR-ROM has 8KB of CHR-RAM instead.
+fn chr_memory(chr_rom: Vec<u8>) -> Chr {
+    if chr_rom.is_empty() {
+        Chr {
+            data: vec![0; 0x2000],
+            is_ram: true,
+        }
+    } else {
+        Chr {
+            data: chr_rom,
+            is_ram: false,
+        }
+    }
+}
+
+/// CHR memory, ROM or RAM. Only RAM is serialized; CHR-ROM comes back with `take_rom`.
+pub(crate) struct Chr {
+    data: Vec<u8>,
+    is_ram: bool,
+}
+
+impl Chr {
+    fn take_rom(&mut self, running: &mut Chr) {
+        if !self.is_ram {
+            self.data = std::mem::take(&mut running.data);
+        }
+    }
+}
+
+impl Deref for Chr {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl DerefMut for Chr {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+}
+
+impl Serialize for Chr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.is_ram.then_some(&self.data).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Chr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(match Option::<Vec<u8>>::deserialize(deserializer)? {
+            Some(ram) => Chr {
+                data: ram,
+                is_ram: true,
+            },
+            None => Chr {
+                data: Vec::new(),
+                is_ram: false,
+            },
+        })
+    }
+}
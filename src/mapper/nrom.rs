@@ -0,0 +1,74 @@
+use serde::{Deserialize, Serialize};
+
+use crate::rom::Mirroring;
+
+use super::{chr_memory, CartridgeRom, Chr, Mapper};
+
+/// Mapper 0: no bank switching. 16KB or 32KB of PRG-ROM at $8000 (16KB is mirrored into
+/// $C000), 8KB of CHR and the mirroring soldered on the board.
+#[derive(Serialize, Deserialize)]
+pub struct Nrom {
+    #[serde(skip)]
+    prg_rom: Vec<u8>,
+    #[serde(with = "crate::serde_array")]
+    prg_ram: [u8; 0x2000],
+    chr: Chr,
+    mirroring: Mirroring,
+}
+
+impl Nrom {
+    pub fn new(prg_rom: Vec<u8>, chr_rom: Vec<u8>, mirroring: Mirroring) -> Self {
+        let chr = chr_memory(chr_rom);
+        Nrom {
+            prg_rom,
+            prg_ram: [0; 0x2000],
+            chr,
+            mirroring,
+        }
+    }
+}
+
+impl CartridgeRom for Nrom {
+    fn take_rom(&mut self, running: &mut Self) {
+        self.prg_rom = std::mem::take(&mut running.prg_rom);
+        self.chr.take_rom(&mut running.chr);
+    }
+}
+
+impl Mapper for Nrom {
+    fn cpu_read(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7fff => self.prg_ram[(addr - 0x6000) as usize],
+            0x8000..=0xffff => self.prg_rom[(addr - 0x8000) as usize % self.prg_rom.len()],
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        if let 0x6000..=0x7fff = addr {
+            self.prg_ram[(addr - 0x6000) as usize] = data;
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr[addr as usize]
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        if self.chr.is_ram {
+            self.chr[addr as usize] = data;
+        }
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.prg_ram
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+}
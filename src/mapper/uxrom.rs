@@ -0,0 +1,96 @@
+use serde::{Deserialize, Serialize};
+
+use crate::rom::Mirroring;
+
+use super::{chr_memory, CartridgeRom, Chr, Mapper};
+
+const PRG_BANK_SIZE: usize = 0x4000;
+
+/// Mapper 2 (UxROM: UNROM, UOROM). Any write to $8000-$FFFF selects the 16KB PRG bank at
+/// $8000; the last bank is fixed at $C000. CHR is 8KB, almost always RAM.
+#[derive(Serialize, Deserialize)]
+pub struct UxRom {
+    #[serde(skip)]
+    prg_rom: Vec<u8>,
+    chr: Chr,
+    mirroring: Mirroring,
+    prg_bank: u8,
+}
+
+impl UxRom {
+    pub fn new(prg_rom: Vec<u8>, chr_rom: Vec<u8>, mirroring: Mirroring) -> Self {
+        let chr = chr_memory(chr_rom);
+        UxRom {
+            prg_rom,
+            chr,
+            mirroring,
+            prg_bank: 0,
+        }
+    }
+}
+
+impl CartridgeRom for UxRom {
+    fn take_rom(&mut self, running: &mut Self) {
+        self.prg_rom = std::mem::take(&mut running.prg_rom);
+        self.chr.take_rom(&mut running.chr);
+    }
+}
+
+impl Mapper for UxRom {
+    fn cpu_read(&mut self, addr: u16) -> u8 {
+        let banks = self.prg_rom.len() / PRG_BANK_SIZE;
+        let bank = match addr {
+            0x8000..=0xbfff => self.prg_bank as usize % banks,
+            0xc000..=0xffff => banks - 1,
+            _ => return 0,
+        };
+
+        self.prg_rom[bank * PRG_BANK_SIZE + (addr as usize & (PRG_BANK_SIZE - 1))]
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        if let 0x8000..=0xffff = addr {
+            self.prg_bank = data;
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr[addr as usize]
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        if self.chr.is_ram {
+            self.chr[addr as usize] = data;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn reset(&mut self) {
+        self.prg_bank = 0;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::mapper_test_utils::*;
+
+    #[test]
+    fn test_prg_bank_select() {
+        // 128KB PRG: 16KB banks 0-7, 8KB tags 0-15
+        let mut bus = test_bus(2, 8, 0);
+        assert_prg_bank(&mut bus, 0x8000, 0);
+        assert_prg_bank(&mut bus, 0xc000, 14);
+
+        write_register(&mut bus, 0x8000, 5);
+        assert_prg_bank(&mut bus, 0x8000, 10);
+        assert_prg_bank(&mut bus, 0xa000, 11);
+        assert_prg_bank(&mut bus, 0xc000, 14);
+        assert_prg_bank(&mut bus, 0xe000, 15);
+
+        write_register(&mut bus, 0xffff, 2);
+        assert_prg_bank(&mut bus, 0x8000, 4);
+    }
+}
@@ -0,0 +1,102 @@
+//! Helpers shared by mapper tests.
+//!
+//! `build_rom` fills every 8KB PRG bank and every 1KB CHR bank with its own bank number, so a
+//! single read tells which bank is mapped at an address. A mapper test then reads like:
+//!
+//! ```ignore
+//! let mut bus = test_bus(1, 8, 2);
+//! write_register(&mut bus, 0xe000, 0x00);
+//! assert_prg_bank(&mut bus, 0x8000, 0);
+//! assert_chr_bank(&mut bus, 0x1000, 4);
+//! ```
+
+use crate::{bus::Bus, cpu::Mem, rom::Rom};
+
+pub const PRG_BANK_SIZE: usize = 0x2000;
+pub const CHR_BANK_SIZE: usize = 0x0400;
+
+/// iNES image with `prg_16k` 16KB PRG pages and `chr_8k` 8KB CHR pages, every bank tagged
+/// with its number.
+pub fn build_rom(mapper: u8, prg_16k: u8, chr_8k: u8) -> Rom {
+    let mut raw = vec![
+        0x4e,
+        0x45,
+        0x53,
+        0x1a,
+        prg_16k,
+        chr_8k,
+        (mapper & 0x0f) << 4,
+        mapper & 0xf0,
+    ];
+    raw.resize(16, 0);
+
+    let prg_banks = prg_16k as usize * 2;
+    for bank in 0..prg_banks {
+        raw.extend(vec![bank as u8; PRG_BANK_SIZE]);
+    }
+
+    let chr_banks = chr_8k as usize * 8;
+    for bank in 0..chr_banks {
+        raw.extend(vec![bank as u8; CHR_BANK_SIZE]);
+    }
+
+    Rom::new(&raw).unwrap()
+}
+
+pub fn test_bus(mapper: u8, prg_16k: u8, chr_8k: u8) -> Bus<'static> {
+    Bus::new(build_rom(mapper, prg_16k, chr_8k), |_, _, _| {})
+}
+
+/// Writes a mapper register in $8000-$FFFF (or anywhere else on the CPU bus).
+pub fn write_register(bus: &mut Bus, addr: u16, value: u8) {
+    bus.mem_write(addr, value);
+}
+
+/// Asserts that the 8KB PRG bank number `bank` is visible at CPU address `addr`.
+pub fn assert_prg_bank(bus: &mut Bus, addr: u16, bank: u8) {
+    assert_eq!(
+        bus.mem_read(addr),
+        bank,
+        "unexpected PRG bank at ${:04X}",
+        addr
+    );
+}
+
+/// Asserts that the 1KB CHR bank number `bank` is visible at PPU address `addr`.
+/// Goes through $2006/$2007 like a game would, so it also moves the PPU address.
+pub fn assert_chr_bank(bus: &mut Bus, addr: u16, bank: u8) {
+    bus.mem_read(0x2002); // reset the address latch
+    bus.mem_write(0x2006, (addr >> 8) as u8);
+    bus.mem_write(0x2006, addr as u8);
+    bus.mem_read(0x2007); // fill the read buffer
+
+    assert_eq!(
+        bus.mem_read(0x2007),
+        bank,
+        "unexpected CHR bank at PPU ${:04X}",
+        addr
+    );
+}
+
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mapper_0_banks() {
+        // NROM-256: 32KB PRG mapped straight through, 8KB CHR
+        let mut bus = test_bus(0, 2, 1);
+        assert_prg_bank(&mut bus, 0x8000, 0);
+        assert_prg_bank(&mut bus, 0xa000, 1);
+        assert_prg_bank(&mut bus, 0xc000, 2);
+        assert_prg_bank(&mut bus, 0xffff, 3);
+        for bank in 0..8 {
+            assert_chr_bank(&mut bus, bank as u16 * CHR_BANK_SIZE as u16, bank);
+        }
+
+        // NROM-128: the 16KB PRG is mirrored into $C000-$FFFF
+        let mut bus = test_bus(0, 1, 1);
+        assert_prg_bank(&mut bus, 0x8000, 0);
+        assert_prg_bank(&mut bus, 0xc000, 0);
+        assert_prg_bank(&mut bus, 0xe000, 1);
+    }
+}
@@ -236,6 +236,30 @@ lazy_static! {
     OpCode::new(0xea, "NOP", 1, 2, AddressingMode::NoneAddressing),
     // Return from interrupt
     OpCode::new(0x40, "RTI", 1, 6, AddressingMode::NoneAddressing),
+
+    /* --- Unofficial Opcodes --- */
+    // Halt the CPU until reset
+    OpCode::new(0x02, "KIL", 1, 2, AddressingMode::NoneAddressing),
+    OpCode::new(0x12, "KIL", 1, 2, AddressingMode::NoneAddressing),
+    OpCode::new(0x22, "KIL", 1, 2, AddressingMode::NoneAddressing),
+    OpCode::new(0x32, "KIL", 1, 2, AddressingMode::NoneAddressing),
+    OpCode::new(0x42, "KIL", 1, 2, AddressingMode::NoneAddressing),
+    OpCode::new(0x52, "KIL", 1, 2, AddressingMode::NoneAddressing),
+    OpCode::new(0x62, "KIL", 1, 2, AddressingMode::NoneAddressing),
+    OpCode::new(0x72, "KIL", 1, 2, AddressingMode::NoneAddressing),
+    OpCode::new(0x92, "KIL", 1, 2, AddressingMode::NoneAddressing),
+    OpCode::new(0xb2, "KIL", 1, 2, AddressingMode::NoneAddressing),
+    OpCode::new(0xd2, "KIL", 1, 2, AddressingMode::NoneAddressing),
+    OpCode::new(0xf2, "KIL", 1, 2, AddressingMode::NoneAddressing),
+    // A = (A | magic) & X & immediate. Unstable; the magic value varies between chips (N,Z)
+    OpCode::new(0x8b, "ANE", 2, 2, AddressingMode::Immediate),
+    // Store A & X & (high byte of the base address + 1)
+    OpCode::new(0x9f, "SHA", 3, 5, AddressingMode::Absolute_Y),
+    OpCode::new(0x93, "SHA", 2, 6, AddressingMode::Indirect_Y),
+    // SP = A & X, then store SP & (high byte of the base address + 1)
+    OpCode::new(0x9b, "TAS", 3, 5, AddressingMode::Absolute_Y),
+    // A, X and SP = memory & SP (N,Z)
+    OpCode::new(0xbb, "LAS", 3, 4, /* +1 if page crossed */ AddressingMode::Absolute_Y),
     ];
 
     pub static ref OPCODES_MAP: HashMap<u8, &'static OpCode> = {
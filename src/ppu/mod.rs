@@ -1,37 +1,76 @@
 pub mod registers;
 
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use serde::{Deserialize, Serialize};
+
 use self::registers::{
     addr::AddrRegister, control::ControlRegister, mask::MaskRegister, scroll::ScrollRegister,
     status::StatusRegister,
 };
-use crate::rom::Mirroring;
+use crate::mapper::{nrom::Nrom, Mapper};
+use crate::rom::{Mirroring, Region};
+
+/// Something worth showing on a debugger timeline, see `NesPPU::set_event_log`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PpuEvent {
+    VblankSet,
+    VblankCleared,
+    Nmi,
+    SpriteZeroHit,
+    ScrollWrite(u8),
+    AddrWrite(u8),
+}
 
+#[derive(Serialize, Deserialize)]
 pub struct NesPPU {
     pub nmi_interrupt: Option<u8>,
-    pub chr_rom: Vec<u8>,
-    pub vram: [u8; 2048],
+    // Shared with the bus; save states store it separately
+    #[serde(skip, default = "crate::mapper::detached")]
+    mapper: Rc<RefCell<dyn Mapper>>,
+    // 2KB of nametable RAM, or 4KB when the cartridge supplies the other two (four-screen)
+    pub vram: Vec<u8>,
     pub palette_table: [u8; 32],
-    pub mirroring: Mirroring,
     pub ctrl: ControlRegister,
     pub mask: MaskRegister,
     pub status: StatusRegister,
     pub scroll: ScrollRegister,
     pub oam_addr: u8,
+    #[serde(with = "crate::serde_array")]
     pub oam_data: [u8; 256],
     pub addr: AddrRegister,
     internal_data_buf: u8,
+    open_bus: u8,
+    oam_addr_bug: bool,
+    region: Region,
     scanline: u16,
     cycles: usize,
+    frame: u64,
+    event_log: Option<Vec<(u16, usize, PpuEvent)>>,
 }
 
 impl NesPPU {
+    /// PPU with a fixed CHR-ROM and mirroring, as on an NROM board.
     pub fn new(chr_rom: Vec<u8>, mirroring: Mirroring) -> Self {
+        NesPPU::with_mapper(
+            Rc::new(RefCell::new(Nrom::new(Vec::new(), chr_rom, mirroring))),
+            Region::Ntsc,
+        )
+    }
+
+    /// PPU reading pattern tables and mirroring from the cartridge's mapper, with the frame
+    /// timing of `region`.
+    pub fn with_mapper(mapper: Rc<RefCell<dyn Mapper>>, region: Region) -> Self {
+        let vram_size = match mapper.borrow().mirroring() {
+            Mirroring::FourScreen => 4096,
+            _ => 2048,
+        };
         NesPPU {
-            chr_rom,
+            mapper,
             palette_table: [0; 32],
-            vram: [0; 2048],
+            vram: vec![0; vram_size],
             oam_data: [0; 64 * 4],
-            mirroring,
             ctrl: ControlRegister::new(),
             mask: MaskRegister::new(),
             status: StatusRegister::new(),
@@ -39,8 +78,13 @@ impl NesPPU {
             oam_addr: 0,
             addr: AddrRegister::new(),
             internal_data_buf: 0,
+            open_bus: 0,
+            oam_addr_bug: false,
+            region,
             scanline: 0,
             cycles: 0,
+            frame: 0,
+            event_log: None,
             nmi_interrupt: None,
         }
     }
@@ -52,55 +96,182 @@ impl NesPPU {
     // Vertical:
     //   [ A ] [ B ]
     //   [ A'] [ B']
+
+    // Single screen (lower or upper):
+    //   [ A ] [ A']
+    //   [ A'] [ A']
+
+    // Four-screen:
+    //   [ A ] [ B ]
+    //   [ C ] [ D ]
     pub fn mirror_vram_addr(&self, addr: u16) -> u16 {
         let mirrored_vram = addr & 0b0010_1111_1111_1111; // mirror down 0x3000-0x3eff to 0x2000-0x2eff
         let vram_index = mirrored_vram - 0x2000; // to vram vector
         let name_table = vram_index / 0x400; // to the name table index
 
-        match (&self.mirroring, name_table) {
+        match (self.mirroring(), name_table) {
             (Mirroring::Vertical, 2) | (Mirroring::Vertical, 3) => vram_index - 0x800,
             (Mirroring::Horizontal, 2) => vram_index - 0x400,
             (Mirroring::Horizontal, 1) => vram_index - 0x400,
             (Mirroring::Horizontal, 3) => vram_index - 0x800,
+            (Mirroring::SingleScreenLower, _) => vram_index & 0x3ff,
+            (Mirroring::SingleScreenUpper, _) => 0x400 | (vram_index & 0x3ff),
+            (Mirroring::FourScreen, _) => vram_index,
             _ => vram_index,
         }
     }
 
+    /// Current nametable mirroring. Some mappers switch it at runtime.
+    pub fn mirroring(&self) -> Mirroring {
+        self.mapper.borrow().mirroring()
+    }
+
+    /// The 16 bytes of the pattern table tile at `addr` ($0000-$1FFF), through the mapper.
+    pub fn chr_tile(&self, addr: u16) -> [u8; 16] {
+        let mut mapper = self.mapper.borrow_mut();
+        let mut tile = [0; 16];
+        for (i, byte) in tile.iter_mut().enumerate() {
+            *byte = mapper.ppu_read(addr + i as u16);
+        }
+        tile
+    }
+
     pub fn new_empty_rom() -> Self {
         NesPPU::new(vec![0; 2048], Mirroring::Horizontal)
     }
 
     pub fn write_to_ppu_addr(&mut self, value: u8) {
+        self.open_bus = value;
+        self.log_event(PpuEvent::AddrWrite(value));
         self.addr.update(value);
     }
 
     pub fn write_to_ctrl(&mut self, value: u8) {
+        self.open_bus = value;
         let before_nmi_status = self.ctrl.generate_vblank_nmi();
         self.ctrl = ControlRegister::from_bits_truncate(value);
         if !before_nmi_status && self.ctrl.generate_vblank_nmi() && self.status.is_in_vblank() {
             self.nmi_interrupt = Some(1);
+            self.log_event(PpuEvent::Nmi);
         }
     }
 
     pub fn write_to_mask(&mut self, value: u8) {
+        self.open_bus = value;
         self.mask = MaskRegister::from_bits_truncate(value);
     }
 
     pub fn write_to_scroll(&mut self, value: u8) {
+        self.open_bus = value;
+        self.log_event(PpuEvent::ScrollWrite(value));
         self.scroll.write(value);
     }
 
+    /// Enables the OAMADDR corruption of real 2C02s: if OAMADDR is 8 or more when rendering
+    /// starts on the pre-render line, the 8 bytes at `OAMADDR & 0xF8` are copied over the first
+    /// 8 bytes of OAM (sprites 0 and 1). Games always reset OAMADDR to 0 (via $2003 or OAM DMA),
+    /// so this only matters for test ROMs. Off by default.
+    ///
+    /// Not emulated: the 2C02G also corrupts OAM on $2003 writes themselves, in a way that is
+    /// not fully understood.
+    pub fn set_oam_addr_bug(&mut self, enabled: bool) {
+        self.oam_addr_bug = enabled;
+    }
+
+    // Debug access to the scroll state in terms of the 2C02's internal "loopy" registers.
+    // This PPU doesn't keep v/t/x/w as such, so they are mapped onto the registers it has:
+    //   v: the $2006 VRAM address
+    //   t: yyy NN YYYYY XXXXX = fine Y, nametable select ($2000 bits 0-1), coarse Y, coarse X
+    //   x: fine X, the low 3 bits of the $2005 X scroll
+    //   w: the write toggle, separate for $2005 and $2006 here. Reads as set after the first
+    //      write to either one; setting it sets both.
+
+    pub fn loopy_v(&self) -> u16 {
+        self.addr.get()
+    }
+
+    pub fn set_loopy_v(&mut self, v: u16) {
+        self.addr.set(v & 0x3fff);
+    }
+
+    pub fn loopy_t(&self) -> u16 {
+        let coarse_x = (self.scroll.x >> 3) as u16;
+        let coarse_y = (self.scroll.y >> 3) as u16;
+        let fine_y = (self.scroll.y & 0b111) as u16;
+        let nametable = (self.ctrl.bits() & 0b11) as u16;
+
+        fine_y << 12 | nametable << 10 | coarse_y << 5 | coarse_x
+    }
+
+    pub fn set_loopy_t(&mut self, t: u16) {
+        let coarse_x = (t & 0b1_1111) as u8;
+        let coarse_y = ((t >> 5) & 0b1_1111) as u8;
+        let nametable = ((t >> 10) & 0b11) as u8;
+        let fine_y = ((t >> 12) & 0b111) as u8;
+
+        self.scroll.x = coarse_x << 3 | (self.scroll.x & 0b111);
+        self.scroll.y = coarse_y << 3 | fine_y;
+        self.ctrl = ControlRegister::from_bits_truncate((self.ctrl.bits() & !0b11) | nametable);
+    }
+
+    pub fn loopy_x(&self) -> u8 {
+        self.scroll.x & 0b111
+    }
+
+    pub fn set_loopy_x(&mut self, x: u8) {
+        self.scroll.x = (self.scroll.x & !0b111) | (x & 0b111);
+    }
+
+    pub fn loopy_w(&self) -> bool {
+        !self.scroll.latch || self.addr.write_toggle()
+    }
+
+    pub fn set_loopy_w(&mut self, w: bool) {
+        self.scroll.latch = !w;
+        self.addr.set_write_toggle(w);
+    }
+
+    /// Starts or stops recording `PpuEvent`s with the (scanline, dot) they happened at.
+    /// Off by default, and then nothing is recorded.
+    pub fn set_event_log(&mut self, enabled: bool) {
+        self.event_log = if enabled { Some(Vec::new()) } else { None };
+    }
+
+    /// Events recorded since the last call, oldest first.
+    pub fn take_events(&mut self) -> Vec<(u16, usize, PpuEvent)> {
+        match &mut self.event_log {
+            Some(log) => std::mem::take(log),
+            None => Vec::new(),
+        }
+    }
+
+    fn log_event(&mut self, event: PpuEvent) {
+        if let Some(log) = &mut self.event_log {
+            log.push((self.scanline, self.cycles, event));
+        }
+    }
+
     pub fn write_to_oam_addr(&mut self, value: u8) {
+        self.open_bus = value;
         self.oam_addr = value;
     }
 
     pub fn write_to_oam_data(&mut self, value: u8) {
+        self.open_bus = value;
         self.oam_data[self.oam_addr as usize] = value;
         self.oam_addr = self.oam_addr.wrapping_add(1);
     }
 
-    pub fn read_oam_data(&self) -> u8 {
-        self.oam_data[self.oam_addr as usize]
+    pub fn read_oam_data(&mut self) -> u8 {
+        self.open_bus = self.oam_data[self.oam_addr as usize];
+        self.open_bus
+    }
+
+    /// Value returned by reads of the write-only registers ($2000, $2001, $2003, $2005, $2006).
+    /// This is the PPU's own I/O latch, separate from the CPU data bus: it holds the last value
+    /// written to or read from any PPU register. Decay over time is not modeled.
+    pub fn read_open_bus(&self) -> u8 {
+        self.open_bus
     }
 
     pub fn write_oam_dma(&mut self, data: &[u8; 256]) {
@@ -111,7 +282,9 @@ impl NesPPU {
     }
 
     pub fn read_status(&mut self) -> u8 {
-        let value = self.status.bits();
+        // Only bits 7-5 are driven by $2002; the rest come from the latch
+        let value = (self.status.bits() & 0b1110_0000) | (self.open_bus & 0b0001_1111);
+        self.open_bus = value;
         self.status.reset_vblank_status();
         self.addr.reset_latch();
         self.scroll.reset_latch();
@@ -123,25 +296,20 @@ impl NesPPU {
     }
 
     pub fn write_to_data(&mut self, value: u8) {
+        self.open_bus = value;
         let addr = self.addr.get();
 
         match addr {
-            0..=0x1fff => {
-                panic!("attempt to write to chr_rom(addr space 0..0x1fff). it's read only. requested = {:x}", addr)
-                // self.chr_rom[addr as usize] = value;
-            }
+            0..=0x1fff => self.mapper.borrow_mut().ppu_write(addr, value),
             0x2000..=0x2fff => {
-                self.vram[self.mirror_vram_addr(addr) as usize] = value;
+                let index = self.mirror_vram_addr(addr) as usize;
+                self.vram[index] = value;
             }
             0x3000..=0x3eff => panic!(
                 "addr space 0x3000..0x3eff is not expected to be used, requested = {:x}",
                 addr
             ),
-            0x3f10 | 0x3f14 | 0x3f18 | 0x3f1c => {
-                let add_mirror = addr - 0x10;
-                self.palette_table[(add_mirror - 0x3f00) as usize] = value;
-            }
-            0x3f00..=0x3fff => self.palette_table[(addr - 0x3f00) as usize] = value,
+            0x3f00..=0x3fff => self.palette_table[palette_index(addr)] = value,
             _ => panic!("unexpected access to mirrored space = {:x}", addr),
         }
 
@@ -152,10 +320,10 @@ impl NesPPU {
         let addr = self.addr.get();
         self.increment_vram_addr();
 
-        match addr {
+        let value = match addr {
             0..=0x1fff => {
                 let result = self.internal_data_buf;
-                self.internal_data_buf = self.chr_rom[addr as usize];
+                self.internal_data_buf = self.mapper.borrow_mut().ppu_read(addr);
                 result
             }
             0x2000..=0x2fff => {
@@ -168,22 +336,80 @@ impl NesPPU {
                 addr
             ),
 
-            //Addresses $3F10/$3F14/$3F18/$3F1C are mirrors of $3F00/$3F04/$3F08/$3F0C
-            0x3f10 | 0x3f14 | 0x3f18 | 0x3f1c => {
-                let addr_mirror = addr - 0x10;
-                self.palette_table[(addr_mirror - 0x3f00) as usize]
+            // Palette entries are 6 bits wide, the top 2 bits come from the latch
+            0x3f00..=0x3fff => {
+                (self.open_bus & 0b1100_0000) | (self.palette_table[palette_index(addr)] & 0x3f)
             }
-
-            0x3f00..=0x3fff => self.palette_table[(addr - 0x3eff) as usize],
             _ => panic!("unexpected access to mirrored space = {:x}", addr),
+        };
+
+        self.open_bus = value;
+        value
+    }
+
+    /// Color index shown where neither background nor sprites are drawn.
+    /// While rendering is disabled and `v` points into palette RAM, the PPU outputs the entry
+    /// `v` points to instead of $3F00 (the "background palette hack").
+    pub fn backdrop_color(&self) -> u8 {
+        let addr = self.addr.get();
+        let rendering = self.mask.show_background() || self.mask.show_sprite();
+        if !rendering && (0x3f00..=0x3fff).contains(&addr) {
+            self.palette_table[palette_index(addr)]
+        } else {
+            self.palette_table[0]
         }
     }
 
+    pub(crate) fn set_mapper(&mut self, mapper: Rc<RefCell<dyn Mapper>>) {
+        self.mapper = mapper;
+    }
+
+    /// Back to the power-on state, keeping the cartridge and settings.
+    pub fn power_cycle(&mut self) {
+        let oam_addr_bug = self.oam_addr_bug;
+        let event_log = self.event_log.take();
+        *self = NesPPU::with_mapper(self.mapper.clone(), self.region);
+        self.oam_addr_bug = oam_addr_bug;
+        self.event_log = event_log;
+    }
+
+    /// (scanline, dot) the PPU is currently at.
+    pub fn position(&self) -> (u16, usize) {
+        (self.scanline, self.cycles)
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    /// Number of frames completed since power on.
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
     pub fn tick(&mut self, cycles: u8) -> bool {
+        let cycles_before = self.cycles;
         self.cycles += cycles as usize;
+
+        // 261 on NTSC, 311 on PAL. PAL's longer vblank is all after the NMI at 241.
+        let pre_render_line = self.region.scanlines() - 1;
+        let rendering = self.mask.show_background() || self.mask.show_sprite();
+        let fetch_line = self.scanline < 240 || self.scanline == pre_render_line;
+        if rendering && fetch_line && cycles_before < 260 && self.cycles >= 260 {
+            self.mapper.borrow_mut().clock_a12();
+        }
         if self.cycles >= 341 {
-            if self.is_sprite_0_hit(self.cycles) {
-                self.status.set_sprite_zero_hit(true);
+            if !self.status.is_sprite_zero_hit() {
+                if let Some(x) = self.sprite_zero_hit_x() {
+                    self.status.set_sprite_zero_hit(true);
+                    if let Some(log) = &mut self.event_log {
+                        log.push((self.scanline, x, PpuEvent::SpriteZeroHit));
+                    }
+                }
+            }
+
+            if rendering && self.scanline < 240 && self.find_sprites_on(self.scanline) > 8 {
+                self.status.set_sprite_overflow(true);
             }
 
             self.cycles = self.cycles - 341;
@@ -191,29 +417,132 @@ impl NesPPU {
 
             if self.scanline == 241 {
                 self.status.set_vblank_status(true);
-                self.status.set_sprite_zero_hit(false);
+                self.log_event(PpuEvent::VblankSet);
 
                 if self.ctrl.generate_vblank_nmi() {
                     self.nmi_interrupt = Some(1);
+                    self.log_event(PpuEvent::Nmi);
+                }
+            }
+
+            if self.scanline == pre_render_line && self.oam_addr_bug && self.oam_addr >= 8 {
+                let rendering = self.mask.show_background() || self.mask.show_sprite();
+                if rendering {
+                    let row = (self.oam_addr & 0xf8) as usize;
+                    self.oam_data.copy_within(row..row + 8, 0);
                 }
             }
 
-            if self.scanline >= 262 {
+            if self.scanline > pre_render_line {
                 self.scanline = 0;
+                self.frame += 1;
                 self.nmi_interrupt = None;
-                self.status.set_sprite_zero_hit(false);
-                self.status.reset_vblank_status();
                 return true;
             }
         }
 
+        // VBlank, sprite 0 hit and sprite overflow are cleared at dot 1 of the pre-render line,
+        // so a $2002 read at dot 0 still sees the values from the previous frame.
+        if self.scanline == pre_render_line && self.cycles >= 1 {
+            if self.status.is_in_vblank() {
+                self.log_event(PpuEvent::VblankCleared);
+            }
+            self.status.reset_vblank_status();
+            self.status.set_sprite_zero_hit(false);
+            self.status.set_sprite_overflow(false);
+        }
+
         return false;
     }
 
-    fn is_sprite_0_hit(&self, cycle: usize) -> bool {
-        let y = self.oam_data[0] as usize;
-        let x = self.oam_data[3] as usize;
-        (y == self.scanline as usize) && x <= cycle && self.mask.show_sprite()
+    /// Number of sprites in range of `scanline`, as counted by sprite evaluation. The real
+    /// PPU only finds a ninth sprite reliably: once eight are found it walks OAM diagonally
+    /// and gives false positives and negatives. That bug isn't emulated; any ninth sprite
+    /// sets the overflow flag.
+    fn find_sprites_on(&self, scanline: u16) -> usize {
+        let height = self.ctrl.sprite_size();
+        self.oam_data
+            .chunks_exact(4)
+            .filter(|sprite| {
+                let y = sprite[0] as u16;
+                scanline >= y && scanline - y < height
+            })
+            .count()
+    }
+
+    /// X of the first pixel on the current scanline where sprite 0 hits the background. A hit
+    /// needs:
+    ///
+    /// - background and sprite rendering both enabled,
+    /// - an opaque sprite 0 pixel over an opaque background pixel,
+    /// - x below 255: the last column never hits,
+    /// - x of 8 or more if either PPUMASK left-column bit hides that layer there.
+    fn sprite_zero_hit_x(&self) -> Option<usize> {
+        if !self.mask.show_background() || !self.mask.show_sprite() || self.scanline >= 240 {
+            return None;
+        }
+
+        let height = self.ctrl.sprite_size();
+        let row = self.scanline.checked_sub(self.oam_data[0] as u16)?;
+        if row >= height {
+            return None;
+        }
+
+        let tile_idx = self.oam_data[1] as u16;
+        let attributes = self.oam_data[2];
+        let flip_vertical = attributes >> 7 & 1 == 1;
+        let flip_horizontal = attributes >> 6 & 1 == 1;
+        let row = if flip_vertical { height - 1 - row } else { row };
+        let tile_addr = if height == 16 {
+            // 8x16 sprites pick the pattern table with bit 0 and stack two tiles
+            (tile_idx & 1) * 0x1000 + (tile_idx & 0xfe) * 16 + row / 8 * 16
+        } else {
+            self.ctrl.sprite_pattern_addr() + tile_idx * 16
+        };
+        let tile = self.chr_tile(tile_addr);
+        let pixels = tile[(row % 8) as usize] | tile[(row % 8) as usize + 8];
+
+        let left_clipped =
+            !self.mask.leftmost_8pxl_background() || !self.mask.leftmost_8pxl_sprite();
+        let sprite_x = self.oam_data[3] as usize;
+        (0..8).find_map(|i| {
+            let x = sprite_x + i;
+            let bit = if flip_horizontal { i } else { 7 - i };
+            let hit = pixels >> bit & 1 == 1
+                && x < 255
+                && !(left_clipped && x < 8)
+                && self.bg_opaque_at(x);
+            hit.then_some(x)
+        })
+    }
+
+    // Whether the background pixel at `x` on the current scanline is opaque, scrolled the
+    // same way `render` draws it
+    fn bg_opaque_at(&self, x: usize) -> bool {
+        let x = self.scroll.x as usize + x;
+        let y = self.scroll.y as usize + self.scanline as usize;
+        let mut nametable = self.ctrl.base_nametable_addr();
+        if x >= 256 {
+            nametable ^= 0x400;
+        }
+        if y >= 240 {
+            nametable ^= 0x800;
+        }
+        let (x, y) = (x % 256, y % 240);
+
+        let tile_addr = nametable + (y / 8 * 32 + x / 8) as u16;
+        let tile_idx = self.vram[self.mirror_vram_addr(tile_addr) as usize] as u16;
+        let tile = self.chr_tile(self.ctrl.background_pattern_addr() + tile_idx * 16);
+        (tile[y % 8] | tile[y % 8 + 8]) >> (7 - x % 8) & 1 == 1
+    }
+}
+
+// $3F20-$3FFF mirror $3F00-$3F1F, and $3F10/$3F14/$3F18/$3F1C are mirrors of $3F00/$3F04/$3F08/$3F0C
+fn palette_index(addr: u16) -> usize {
+    let index = (addr & 0x1f) as usize;
+    match index {
+        0x10 | 0x14 | 0x18 | 0x1c => index - 0x10,
+        _ => index,
     }
 }
 
@@ -336,6 +665,26 @@ pub mod test {
         assert_eq!(ppu.read_data(), 0x77); //read from B
     }
 
+    #[test]
+    fn test_vram_four_screen() {
+        let mut ppu = NesPPU::new(vec![0; 2048], Mirroring::FourScreen);
+        assert_eq!(ppu.vram.len(), 4096);
+
+        for (nametable, value) in [(0x20, 0x11), (0x24, 0x22), (0x28, 0x33), (0x2C, 0x44)] {
+            ppu.write_to_ppu_addr(nametable);
+            ppu.write_to_ppu_addr(0x05);
+            ppu.write_to_data(value);
+        }
+
+        for (nametable, value) in [(0x20, 0x11), (0x24, 0x22), (0x28, 0x33), (0x2C, 0x44)] {
+            ppu.write_to_ppu_addr(nametable);
+            ppu.write_to_ppu_addr(0x05);
+            ppu.read_data(); //load into buffer
+            assert_eq!(ppu.read_data(), value);
+        }
+        assert_eq!(ppu.vram[0x0c05], 0x44);
+    }
+
     #[test]
     fn test_read_status_resets_latch() {
         let mut ppu = NesPPU::new_empty_rom();
@@ -382,6 +731,143 @@ pub mod test {
         assert_eq!(ppu.status.bits() >> 7, 0);
     }
 
+    #[test]
+    fn test_status_flags_cleared_at_prerender_dot_1() {
+        let mut ppu = NesPPU::new_empty_rom();
+        while !(ppu.scanline == 261 && ppu.cycles == 0) {
+            ppu.tick(1);
+        }
+        ppu.status.set_sprite_zero_hit(true);
+        ppu.status.set_sprite_overflow(true);
+
+        // dot 0 of the pre-render line: flags are still set
+        assert_eq!(ppu.read_status() & 0b0110_0000, 0b0110_0000);
+
+        ppu.tick(1);
+
+        // dot 1: flags are cleared
+        assert_eq!(ppu.read_status() & 0b0110_0000, 0);
+    }
+
+    #[test]
+    fn test_sprite_overflow() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.write_to_mask(0b0001_0000);
+        for sprite in ppu.oam_data.chunks_exact_mut(4) {
+            sprite[0] = 0xff;
+        }
+        for i in 0..8 {
+            ppu.oam_data[i * 4] = 50;
+        }
+        ppu.oam_data[8 * 4] = 43; // 8x8 sprite covering lines 43-50
+
+        while ppu.scanline != 50 {
+            ppu.tick(1);
+        }
+        assert!(!ppu.status.contains(StatusRegister::SPRITE_OVERFLOW));
+
+        ppu.tick(255);
+        ppu.tick(86);
+        assert_eq!(ppu.scanline, 51);
+        assert!(ppu.status.contains(StatusRegister::SPRITE_OVERFLOW));
+
+        while !(ppu.scanline == 261 && ppu.cycles >= 1) {
+            ppu.tick(1);
+        }
+        assert!(!ppu.status.contains(StatusRegister::SPRITE_OVERFLOW));
+    }
+
+    #[test]
+    fn test_pal_scanlines() {
+        let mapper = Rc::new(RefCell::new(Nrom::new(
+            Vec::new(),
+            vec![0; 0x2000],
+            Mirroring::Horizontal,
+        )));
+        let mut ppu = NesPPU::with_mapper(mapper, Region::Pal);
+
+        while ppu.scanline != 261 {
+            ppu.tick(1);
+        }
+        ppu.tick(2);
+        // Still in vblank where NTSC would be on the pre-render line
+        assert!(ppu.status.is_in_vblank());
+
+        let mut lines = 0;
+        while !ppu.tick(1) {
+            if ppu.cycles == 0 {
+                lines += 1;
+            }
+        }
+        assert_eq!(ppu.scanline, 0);
+        assert_eq!(lines, 311 - 261);
+        assert!(!ppu.status.is_in_vblank());
+        assert_eq!(ppu.frame(), 1);
+    }
+
+    #[test]
+    fn test_sprite_zero_hit_survives_vblank() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.status.set_sprite_zero_hit(true);
+        while ppu.scanline != 241 {
+            ppu.tick(1);
+        }
+
+        assert!(ppu.status.is_in_vblank());
+        assert_eq!(ppu.read_status() & 0b0100_0000, 0b0100_0000);
+    }
+
+    #[test]
+    fn test_palette_mirrors() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.write_to_ppu_addr(0x3f);
+        ppu.write_to_ppu_addr(0x10);
+        ppu.write_to_data(0x21); // $3F10 -> $3F00
+
+        ppu.write_to_ppu_addr(0x3f);
+        ppu.write_to_ppu_addr(0x24);
+        ppu.write_to_data(0x22); // $3F24 -> $3F04
+
+        assert_eq!(ppu.palette_table[0x00], 0x21);
+        assert_eq!(ppu.palette_table[0x04], 0x22);
+
+        ppu.write_to_ppu_addr(0x3f);
+        ppu.write_to_ppu_addr(0x04);
+        assert_eq!(ppu.read_data(), 0x22);
+    }
+
+    #[test]
+    fn test_backdrop_color_follows_v_while_rendering_disabled() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.palette_table[0x00] = 0x0f;
+        ppu.palette_table[0x05] = 0x16;
+
+        ppu.write_to_ppu_addr(0x3f);
+        ppu.write_to_ppu_addr(0x05);
+        assert_eq!(ppu.backdrop_color(), 0x16);
+
+        ppu.write_to_mask(0b0000_1000);
+        assert_eq!(ppu.backdrop_color(), 0x0f);
+    }
+
+    #[test]
+    fn test_open_bus_latch() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.write_to_ctrl(0);
+        ppu.write_to_scroll(0x5a);
+        assert_eq!(ppu.read_open_bus(), 0x5a);
+
+        // $2002 only drives bits 7-5, the low bits come from the latch
+        ppu.status.set_vblank_status(true);
+        assert_eq!(ppu.read_status(), 0x9a);
+        assert_eq!(ppu.read_open_bus(), 0x9a);
+
+        ppu.write_to_oam_addr(0x20);
+        ppu.oam_data[0x20] = 0x33;
+        assert_eq!(ppu.read_oam_data(), 0x33);
+        assert_eq!(ppu.read_open_bus(), 0x33);
+    }
+
     #[test]
     fn test_oam_read_write() {
         let mut ppu = NesPPU::new_empty_rom();
@@ -396,6 +882,117 @@ pub mod test {
         assert_eq!(ppu.read_oam_data(), 0x77);
     }
 
+    #[test]
+    fn test_oam_reads_start_at_oam_addr() {
+        let mut ppu = NesPPU::new_empty_rom();
+        for i in 0..=255u8 {
+            ppu.oam_data[i as usize] = i;
+        }
+
+        ppu.write_to_oam_addr(0x42);
+        assert_eq!(ppu.read_oam_data(), 0x42);
+        // $2004 reads don't advance OAMADDR
+        assert_eq!(ppu.read_oam_data(), 0x42);
+        ppu.write_to_oam_data(0xaa);
+        assert_eq!(ppu.read_oam_data(), 0x43);
+    }
+
+    #[test]
+    fn test_oam_addr_bug() {
+        let mut ppu = NesPPU::new_empty_rom();
+        for i in 0..=255u8 {
+            ppu.oam_data[i as usize] = i;
+        }
+        ppu.write_to_mask(0b0001_0000);
+        ppu.write_to_oam_addr(0x2b);
+
+        // disabled: OAM is untouched
+        while ppu.scanline != 261 {
+            ppu.tick(1);
+        }
+        assert_eq!(ppu.oam_data[0], 0x00);
+
+        // enabled: the row at $28 is copied over sprites 0-1 on the next pre-render line
+        ppu.set_oam_addr_bug(true);
+        while ppu.scanline == 261 {
+            ppu.tick(1);
+        }
+        while ppu.scanline != 261 {
+            ppu.tick(1);
+        }
+        assert_eq!(
+            &ppu.oam_data[0..8],
+            &[0x28, 0x29, 0x2a, 0x2b, 0x2c, 0x2d, 0x2e, 0x2f]
+        );
+        assert_eq!(ppu.oam_data[8], 0x08);
+    }
+
+    // Sprite 0 at (`x`, 10) over a background of solid tiles. Tile 1 is opaque, tile 0
+    // transparent.
+    fn sprite_zero_ppu(x: u8, mask: u8) -> NesPPU {
+        let mut chr = vec![0; 0x2000];
+        chr[0x10..0x18].fill(0xff);
+        let mut ppu = NesPPU::new(chr, Mirroring::Horizontal);
+        ppu.vram[0..0x3c0].fill(1);
+        ppu.oam_data[0..4].copy_from_slice(&[10, 1, 0, x]);
+        ppu.write_to_mask(mask);
+        ppu
+    }
+
+    fn sprite_zero_hit(mut ppu: NesPPU) -> bool {
+        while ppu.scanline != 11 {
+            ppu.tick(1);
+        }
+        ppu.status.is_sprite_zero_hit()
+    }
+
+    #[test]
+    fn test_sprite_zero_hit() {
+        assert!(sprite_zero_hit(sprite_zero_ppu(10, 0b0001_1110)));
+        // The last column never hits
+        assert!(!sprite_zero_hit(sprite_zero_ppu(255, 0b0001_1110)));
+        // Left 8 pixels with either layer clipped there
+        assert!(sprite_zero_hit(sprite_zero_ppu(0, 0b0001_1110)));
+        assert!(!sprite_zero_hit(sprite_zero_ppu(0, 0b0001_1100)));
+        assert!(!sprite_zero_hit(sprite_zero_ppu(0, 0b0001_1010)));
+        // Both layers have to be enabled
+        assert!(!sprite_zero_hit(sprite_zero_ppu(10, 0b0001_0110)));
+
+        // Transparent background
+        let mut ppu = sprite_zero_ppu(10, 0b0001_1110);
+        ppu.vram[0..0x3c0].fill(0);
+        assert!(!sprite_zero_hit(ppu));
+    }
+
+    #[test]
+    fn test_event_log() {
+        let mut ppu = sprite_zero_ppu(20, 0b0001_1000);
+        ppu.write_to_ctrl(0b1000_0000);
+        ppu.write_to_scroll(0x05); // not logged yet
+
+        ppu.set_event_log(true);
+        ppu.write_to_scroll(0x07);
+        for _ in 0..262 * 341 {
+            ppu.tick(1);
+        }
+
+        assert_eq!(
+            ppu.take_events(),
+            vec![
+                (0, 0, PpuEvent::ScrollWrite(0x07)),
+                (10, 20, PpuEvent::SpriteZeroHit),
+                (241, 0, PpuEvent::VblankSet),
+                (241, 0, PpuEvent::Nmi),
+                (261, 1, PpuEvent::VblankCleared),
+            ]
+        );
+        assert!(ppu.take_events().is_empty());
+
+        ppu.set_event_log(false);
+        ppu.write_to_ppu_addr(0x20);
+        assert!(ppu.take_events().is_empty());
+    }
+
     #[test]
     fn test_oam_dma() {
         let mut ppu = NesPPU::new_empty_rom();
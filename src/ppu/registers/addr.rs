@@ -1,3 +1,6 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize)]
 pub struct AddrRegister {
     value: (u8, u8),
     hi_ptr: bool,
@@ -11,7 +14,7 @@ impl AddrRegister {
         }
     }
 
-    fn set(&mut self, data: u16) {
+    pub fn set(&mut self, data: u16) {
         self.value.0 = (data >> 8) as u8;
         self.value.1 = (data & 0xff) as u8;
     }
@@ -46,7 +49,22 @@ impl AddrRegister {
         self.hi_ptr = true;
     }
 
+    /// True when the next write goes to the low byte.
+    pub fn write_toggle(&self) -> bool {
+        !self.hi_ptr
+    }
+
+    pub fn set_write_toggle(&mut self, second_write: bool) {
+        self.hi_ptr = !second_write;
+    }
+
     pub fn get(&self) -> u16 {
         ((self.value.0 as u16) << 8) | (self.value.1 as u16)
     }
 }
+
+impl Default for AddrRegister {
+    fn default() -> Self {
+        AddrRegister::new()
+    }
+}
@@ -1,4 +1,5 @@
 use bitflags::bitflags;
+use serde::{Deserialize, Serialize};
 
 bitflags! {
     // 7  bit  0
@@ -17,6 +18,7 @@ bitflags! {
     // |          (0: read backdrop from EXT pins; 1: output color on EXT pins)
     // +--------- Generate an NMI at the start of the
     //            vertical blanking interval (0: off; 1: on)
+    #[derive(Serialize, Deserialize)]
     pub struct ControlRegister: u8 {
         const NAMETABLE1              = 0b0000_0001;
         const NAMETABLE2              = 0b0000_0010;
@@ -88,3 +90,9 @@ impl ControlRegister {
         self.contains(ControlRegister::GENERATE_NMI)
     }
 }
+
+impl Default for ControlRegister {
+    fn default() -> Self {
+        ControlRegister::new()
+    }
+}
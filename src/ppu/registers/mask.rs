@@ -1,4 +1,5 @@
 use bitflags::bitflags;
+use serde::{Deserialize, Serialize};
 
 pub enum Color {
     Red,
@@ -19,6 +20,7 @@ bitflags! {
     // ||+------- Emphasize red (green on PAL/Dendy)
     // |+-------- Emphasize green (red on PAL/Dendy)
     // +--------- Emphasize blue
+    #[derive(Serialize, Deserialize)]
     pub struct MaskRegister: u8 {
         const GREYSCALE        = 0b0000_0001;
         const LEFTMOST_OBJ     = 0b0000_0010;
@@ -70,3 +72,9 @@ impl MaskRegister {
         result
     }
 }
+
+impl Default for MaskRegister {
+    fn default() -> Self {
+        MaskRegister::new()
+    }
+}
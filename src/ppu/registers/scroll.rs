@@ -1,3 +1,6 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize)]
 pub struct ScrollRegister {
     pub x: u8,
     pub y: u8,
@@ -28,3 +31,9 @@ impl ScrollRegister {
         self.latch = true;
     }
 }
+
+impl Default for ScrollRegister {
+    fn default() -> Self {
+        ScrollRegister::new()
+    }
+}
@@ -1,4 +1,5 @@
 use bitflags::bitflags;
+use serde::{Deserialize, Serialize};
 
 bitflags! {
     // 7  bit  0
@@ -20,6 +21,7 @@ bitflags! {
     //            Set at dot 1 of line 241 (the line *after* the post-render
     //            line); cleared after reading $2002 and at dot 1 of the
     //            pre-render line.
+    #[derive(Serialize, Deserialize)]
     pub struct StatusRegister: u8 {
         const NOTUSED         = 0b0000_0001;
         const NOTUSED2        = 0b0000_0010;
@@ -56,4 +58,14 @@ impl StatusRegister {
     pub fn is_in_vblank(&self) -> bool {
         self.contains(StatusRegister::VBLANK_STARTED)
     }
+
+    pub fn is_sprite_zero_hit(&self) -> bool {
+        self.contains(StatusRegister::SPRITE_ZERO_HIT)
+    }
+}
+
+impl Default for StatusRegister {
+    fn default() -> Self {
+        StatusRegister::new()
+    }
 }
@@ -0,0 +1,26 @@
+//! Everything needed to run a game from another crate, in one import:
+//!
+//! ```no_run
+//! use nes::prelude::*;
+//!
+//! let raw = std::fs::read("game.nes").unwrap();
+//! let rom = Rom::new(&raw).unwrap();
+//! let mut frame = Frame::new();
+//! let bus = Bus::new(rom, |ppu: &NesPPU, joypad: &mut Joypad, _joypad2: &mut Joypad| {
+//!     render(ppu, &mut frame);
+//!     joypad.set_button_pressed_status(JoypadButton::START, true);
+//! });
+//! let mut cpu = CPU::new(bus);
+//! cpu.reset();
+//! while cpu.step() {}
+//! ```
+//!
+//! Anything not re-exported here is an implementation detail and may change.
+
+pub use crate::bus::Bus;
+pub use crate::cpu::{Mem, CPU};
+pub use crate::joypad::{Joypad, JoypadButton};
+pub use crate::ppu::NesPPU;
+pub use crate::render::{frame::Frame, render};
+pub use crate::rom::{Mirroring, Region, Rom, RomError};
+pub use crate::worker::EmulatorThread;
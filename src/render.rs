@@ -1,25 +1,81 @@
 pub mod frame;
 pub mod palette;
+pub mod viewport;
 
-use crate::{ppu::NesPPU, rom::Mirroring};
+use crate::{
+    ppu::{registers::mask::MaskRegister, NesPPU},
+    rom::Mirroring,
+};
 use frame::Frame;
 
-fn bg_palette(
-    ppu: &NesPPU,
-    attribute_table: &[u8],
-    tile_column: usize,
-    tile_row: usize,
-) -> [u8; 4] {
+/// Size of the `render_nametables` picture: 2x2 nametables of 256x240.
+pub const NAMETABLES_WIDTH: usize = 512;
+pub const NAMETABLES_HEIGHT: usize = 480;
+
+const ATTRIBUTE_GRID_COLOR: (u8, u8, u8) = (0xff, 0xff, 0xff);
+// Tint for background palettes 0-3 in the attribute overlay
+const ATTRIBUTE_TINTS: [(u8, u8, u8); 4] = [
+    (0xff, 0x00, 0x00),
+    (0x00, 0xff, 0x00),
+    (0x00, 0x00, 0xff),
+    (0xff, 0xff, 0x00),
+];
+
+/// Attenuates the channels that aren't emphasised by PPUMASK bits 5-7. Setting all three
+/// darkens the whole picture.
+pub fn apply_emphasis(rgb: (u8, u8, u8), mask: &MaskRegister) -> (u8, u8, u8) {
+    let emphasis = mask.bits() & 0b1110_0000;
+    if emphasis == 0 {
+        return rgb;
+    }
+
+    let attenuate = |channel: u8, emphasised: MaskRegister| {
+        if emphasis == 0b1110_0000 || !mask.contains(emphasised) {
+            (channel as u16 * 3 / 4) as u8
+        } else {
+            channel
+        }
+    };
+    (
+        attenuate(rgb.0, MaskRegister::EMPHASIZE_RED),
+        attenuate(rgb.1, MaskRegister::EMPHASIZE_GREEN),
+        attenuate(rgb.2, MaskRegister::EMPHASIZE_BLUE),
+    )
+}
+
+// Draws a palette RAM value at (x, y) in the frame's colors, with greyscale and emphasis
+// from PPUMASK applied. The pixel buffer gets the system palette index.
+fn draw_pixel(ppu: &NesPPU, frame: &mut Frame, x: usize, y: usize, color_idx: u8) {
+    let color_idx = if ppu.mask.greyscale() {
+        color_idx & 0x30
+    } else {
+        color_idx
+    };
+    let rgb = apply_emphasis(frame.palette()[color_idx as usize], &ppu.mask);
+    frame.set_pixel(x, y, rgb);
+    frame.set_pixel_index(x, y, color_idx);
+}
+
+fn attribute_palette_idx(attribute_table: &[u8], tile_column: usize, tile_row: usize) -> u8 {
     let attr_table_idx = tile_row / 4 * 8 + tile_column / 4;
     let attr_byte = attribute_table[attr_table_idx];
 
-    let palette_idx = match (tile_column % 4 / 2, tile_row % 4 / 2) {
+    match (tile_column % 4 / 2, tile_row % 4 / 2) {
         (0, 0) => attr_byte & 0b11,
         (1, 0) => (attr_byte >> 2) & 0b11,
         (0, 1) => (attr_byte >> 4) & 0b11,
         (1, 1) => (attr_byte >> 6) & 0b11,
         _ => unreachable!(),
-    };
+    }
+}
+
+fn bg_palette(
+    ppu: &NesPPU,
+    attribute_table: &[u8],
+    tile_column: usize,
+    tile_row: usize,
+) -> [u8; 4] {
+    let palette_idx = attribute_palette_idx(attribute_table, tile_column, tile_row);
 
     let palette_start: usize = 1 + (palette_idx as usize) * 4;
     [
@@ -68,8 +124,7 @@ fn render_name_table(
         let tile_column = i % 32;
         let tile_row = i / 32;
         let tile_idx = name_table[i] as u16;
-        let tile =
-            &ppu.chr_rom[(bank + tile_idx * 16) as usize..=(bank + tile_idx * 16 + 15) as usize];
+        let tile = ppu.chr_tile(bank + tile_idx * 16);
         let palette = bg_palette(ppu, attribute_table, tile_column, tile_row);
 
         for y in 0..=7 {
@@ -80,11 +135,11 @@ fn render_name_table(
                 let value = (1 & upper_bits) << 1 | (1 & lower_bits);
                 upper_bits = upper_bits >> 1;
                 lower_bits = lower_bits >> 1;
-                let rgb = match value {
-                    0 => palette::SYSTEM_PALLETE[ppu.palette_table[0] as usize],
-                    1 => palette::SYSTEM_PALLETE[palette[1] as usize],
-                    2 => palette::SYSTEM_PALLETE[palette[2] as usize],
-                    3 => palette::SYSTEM_PALLETE[palette[3] as usize],
+                let color_idx = match value {
+                    0 => ppu.palette_table[0],
+                    1 => palette[1],
+                    2 => palette[2],
+                    3 => palette[3],
                     _ => panic!("can't be"),
                 };
                 let pixel_x = tile_column * 8 + x;
@@ -95,10 +150,12 @@ fn render_name_table(
                     && pixel_y >= view_port.y1
                     && pixel_y < view_port.y2
                 {
-                    frame.set_pixel(
+                    draw_pixel(
+                        ppu,
+                        frame,
                         (shift_x + pixel_x as isize) as usize,
                         (shift_y + pixel_y as isize) as usize,
-                        rgb,
+                        color_idx,
                     );
                 }
             }
@@ -106,12 +163,9 @@ fn render_name_table(
     }
 }
 
-pub fn render(ppu: &NesPPU, frame: &mut Frame) {
-    let scroll_x = ppu.scroll.x as usize;
-    let scroll_y = ppu.scroll.y as usize;
-
-    let (main_nametable, second_nametable) = match (&ppu.mirroring, ppu.ctrl.base_nametable_addr())
-    {
+// The nametable the scroll origin is in, and the one that is scrolled into view next to it
+fn nametables(ppu: &NesPPU) -> (&[u8], &[u8]) {
+    match (ppu.mirroring(), ppu.ctrl.base_nametable_addr()) {
         (Mirroring::Vertical, 0x2000)
         | (Mirroring::Vertical, 0x2800)
         | (Mirroring::Horizontal, 0x2000)
@@ -120,10 +174,37 @@ pub fn render(ppu: &NesPPU, frame: &mut Frame) {
         | (Mirroring::Vertical, 0x2C00)
         | (Mirroring::Horizontal, 0x2800)
         | (Mirroring::Horizontal, 0x2C00) => (&ppu.vram[0x400..0x800], &ppu.vram[0..0x400]),
+        (Mirroring::SingleScreenLower, _) => (&ppu.vram[0..0x400], &ppu.vram[0..0x400]),
+        (Mirroring::SingleScreenUpper, _) => (&ppu.vram[0x400..0x800], &ppu.vram[0x400..0x800]),
+        (Mirroring::FourScreen, base) => {
+            let main = (base - 0x2000) as usize;
+            let second = main ^ 0x400;
+            (
+                &ppu.vram[main..main + 0x400],
+                &ppu.vram[second..second + 0x400],
+            )
+        }
         (_, _) => {
-            panic!("Unimplemented nametable mirroring: {:?}", ppu.mirroring);
+            panic!("Unimplemented nametable mirroring: {:?}", ppu.mirroring());
         }
-    };
+    }
+}
+
+pub fn render(ppu: &NesPPU, frame: &mut Frame) {
+    if !ppu.mask.show_background() && !ppu.mask.show_sprite() {
+        // Rendering is disabled, only the backdrop color is output
+        for y in 0..240 {
+            for x in 0..256 {
+                draw_pixel(ppu, frame, x, y, ppu.backdrop_color());
+            }
+        }
+        return;
+    }
+
+    let scroll_x = ppu.scroll.x as usize;
+    let scroll_y = ppu.scroll.y as usize;
+
+    let (main_nametable, second_nametable) = nametables(ppu);
 
     render_name_table(
         ppu,
@@ -166,8 +247,7 @@ pub fn render(ppu: &NesPPU, frame: &mut Frame) {
 
         let bank: u16 = ppu.ctrl.sprite_pattern_addr();
 
-        let tile =
-            &ppu.chr_rom[(bank + tile_idx * 16) as usize..=(bank + tile_idx * 16 + 15) as usize];
+        let tile = ppu.chr_tile(bank + tile_idx * 16);
 
         for y in 0..=7 {
             let mut lower_bits = tile[y];
@@ -177,20 +257,263 @@ pub fn render(ppu: &NesPPU, frame: &mut Frame) {
                 upper_bits = upper_bits >> 1;
                 lower_bits = lower_bits >> 1;
 
-                let rgb = match value {
+                let color_idx = match value {
                     0 => continue 'x, // skip coloring the pixel because it's transparent
-                    1 => palette::SYSTEM_PALLETE[sprite_palette[1] as usize],
-                    2 => palette::SYSTEM_PALLETE[sprite_palette[2] as usize],
-                    3 => palette::SYSTEM_PALLETE[sprite_palette[3] as usize],
+                    1 => sprite_palette[1],
+                    2 => sprite_palette[2],
+                    3 => sprite_palette[3],
                     _ => unreachable!("can't be"),
                 };
-                match (flip_horizontal, flip_vertical) {
-                    (false, false) => frame.set_pixel(tile_x + x, tile_y + y, rgb),
-                    (true, false) => frame.set_pixel(tile_x + 7 - x, tile_y + y, rgb),
-                    (false, true) => frame.set_pixel(tile_x + x, tile_y + 7 - y, rgb),
-                    (true, true) => frame.set_pixel(tile_x + 7 - x, tile_y + 7 - y, rgb),
-                }
+                let (pixel_x, pixel_y) = match (flip_horizontal, flip_vertical) {
+                    (false, false) => (tile_x + x, tile_y + y),
+                    (true, false) => (tile_x + 7 - x, tile_y + y),
+                    (false, true) => (tile_x + x, tile_y + 7 - y),
+                    (true, true) => (tile_x + 7 - x, tile_y + 7 - y),
+                };
+                draw_pixel(ppu, frame, pixel_x, pixel_y, color_idx);
             }
         }
     }
 }
+
+/// Debug view of the four logical nametables, $2000 top left, $2400 top right, $2800 bottom
+/// left and $2C00 bottom right, resolved through the current mirroring. Scroll and sprites
+/// are ignored. Returns RGB24 pixels, `NAMETABLES_WIDTH` x `NAMETABLES_HEIGHT`.
+pub fn render_nametables(ppu: &NesPPU) -> Vec<u8> {
+    let mut map = vec![0; NAMETABLES_WIDTH * NAMETABLES_HEIGHT * 3];
+    let mut frame = Frame::new();
+
+    for (i, addr) in [0x2000, 0x2400, 0x2800, 0x2c00].into_iter().enumerate() {
+        let start = ppu.mirror_vram_addr(addr) as usize;
+        let name_table = &ppu.vram[start..start + 0x400];
+        render_name_table(ppu, &mut frame, name_table, Rect::new(0, 0, 256, 240), 0, 0);
+
+        let (left, top) = (i % 2 * 256, i / 2 * 240);
+        for (row, line) in frame.rgb_bytes().chunks(Frame::PITCH).enumerate() {
+            let offset = ((top + row) * NAMETABLES_WIDTH + left) * 3;
+            map[offset..offset + Frame::PITCH].copy_from_slice(line);
+        }
+    }
+    map
+}
+
+/// Debug view of a pattern table: the 256 tiles of `bank` (0 for $0000, 1 for $1000) as a
+/// 16x16 grid in the top left 128x128 pixels of the frame. `palette` holds the system palette
+/// indices for pixel values 0-3. CHR is read through the mapper, so CHR-RAM works too.
+pub fn render_pattern_table(ppu: &NesPPU, bank: u16, palette: [u8; 4]) -> Frame {
+    assert!(bank <= 1);
+    let mut frame = Frame::new();
+
+    for tile_n in 0..256 {
+        let tile = ppu.chr_tile(bank * 0x1000 + tile_n * 16);
+        let (tile_x, tile_y) = (tile_n as usize % 16 * 8, tile_n as usize / 16 * 8);
+
+        for y in 0..=7 {
+            let mut lower_bits = tile[y];
+            let mut upper_bits = tile[y + 8];
+
+            for x in (0..=7).rev() {
+                let value = (1 & upper_bits) << 1 | (1 & lower_bits);
+                upper_bits >>= 1;
+                lower_bits >>= 1;
+                let rgb = palette::SYSTEM_PALLETE[palette[value as usize] as usize];
+                frame.set_pixel(tile_x + x, tile_y + y, rgb);
+            }
+        }
+    }
+    frame
+}
+
+/// Debug overlay for background palettes: draws the 16x16 attribute cell boundaries over
+/// `frame` and tints every cell by the palette its attribute bits select. Uses the same
+/// scroll as `render`, so call it on a frame right after rendering it.
+pub fn render_attribute_overlay(ppu: &NesPPU, frame: &mut Frame) {
+    let scroll_x = ppu.scroll.x as usize;
+    let scroll_y = ppu.scroll.y as usize;
+    let (main_nametable, second_nametable) = nametables(ppu);
+
+    for y in 0..240 {
+        for x in 0..256 {
+            let (mut nt_x, mut nt_y) = (x + scroll_x, y + scroll_y);
+            let mut name_table = main_nametable;
+            if nt_x >= 256 || nt_y >= 240 {
+                nt_x %= 256;
+                nt_y %= 240;
+                name_table = second_nametable;
+            }
+
+            let rgb = if nt_x % 16 == 0 || nt_y % 16 == 0 {
+                ATTRIBUTE_GRID_COLOR
+            } else {
+                let attribute_table = &name_table[0x03c0..0x0400];
+                let palette_idx = attribute_palette_idx(attribute_table, nt_x / 8, nt_y / 8);
+                let tint = ATTRIBUTE_TINTS[palette_idx as usize];
+                let pixel = frame.get_pixel(x, y);
+                (
+                    ((pixel.0 as u16 + tint.0 as u16) / 2) as u8,
+                    ((pixel.1 as u16 + tint.1 as u16) / 2) as u8,
+                    ((pixel.2 as u16 + tint.2 as u16) / 2) as u8,
+                )
+            };
+            frame.set_pixel(x, y, rgb);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render_with_loopy_scroll() {
+        let mut chr_rom = vec![0; 2048];
+        chr_rom[0x10..0x18].copy_from_slice(&[0xff; 8]); // tile 1: solid color 1
+        let mut ppu = NesPPU::new(chr_rom, Mirroring::Horizontal);
+        ppu.vram[0x0001] = 1; // coarse X 1, coarse Y 0
+        ppu.palette_table[0] = 0x0f;
+        ppu.palette_table[1] = 0x30;
+        ppu.write_to_mask(0b0000_1000);
+
+        ppu.set_loopy_v(0x2345);
+        ppu.set_loopy_t(0x0001);
+        ppu.set_loopy_x(0);
+        assert_eq!(ppu.loopy_v(), 0x2345);
+        assert_eq!(ppu.loopy_t(), 0x0001);
+        assert_eq!(ppu.scroll.x, 8);
+
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+        assert_eq!(frame.get_pixel(0, 0), palette::SYSTEM_PALLETE[0x30]);
+        assert_eq!(frame.get_pixel(8, 0), palette::SYSTEM_PALLETE[0x0f]);
+        assert_eq!(frame.get_pixel(0, 8), palette::SYSTEM_PALLETE[0x0f]);
+
+        ppu.set_loopy_x(5);
+        assert_eq!(ppu.loopy_x(), 5);
+        render(&ppu, &mut frame);
+        assert_eq!(frame.get_pixel(2, 0), palette::SYSTEM_PALLETE[0x30]);
+        assert_eq!(frame.get_pixel(3, 0), palette::SYSTEM_PALLETE[0x0f]);
+    }
+
+    #[test]
+    fn test_attribute_overlay() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.vram[0x03c0] = 0b0000_0001; // top-left cell uses palette 1
+
+        let mut frame = Frame::new();
+        render_attribute_overlay(&ppu, &mut frame);
+
+        for x in 0..256 {
+            assert_eq!(frame.get_pixel(x, 5) == ATTRIBUTE_GRID_COLOR, x % 16 == 0);
+        }
+        for y in 0..240 {
+            assert_eq!(frame.get_pixel(5, y) == ATTRIBUTE_GRID_COLOR, y % 16 == 0);
+        }
+        assert_eq!(frame.get_pixel(1, 1), (0x00, 0x7f, 0x00));
+        assert_eq!(frame.get_pixel(17, 1), (0x7f, 0x00, 0x00));
+    }
+
+    #[test]
+    fn test_render_nametables() {
+        let mut chr_rom = vec![0; 2048];
+        chr_rom[0x10..0x18].copy_from_slice(&[0xff; 8]); // tile 1: solid color 1
+        let mut ppu = NesPPU::new(chr_rom, Mirroring::Vertical);
+        ppu.vram[0x0400..0x07c0].fill(1); // $2400, mirrored at $2C00
+        ppu.palette_table[0] = 0x0f;
+        ppu.palette_table[1] = 0x30;
+
+        let map = render_nametables(&ppu);
+        assert_eq!(map.len(), NAMETABLES_WIDTH * NAMETABLES_HEIGHT * 3);
+        let pixel = |x: usize, y: usize| {
+            let i = (y * NAMETABLES_WIDTH + x) * 3;
+            (map[i], map[i + 1], map[i + 2])
+        };
+        let (backdrop, tile) = (palette::SYSTEM_PALLETE[0x0f], palette::SYSTEM_PALLETE[0x30]);
+        assert_eq!(pixel(10, 10), backdrop);
+        assert_eq!(pixel(256 + 10, 10), tile);
+        assert_eq!(pixel(511, 239), tile);
+        assert_eq!(pixel(10, 240 + 10), backdrop);
+        assert_eq!(pixel(256 + 10, 240 + 10), tile);
+    }
+
+    #[test]
+    fn test_render_pattern_table() {
+        let mut chr_rom = vec![0; 0x2000];
+        chr_rom[0x0000] = 0b1010_0000; // tile 0, row 0: values 1, 2, 3, 0, ...
+        chr_rom[0x0008] = 0b0110_0000;
+        chr_rom[0x1010..0x1018].copy_from_slice(&[0xff; 8]); // bank 1, tile 1: solid 1
+        let ppu = NesPPU::new(chr_rom, Mirroring::Horizontal);
+        let palette = [0x0f, 0x16, 0x2a, 0x30];
+        let color = |value: usize| palette::SYSTEM_PALLETE[palette[value] as usize];
+
+        let frame = render_pattern_table(&ppu, 0, palette);
+        assert_eq!(frame.get_pixel(0, 0), color(1));
+        assert_eq!(frame.get_pixel(1, 0), color(2));
+        assert_eq!(frame.get_pixel(2, 0), color(3));
+        for (x, y) in [(3, 0), (7, 0), (0, 1), (7, 7), (8, 0)] {
+            assert_eq!(frame.get_pixel(x, y), color(0));
+        }
+
+        let frame = render_pattern_table(&ppu, 1, palette);
+        assert_eq!(frame.get_pixel(0, 0), color(0));
+        assert_eq!(frame.get_pixel(8, 0), color(1));
+        assert_eq!(frame.get_pixel(15, 7), color(1));
+    }
+
+    #[test]
+    fn test_render_palette_hack() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.palette_table[0x00] = 0x0f;
+        ppu.palette_table[0x03] = 0x2a;
+        ppu.write_to_ppu_addr(0x3f);
+        ppu.write_to_ppu_addr(0x03);
+
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+
+        let (r, g, b) = palette::SYSTEM_PALLETE[0x2a];
+        assert_eq!(&frame.data[0..3], &[r, g, b]);
+        assert_eq!(&frame.data[frame.data.len() - 3..], &[r, g, b]);
+    }
+
+    #[test]
+    fn test_greyscale() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.palette_table[0x00] = 0x16; // red
+
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+        assert_eq!(frame.get_pixel(0, 0), palette::SYSTEM_PALLETE[0x16]);
+
+        ppu.write_to_mask(0b0000_0001);
+        render(&ppu, &mut frame);
+        let (r, g, b) = frame.get_pixel(0, 0);
+        assert!(r == g && g == b);
+        assert_eq!((r, g, b), palette::SYSTEM_PALLETE[0x10]);
+        assert_eq!(frame.pixel_buffer()[0], 0x10);
+    }
+
+    #[test]
+    fn test_pixel_buffer_ignores_emphasis() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.palette_table[0x00] = 0x2a;
+        ppu.write_to_mask(0b1110_0000);
+
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+        assert_ne!(frame.get_pixel(0, 0), palette::SYSTEM_PALLETE[0x2a]);
+        assert!(frame.pixel_buffer().iter().all(|&index| index == 0x2a));
+    }
+
+    #[test]
+    fn test_apply_emphasis() {
+        let white = (0xff, 0xff, 0xff);
+        let red = MaskRegister::EMPHASIZE_RED;
+        let all = MaskRegister::EMPHASIZE_RED
+            | MaskRegister::EMPHASIZE_GREEN
+            | MaskRegister::EMPHASIZE_BLUE;
+
+        assert_eq!(apply_emphasis(white, &MaskRegister::new()), white);
+        assert_eq!(apply_emphasis(white, &red), (0xff, 0xbf, 0xbf));
+        assert_eq!(apply_emphasis(white, &all), (0xbf, 0xbf, 0xbf));
+    }
+}
@@ -1,19 +1,40 @@
-use super::palette::SYSTEM_PALLETE;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::Path;
+
+use super::palette::{Palette, SYSTEM_PALLETE};
 
 pub struct Frame {
     pub data: Vec<u8>,
+    // System palette index ($00-$3F) of every pixel `render` drew, one byte per pixel
+    pixel_buffer: Vec<u8>,
+    // Colors `render` draws with
+    palette: Palette,
 }
 
 impl Frame {
     const WIDTH: usize = 256;
     const HIGHT: usize = 240;
+    /// Bytes per row of `rgb_bytes()`: 3 bytes (R, G, B) per pixel.
+    pub const PITCH: usize = Frame::WIDTH * 3;
 
     pub fn new() -> Self {
         Frame {
             data: vec![0; (Frame::WIDTH) * (Frame::HIGHT) * 3],
+            pixel_buffer: vec![0; Frame::WIDTH * Frame::HIGHT],
+            palette: SYSTEM_PALLETE,
         }
     }
 
+    /// Switches the colors used from the next render on.
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette;
+    }
+
+    pub fn palette(&self) -> &Palette {
+        &self.palette
+    }
+
     pub fn set_pixel(&mut self, x: usize, y: usize, rgb: (u8, u8, u8)) {
         let base = y * 3 * Frame::WIDTH + x * 3;
         if base + 2 < self.data.len() {
@@ -22,6 +43,71 @@ impl Frame {
             self.data[base + 2] = rgb.2;
         }
     }
+
+    pub fn get_pixel(&self, x: usize, y: usize) -> (u8, u8, u8) {
+        let base = y * 3 * Frame::WIDTH + x * 3;
+        (self.data[base], self.data[base + 1], self.data[base + 2])
+    }
+
+    pub fn set_pixel_index(&mut self, x: usize, y: usize, index: u8) {
+        let base = y * Frame::WIDTH + x;
+        if base < self.pixel_buffer.len() {
+            self.pixel_buffer[base] = index;
+        }
+    }
+
+    /// System palette index of every pixel, row-major with a stride of 256. Greyscale is
+    /// applied but emphasis isn't, so it doesn't depend on the palette or on how the colors
+    /// are dimmed.
+    pub fn pixel_buffer(&self) -> &[u8] {
+        &self.pixel_buffer
+    }
+
+    /// Packed RGB24 pixels, row-major with a stride of `Frame::PITCH`.
+    pub fn rgb_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Copies out the frame without `top`/`bottom` rows and `left`/`right` columns, as packed RGB24
+    /// with a stride of `(256 - left - right) * 3`. Used to hide the overscan area TVs don't show.
+    pub fn cropped_view(&self, top: usize, bottom: usize, left: usize, right: usize) -> Vec<u8> {
+        let rows = top..Frame::HIGHT - bottom;
+        let columns = left * 3..Frame::PITCH - right * 3;
+        self.data
+            .chunks_exact(Frame::PITCH)
+            .skip(rows.start)
+            .take(rows.len())
+            .flat_map(|row| &row[columns.clone()])
+            .copied()
+            .collect()
+    }
+
+    /// Saves the frame as a 256x240 RGB PNG, e.g. for screenshots.
+    pub fn to_png(&self, path: &Path) -> io::Result<()> {
+        let file = BufWriter::new(File::create(path)?);
+        let mut encoder = png::Encoder::new(file, Frame::WIDTH as u32, Frame::HIGHT as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(self.rgb_bytes())?;
+        writer.finish()?;
+        Ok(())
+    }
+
+    /// Fills this frame with the per-channel average of `current` and `previous`.
+    /// Used to smooth out sprite flicker on displays that show every frame.
+    pub fn blend(&mut self, current: &Frame, previous: &Frame) {
+        for ((out, a), b) in self.data.iter_mut().zip(&current.data).zip(&previous.data) {
+            *out = ((*a as u16 + *b as u16) / 2) as u8;
+        }
+    }
+}
+
+impl Default for Frame {
+    fn default() -> Self {
+        Frame::new()
+    }
 }
 
 pub fn show_tile(chr_rom: &Vec<u8>, bank: usize, tile_n: usize) -> Frame {
@@ -53,3 +139,71 @@ pub fn show_tile(chr_rom: &Vec<u8>, bank: usize, tile_n: usize) -> Frame {
 
     frame
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rgb_bytes_len() {
+        let frame = Frame::new();
+        assert_eq!(frame.rgb_bytes().len(), 256 * 240 * 3);
+        assert_eq!(frame.rgb_bytes().len(), Frame::PITCH * 240);
+    }
+
+    #[test]
+    fn test_cropped_view() {
+        let mut frame = Frame::new();
+        frame.set_pixel(0, 7, (0xff, 0xff, 0xff));
+        frame.set_pixel(0, 8, (0x12, 0x34, 0x56));
+        frame.set_pixel(255, 8, (0x65, 0x43, 0x21));
+
+        let cropped = frame.cropped_view(8, 8, 0, 0);
+        assert_eq!(cropped.len(), 256 * 224 * 3);
+        assert_eq!(
+            &cropped[..Frame::PITCH],
+            &frame.rgb_bytes()[8 * Frame::PITCH..9 * Frame::PITCH]
+        );
+        assert_eq!(&cropped[..3], &[0x12, 0x34, 0x56]);
+
+        let cropped = frame.cropped_view(8, 8, 8, 8);
+        assert_eq!(cropped.len(), 240 * 224 * 3);
+        assert_eq!(&cropped[..3], &[0, 0, 0]);
+    }
+
+    #[test]
+    fn test_to_png() {
+        let mut frame = Frame::new();
+        frame.set_pixel(3, 2, (0x12, 0x34, 0x56));
+        let path = std::env::temp_dir().join(format!("nes-frame-{}.png", std::process::id()));
+        frame.to_png(&path).unwrap();
+
+        let decoder = png::Decoder::new(File::open(&path).unwrap());
+        let mut reader = decoder.read_info().unwrap();
+        let mut buffer = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buffer).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!((info.width, info.height), (256, 240));
+        assert_eq!(info.line_size, Frame::PITCH);
+        let pixel = 2 * Frame::PITCH + 3 * 3;
+        assert_eq!(&buffer[pixel..pixel + 3], &[0x12, 0x34, 0x56]);
+    }
+
+    #[test]
+    fn test_blend() {
+        let mut current = Frame::new();
+        let mut previous = Frame::new();
+        current.set_pixel(0, 0, (0xff, 0x10, 0x00));
+        previous.set_pixel(0, 0, (0x00, 0x30, 0x00));
+        current.set_pixel(255, 239, (0xff, 0xff, 0xff));
+        previous.set_pixel(255, 239, (0xff, 0xff, 0xff));
+
+        let mut blended = Frame::new();
+        blended.blend(&current, &previous);
+
+        assert_eq!(&blended.rgb_bytes()[0..3], &[0x7f, 0x20, 0x00]);
+        let last = blended.rgb_bytes().len() - 3;
+        assert_eq!(&blended.rgb_bytes()[last..], &[0xff, 0xff, 0xff]);
+    }
+}
@@ -1,5 +1,14 @@
+//! NES color palettes: RGB for each of the 64 colors the PPU can output.
+
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+/// RGB for each of the 64 PPU colors.
+pub type Palette = [(u8, u8, u8); 64];
+
 #[rustfmt::skip]
-pub static SYSTEM_PALLETE: [(u8,u8,u8); 64] = [
+pub static SYSTEM_PALLETE: Palette = [
    (0x80, 0x80, 0x80), (0x00, 0x3D, 0xA6), (0x00, 0x12, 0xB0), (0x44, 0x00, 0x96), (0xA1, 0x00, 0x5E),
    (0xC7, 0x00, 0x28), (0xBA, 0x06, 0x00), (0x8C, 0x17, 0x00), (0x5C, 0x2F, 0x00), (0x10, 0x45, 0x00),
    (0x05, 0x4A, 0x00), (0x00, 0x47, 0x2E), (0x00, 0x41, 0x66), (0x00, 0x00, 0x00), (0x05, 0x05, 0x05),
@@ -14,3 +23,113 @@ pub static SYSTEM_PALLETE: [(u8,u8,u8); 64] = [
    (0xFF, 0xEF, 0xA6), (0xFF, 0xF7, 0x9C), (0xD7, 0xE8, 0x95), (0xA6, 0xED, 0xAF), (0xA2, 0xF2, 0xDA),
    (0x99, 0xFF, 0xFC), (0xDD, 0xDD, 0xDD), (0x11, 0x11, 0x11), (0x11, 0x11, 0x11)
 ];
+
+/// Measured colors of the NTSC 2C02.
+#[rustfmt::skip]
+pub static NTSC_2C02: Palette = [
+   (0x54, 0x54, 0x54), (0x00, 0x1E, 0x74), (0x08, 0x10, 0x90), (0x30, 0x00, 0x88), (0x44, 0x00, 0x64),
+   (0x5C, 0x00, 0x30), (0x54, 0x04, 0x00), (0x3C, 0x18, 0x00), (0x20, 0x2A, 0x00), (0x08, 0x3A, 0x00),
+   (0x00, 0x40, 0x00), (0x00, 0x3C, 0x00), (0x00, 0x32, 0x3C), (0x00, 0x00, 0x00), (0x00, 0x00, 0x00),
+   (0x00, 0x00, 0x00), (0x98, 0x96, 0x98), (0x08, 0x4C, 0xC4), (0x30, 0x32, 0xEC), (0x5C, 0x1E, 0xE4),
+   (0x88, 0x14, 0xB0), (0xA0, 0x14, 0x64), (0x98, 0x22, 0x20), (0x78, 0x3C, 0x00), (0x54, 0x5A, 0x00),
+   (0x28, 0x72, 0x00), (0x08, 0x7C, 0x00), (0x00, 0x76, 0x28), (0x00, 0x66, 0x78), (0x00, 0x00, 0x00),
+   (0x00, 0x00, 0x00), (0x00, 0x00, 0x00), (0xEC, 0xEE, 0xEC), (0x4C, 0x9A, 0xEC), (0x78, 0x7C, 0xEC),
+   (0xB0, 0x62, 0xEC), (0xE4, 0x54, 0xEC), (0xEC, 0x58, 0xB4), (0xEC, 0x6A, 0x64), (0xD4, 0x88, 0x20),
+   (0xA0, 0xAA, 0x00), (0x74, 0xC4, 0x00), (0x4C, 0xD0, 0x20), (0x38, 0xCC, 0x6C), (0x38, 0xB4, 0xCC),
+   (0x3C, 0x3C, 0x3C), (0x00, 0x00, 0x00), (0x00, 0x00, 0x00), (0xEC, 0xEE, 0xEC), (0xA8, 0xCC, 0xEC),
+   (0xBC, 0xBC, 0xEC), (0xD4, 0xB2, 0xEC), (0xEC, 0xAE, 0xEC), (0xEC, 0xAE, 0xD4), (0xEC, 0xB4, 0xB0),
+   (0xE4, 0xC4, 0x90), (0xCC, 0xD2, 0x78), (0xB4, 0xDE, 0x78), (0xA8, 0xE2, 0x90), (0x98, 0xE2, 0xB4),
+   (0xA0, 0xD6, 0xE4), (0xA0, 0xA2, 0xA0), (0x00, 0x00, 0x00), (0x00, 0x00, 0x00)
+];
+
+/// `palette` with every color replaced by its luma, for a black-and-white TV look.
+pub fn greyscale(palette: &Palette) -> Palette {
+    palette.map(|(r, g, b)| {
+        let luma = ((r as u32 * 299 + g as u32 * 587 + b as u32 * 114) / 1000) as u8;
+        (luma, luma, luma)
+    })
+}
+
+#[derive(Debug)]
+pub enum PaletteError {
+    /// A .pal file holds 64 RGB triples, optionally followed by the emphasis variants.
+    WrongSize(usize),
+    Io(io::Error),
+}
+
+impl fmt::Display for PaletteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PaletteError::WrongSize(size) => {
+                write!(f, "Palette must be 192 or 1536 bytes, got {}.", size)
+            }
+            PaletteError::Io(e) => write!(f, "Failed to read palette: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for PaletteError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PaletteError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Parses a .pal file: 64 RGB triples. The 1536-byte variant also lists the colors for
+/// each emphasis combination; only the first 64 are used.
+pub fn from_pal(raw: &[u8]) -> Result<Palette, PaletteError> {
+    if raw.len() != 192 && raw.len() != 1536 {
+        return Err(PaletteError::WrongSize(raw.len()));
+    }
+
+    let mut palette = [(0, 0, 0); 64];
+    for (color, rgb) in palette.iter_mut().zip(raw.chunks_exact(3)) {
+        *color = (rgb[0], rgb[1], rgb[2]);
+    }
+    Ok(palette)
+}
+
+pub fn load_pal(path: &Path) -> Result<Palette, PaletteError> {
+    from_pal(&std::fs::read(path).map_err(PaletteError::Io)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ppu::NesPPU;
+    use crate::render::{self, frame::Frame};
+
+    #[test]
+    fn test_load_pal() {
+        let mut raw: Vec<u8> = (0..64).flat_map(|i| [i, 0x80, 0xff - i]).collect();
+        raw[0..3].copy_from_slice(&[0x12, 0x34, 0x56]);
+        let path = std::env::temp_dir().join(format!("nes-palette-{}.pal", std::process::id()));
+        std::fs::write(&path, &raw).unwrap();
+        let palette = load_pal(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(palette[0x00], (0x12, 0x34, 0x56));
+        assert_eq!(palette[0x3f], (0x3f, 0x80, 0xc0));
+
+        // The backdrop is color 0 in palette RAM
+        let ppu = NesPPU::new_empty_rom();
+        let mut frame = Frame::new();
+        frame.set_palette(palette);
+        render::render(&ppu, &mut frame);
+        assert_eq!(frame.get_pixel(0, 0), (0x12, 0x34, 0x56));
+
+        assert!(matches!(
+            from_pal(&raw[..191]),
+            Err(PaletteError::WrongSize(191))
+        ));
+    }
+
+    #[test]
+    fn test_greyscale() {
+        let grey = greyscale(&NTSC_2C02);
+        assert_eq!(grey[0x20], (0xed, 0xed, 0xed));
+        assert_eq!(grey[0x0f], (0, 0, 0));
+    }
+}
@@ -0,0 +1,70 @@
+//! Where a frame is drawn in the window. The frame is scaled by the largest whole factor that
+//! fits and centred, so pixels stay square and any leftover space becomes black bars.
+//!
+//! Sizes are `(width, height)` pairs, so the frame can be the full picture or one with the
+//! overscan cropped off.
+
+use sdl2::rect::Rect;
+
+pub const WIDTH: u32 = 256;
+pub const HEIGHT: u32 = 240;
+
+/// Window scales cycled through in windowed mode.
+pub const SCALES: std::ops::RangeInclusive<u32> = 1..=6;
+
+/// The destination rect for a `frame` sized picture drawn into a `window` sized window.
+pub fn letterbox(window: (u32, u32), frame: (u32, u32)) -> Rect {
+    let scale = (window.0 / frame.0).min(window.1 / frame.1).max(1);
+    let (width, height) = (frame.0 * scale, frame.1 * scale);
+    Rect::new(
+        (window.0 as i32 - width as i32) / 2,
+        (window.1 as i32 - height as i32) / 2,
+        width,
+        height,
+    )
+}
+
+/// Maps a point in the window to the frame pixel under it, clamped to the frame's edges.
+pub fn window_to_frame(rect: Rect, frame: (u32, u32), x: i32, y: i32) -> (usize, usize) {
+    let scale = (rect.width() / frame.0) as i32;
+    let x = ((x - rect.x()) / scale).clamp(0, frame.0 as i32 - 1);
+    let y = ((y - rect.y()) / scale).clamp(0, frame.1 as i32 - 1);
+    (x as usize, y as usize)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_letterbox() {
+        // Exactly 3x: the whole window
+        assert_eq!(
+            letterbox((768, 720), (WIDTH, HEIGHT)),
+            Rect::new(0, 0, 768, 720)
+        );
+        // 1920x1080 fits 4x (1024x960), pillarboxed and letterboxed
+        assert_eq!(
+            letterbox((1920, 1080), (WIDTH, HEIGHT)),
+            Rect::new(448, 60, 1024, 960)
+        );
+        // Smaller than 1x still draws at 1x
+        assert_eq!(
+            letterbox((200, 200), (WIDTH, HEIGHT)),
+            Rect::new(-28, -20, 256, 240)
+        );
+        // With 8 rows cropped top and bottom, 256x224 fits 4x
+        assert_eq!(
+            letterbox((1920, 1080), (WIDTH, 224)),
+            Rect::new(448, 92, 1024, 896)
+        );
+
+        let rect = letterbox((1920, 1080), (WIDTH, HEIGHT));
+        assert_eq!(window_to_frame(rect, (WIDTH, HEIGHT), 448, 60), (0, 0));
+        assert_eq!(
+            window_to_frame(rect, (WIDTH, HEIGHT), 448 + 4 * 10 + 3, 60 + 4 * 20),
+            (10, 20)
+        );
+        assert_eq!(window_to_frame(rect, (WIDTH, HEIGHT), 0, 1079), (0, 239));
+    }
+}
@@ -0,0 +1,115 @@
+//! Rewind buffer: the last few seconds of save states, one per frame, kept deflate
+//! compressed. Once the buffer is full the oldest state is dropped for every new one, so
+//! memory stays bounded by the window size.
+
+use std::collections::VecDeque;
+
+const COMPRESSION_LEVEL: u8 = 1;
+
+#[derive(Default)]
+pub struct Rewind {
+    states: VecDeque<Vec<u8>>,
+    // Number of frames kept; 0 disables rewinding
+    capacity: usize,
+}
+
+impl Rewind {
+    pub fn new(capacity: usize) -> Self {
+        Rewind {
+            states: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn push(&mut self, state: &[u8]) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.states.len() == self.capacity {
+            self.states.pop_front();
+        }
+        self.states.push_back(miniz_oxide::deflate::compress_to_vec(
+            state,
+            COMPRESSION_LEVEL,
+        ));
+    }
+
+    /// Takes the most recent state off the buffer.
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let compressed = self.states.pop_back()?;
+        Some(miniz_oxide::inflate::decompress_to_vec(&compressed).unwrap())
+    }
+
+    pub fn len(&self) -> usize {
+        self.states.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.states.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bus::Bus;
+    use crate::cpu::CPU;
+    use crate::rom::{test::TestRom, Rom};
+
+    fn nmi_counter_rom() -> Rom {
+        let mut rom = TestRom::create_test_rom(vec![
+            0xa9, 0x80, // LDA #$80
+            0x8d, 0x00, 0x20, // STA $2000 (enable NMI)
+            0xe6, 0x10, // INC $10
+            0x4c, 0x05, 0x80, // JMP $8005
+            0xe6, 0x12, // INC $12 (NMI handler at $800a)
+            0x40, // RTI
+        ]);
+        rom.prg_rom[0x7ffa] = 0x0a;
+        rom.prg_rom[0x7ffb] = 0x80;
+        rom
+    }
+
+    #[test]
+    fn test_rewind_frames() {
+        let mut cpu = CPU::new(Bus::new(nmi_counter_rom(), |_, _, _| {}));
+        cpu.enable_rewind(60);
+        cpu.reset();
+
+        let mut recorded = Vec::new();
+        for _ in 0..100 {
+            let frame = cpu.bus.ppu().frame();
+            while cpu.bus.ppu().frame() == frame {
+                cpu.step();
+            }
+            cpu.push_rewind_frame();
+            recorded.push((cpu.program_counter, cpu.bus.ppu().frame()));
+        }
+
+        for _ in 0..10 {
+            assert!(cpu.rewind_one());
+        }
+        assert_eq!((cpu.program_counter, cpu.bus.ppu().frame()), recorded[90]);
+
+        // Only the last 60 frames are kept
+        let mut rewound = 10;
+        while cpu.rewind_one() {
+            rewound += 1;
+        }
+        assert_eq!(rewound, 60);
+        assert_eq!((cpu.program_counter, cpu.bus.ppu().frame()), recorded[40]);
+    }
+
+    #[test]
+    fn test_state_size_is_constant() {
+        let mut cpu = CPU::new(Bus::new(nmi_counter_rom(), |_, _, _| {}));
+        cpu.reset();
+        cpu.run_one_frame();
+        let size = cpu.save_state().len();
+
+        // Every rewind frame is as big as the first, however long the game runs
+        for _ in 0..600 {
+            cpu.run_one_frame();
+        }
+        assert_eq!(cpu.save_state().len(), size);
+    }
+}
@@ -1,57 +1,257 @@
+use std::fmt;
+use std::io::{self, Read};
+
+use serde::{Deserialize, Serialize};
+
 const NES_TAG: [u8; 4] = [0x4e, 0x45, 0x53, 0x1a];
+const HEADER_SIZE: usize = 16;
+const TRAINER_SIZE: usize = 512;
 const PRG_ROM_PAGE_SIZE: usize = 16384;
 const CHR_ROM_PAGE_SIZE: usize = 8192;
+const PRG_RAM_PAGE_SIZE: usize = 8192;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
 pub enum Mirroring {
     Vertical,
     Horizontal,
     FourScreen,
+    // All four nametables show the first (lower) or second (upper) 1KB of VRAM.
+    // Only selectable by mappers.
+    SingleScreenLower,
+    SingleScreenUpper,
+}
+
+impl Mirroring {
+    /// Mirroring declared by iNES header byte 6. The four-screen bit (3) wins over the
+    /// vertical/horizontal bit (0).
+    pub fn from_header(byte6: u8) -> Mirroring {
+        let four_screen = byte6 & 0b1000 != 0;
+        let vertical_mirroring = byte6 & 0b1 != 0;
+        match (four_screen, vertical_mirroring) {
+            (true, _) => Mirroring::FourScreen,
+            (false, true) => Mirroring::Vertical,
+            (false, false) => Mirroring::Horizontal,
+        }
+    }
+
+    /// Mirroring the PPU should use. A mapper that controls mirroring takes precedence over
+    /// the header, except on boards wired for four-screen VRAM, which ignore it.
+    pub fn resolve(header: Mirroring, mapper_override: Option<Mirroring>) -> Mirroring {
+        match (header, mapper_override) {
+            (Mirroring::FourScreen, _) | (_, None) => header,
+            (_, Some(mirroring)) => mirroring,
+        }
+    }
+}
+
+/// TV system the console timing follows.
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub enum Region {
+    Ntsc,
+    Pal,
+}
+
+impl Region {
+    /// Scanlines per frame, pre-render line included.
+    pub fn scanlines(&self) -> u16 {
+        match self {
+            Region::Ntsc => 262,
+            Region::Pal => 312,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RomError {
+    /// The file doesn't start with "NES\x1a".
+    BadMagic,
+    /// Header bits 2-3 of byte 7 are neither iNES 1.0 nor NES 2.0.
+    UnsupportedVersion,
+    UnsupportedMapper(u16),
+    /// The header declares more data than the file holds.
+    TruncatedFile {
+        expected: usize,
+        got: usize,
+    },
+    Io(io::Error),
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RomError::BadMagic => write!(f, "File is not in iNES format."),
+            RomError::UnsupportedVersion => write!(f, "Unknown iNES header version."),
+            RomError::UnsupportedMapper(mapper) => write!(f, "Mapper {} is not supported.", mapper),
+            RomError::TruncatedFile { expected, got } => {
+                write!(
+                    f,
+                    "ROM is truncated: expected {} bytes, got {}.",
+                    expected, got
+                )
+            }
+            RomError::Io(e) => write!(f, "Failed to read ROM: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RomError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RomError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct Rom {
     pub prg_rom: Vec<u8>,
     pub chr_rom: Vec<u8>,
-    pub mapper: u8,
+    pub mapper: u16,
+    // Always 0 for iNES 1.0 images
+    pub submapper: u8,
     pub screen_mirroring: Mirroring,
+    // PRG-RAM is battery-backed and should be saved
+    pub battery: bool,
+    pub region: Region,
+    // Volatile plus battery-backed sizes, in bytes
+    pub prg_ram_size: usize,
+    pub chr_ram_size: usize,
 }
 
 impl Rom {
-    pub fn new(raw: &Vec<u8>) -> Result<Rom, String> {
-        if &raw[0..4] != NES_TAG {
-            return Err("File is not in iNES format.".to_string());
+    pub fn new(raw: &[u8]) -> Result<Rom, RomError> {
+        if raw.len() < HEADER_SIZE {
+            return Err(RomError::TruncatedFile {
+                expected: HEADER_SIZE,
+                got: raw.len(),
+            });
+        }
+        if raw[0..4] != NES_TAG {
+            return Err(RomError::BadMagic);
         }
 
-        let mapper = raw[7] & 0b1111_0000 | raw[6] >> 4;
-        let ines_ver = (raw[7] >> 2) & 0b11;
-        if ines_ver != 0 {
-            return Err("NES2.0 format is not supported.".to_string());
+        let mut mapper = (raw[7] & 0b1111_0000 | raw[6] >> 4) as u16;
+        let mut submapper = 0;
+        let prg_rom_size;
+        let chr_rom_size;
+        let prg_ram_size;
+        let chr_ram_size;
+        let region;
+
+        match (raw[7] >> 2) & 0b11 {
+            0 => {
+                prg_rom_size = raw[4] as usize * PRG_ROM_PAGE_SIZE;
+                chr_rom_size = raw[5] as usize * CHR_ROM_PAGE_SIZE;
+                // Byte 8 is rarely set; 0 means the usual 8KB
+                prg_ram_size = raw[8].max(1) as usize * PRG_RAM_PAGE_SIZE;
+                chr_ram_size = if chr_rom_size == 0 {
+                    CHR_ROM_PAGE_SIZE
+                } else {
+                    0
+                };
+                region = if raw[9] & 1 == 0 {
+                    Region::Ntsc
+                } else {
+                    Region::Pal
+                };
+            }
+            2 => {
+                mapper |= ((raw[8] & 0b1111) as u16) << 8;
+                submapper = raw[8] >> 4;
+                prg_rom_size = nes2_rom_size(raw[4], raw[9] & 0b1111, PRG_ROM_PAGE_SIZE);
+                chr_rom_size = nes2_rom_size(raw[5], raw[9] >> 4, CHR_ROM_PAGE_SIZE);
+                prg_ram_size = nes2_ram_size(raw[10]);
+                chr_ram_size = nes2_ram_size(raw[11]);
+                // Multi-region and Dendy images run as NTSC
+                region = if raw[12] & 0b11 == 1 {
+                    Region::Pal
+                } else {
+                    Region::Ntsc
+                };
+            }
+            _ => return Err(RomError::UnsupportedVersion),
         }
 
-        let four_screen = raw[6] & 0b1000 != 0;
-        let vertical_mirroring = raw[6] & 0b1 != 0;
-        let screen_mirroring = match (four_screen, vertical_mirroring) {
-            (true, _) => Mirroring::FourScreen,
-            (false, true) => Mirroring::Vertical,
-            (false, false) => Mirroring::Horizontal,
-        };
+        if !crate::mapper::is_supported(mapper) {
+            return Err(RomError::UnsupportedMapper(mapper));
+        }
 
-        let prg_rom_size = raw[4] as usize * PRG_ROM_PAGE_SIZE;
-        let chr_rom_size = raw[5] as usize * CHR_ROM_PAGE_SIZE;
+        let screen_mirroring = Mirroring::from_header(raw[6]);
 
+        let battery = raw[6] & 0b10 != 0;
         let skip_trainer = raw[6] & 0b100 != 0;
 
-        let prg_rom_start = 16 + if skip_trainer { 512 } else { 0 };
+        let prg_rom_start = HEADER_SIZE + if skip_trainer { TRAINER_SIZE } else { 0 };
         let chr_rom_start = prg_rom_start + prg_rom_size;
+        let expected = chr_rom_start + chr_rom_size;
+        if raw.len() < expected {
+            return Err(RomError::TruncatedFile {
+                expected,
+                got: raw.len(),
+            });
+        }
 
         Ok(Rom {
             prg_rom: raw[prg_rom_start..(prg_rom_start + prg_rom_size)].to_vec(),
             chr_rom: raw[chr_rom_start..(chr_rom_start + chr_rom_size)].to_vec(),
             mapper,
+            submapper,
             screen_mirroring,
+            battery,
+            region,
+            prg_ram_size,
+            chr_ram_size,
         })
     }
+
+    /// Reads the whole stream and parses it as an iNES image.
+    pub fn from_reader(mut reader: impl Read) -> Result<Rom, RomError> {
+        let mut raw = Vec::new();
+        reader.read_to_end(&mut raw).map_err(RomError::Io)?;
+        Rom::new(&raw)
+    }
+
+    /// CRC-32 of PRG and CHR ROM, the header left out. Save states carry it to tell which
+    /// cartridge they belong to.
+    pub fn crc32(&self) -> u32 {
+        let mut crc = !0u32;
+        for byte in self.prg_rom.iter().chain(&self.chr_rom) {
+            crc ^= *byte as u32;
+            for _ in 0..8 {
+                crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+            }
+        }
+        !crc
+    }
+}
+
+/// NES 2.0 ROM size from the LSB byte (4 or 5) and the matching MSB nibble of byte 9.
+/// An MSB nibble of $F switches to the exponent-multiplier form: LSB is EEEEEEMM and the
+/// size is 2^E * (MM*2+1) bytes.
+fn nes2_rom_size(lsb: u8, msb: u8, page_size: usize) -> usize {
+    if msb == 0b1111 {
+        let exponent = lsb >> 2;
+        let multiplier = (lsb & 0b11) as usize * 2 + 1;
+        (1usize << exponent) * multiplier
+    } else {
+        ((msb as usize) << 8 | lsb as usize) * page_size
+    }
+}
+
+/// NES 2.0 RAM size from byte 10 or 11. Each nibble is a shift count (64 << shift bytes,
+/// 0 for none); the low nibble is volatile RAM and the high nibble battery-backed RAM.
+fn nes2_ram_size(byte: u8) -> usize {
+    let size = |shift: u8| if shift == 0 { 0 } else { 64 << shift };
+    size(byte & 0b1111) + size(byte >> 4)
+}
+
+impl TryFrom<&[u8]> for Rom {
+    type Error = RomError;
+
+    fn try_from(raw: &[u8]) -> Result<Self, Self::Error> {
+        Rom::new(raw)
+    }
 }
 
 pub mod test {
@@ -73,7 +273,7 @@ pub mod test {
                 0x1a,
                 0x02, // Size of PRG ROM in 16KB PRG
                 0x01, // Size of CHR ROM in 8KB
-                0b0011_0001,
+                0b0000_0001,
                 0b0000_0000,
                 0x00,
                 0x00,
@@ -196,6 +396,76 @@ pub mod test {
         assert_eq!(rom.chr_rom[0], 2);
     }
 
+    #[test]
+    fn test_from_reader() {
+        let test_rom = TestRom {
+            header: vec![
+                0x4e,
+                0x45,
+                0x53,
+                0x1a,
+                0x01, // Size of PRG ROM in 16KB PRG
+                0x01, // Size of CHR ROM in 8KB
+                0b0000_0000,
+                0b0000_0000,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+            ],
+            trainer: None,
+            prg_rom: vec![1; PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        };
+
+        let raw = test_rom.dump();
+        let rom = Rom::from_reader(std::io::Cursor::new(&raw)).unwrap();
+        assert_eq!(rom.prg_rom, vec![1; PRG_ROM_PAGE_SIZE]);
+        assert_eq!(rom.chr_rom, vec![2; CHR_ROM_PAGE_SIZE]);
+
+        let rom = Rom::try_from(raw.as_slice()).unwrap();
+        assert_eq!(rom.screen_mirroring, Mirroring::Horizontal);
+    }
+
+    #[test]
+    fn test_mirroring_from_header() {
+        assert_eq!(Mirroring::from_header(0b0000_0000), Mirroring::Horizontal);
+        assert_eq!(Mirroring::from_header(0b0000_0001), Mirroring::Vertical);
+        assert_eq!(Mirroring::from_header(0b0000_1000), Mirroring::FourScreen);
+        assert_eq!(Mirroring::from_header(0b0000_1001), Mirroring::FourScreen);
+        // mapper and trainer bits don't affect mirroring
+        assert_eq!(Mirroring::from_header(0b1111_0100), Mirroring::Horizontal);
+    }
+
+    #[test]
+    fn test_mirroring_resolve() {
+        let header = Mirroring::from_header(0b0000_0001);
+        assert_eq!(Mirroring::resolve(header, None), Mirroring::Vertical);
+        assert_eq!(
+            Mirroring::resolve(header, Some(Mirroring::Horizontal)),
+            Mirroring::Horizontal
+        );
+
+        let header = Mirroring::from_header(0b0000_1000);
+        assert_eq!(Mirroring::resolve(header, None), Mirroring::FourScreen);
+        assert_eq!(
+            Mirroring::resolve(header, Some(Mirroring::Vertical)),
+            Mirroring::FourScreen
+        );
+    }
+
+    #[test]
+    fn test_crc32() {
+        let mut rom = TestRom::create_test_rom(vec![]);
+        rom.prg_rom = b"12345".to_vec();
+        rom.chr_rom = b"6789".to_vec();
+        assert_eq!(rom.crc32(), 0xcbf4_3926);
+    }
+
     #[test]
     fn test_nes2() {
         let header = vec![
@@ -203,14 +473,63 @@ pub mod test {
             0x45,
             0x53,
             0x1a,
-            0x02, // Size of PRG ROM in 16KB PRG
-            0x01, // Size of CHR ROM in 8KB
-            0b0011_0100,
+            0x02, // PRG ROM size LSB in 16KB
+            0x01, // CHR ROM size LSB in 8KB
+            0b0011_0011,
             0b0000_1000,
+            0b0010_0001, // Submapper 2, mapper bits 8-11
+            0x00,        // PRG/CHR ROM size MSB
+            0x70,        // 8KB battery-backed PRG RAM
+            0x07,        // 8KB CHR RAM
             0x00,
             0x00,
             0x00,
             0x00,
+        ];
+
+        let test_rom = TestRom {
+            header,
+            trainer: None,
+            prg_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; 1 * CHR_ROM_PAGE_SIZE],
+        };
+
+        let mut raw = test_rom.dump();
+        assert!(matches!(
+            Rom::new(&raw),
+            Err(RomError::UnsupportedMapper(0x103))
+        ));
+
+        raw[8] = 0b0010_0000;
+        let rom = Rom::new(&raw).unwrap();
+
+        assert_eq!(rom.screen_mirroring, Mirroring::Vertical);
+        assert!(rom.battery);
+        assert_eq!(rom.mapper, 3);
+        assert_eq!(rom.submapper, 2);
+        assert_eq!(rom.prg_rom.len(), 2 * PRG_ROM_PAGE_SIZE);
+        assert_eq!(rom.chr_rom.len(), CHR_ROM_PAGE_SIZE);
+        assert_eq!(rom.prg_rom[0], 1);
+        assert_eq!(rom.chr_rom[0], 2);
+        assert_eq!(rom.prg_ram_size, 0x2000);
+        assert_eq!(rom.chr_ram_size, 0x2000);
+    }
+
+    #[test]
+    fn test_nes2_exponent_size() {
+        let header = vec![
+            0x4e,
+            0x45,
+            0x53,
+            0x1a,
+            0b0011_1101, // 2^15 * 3 bytes of PRG ROM
+            0b0000_1100, // 2^3 * 1 bytes of CHR ROM
+            0b0000_0000,
+            0b0000_1000,
+            0x00,
+            0xff, // Both sizes in exponent form
+            0x07, // 8KB PRG RAM
+            0x00,
             0x00,
             0x00,
             0x00,
@@ -220,13 +539,78 @@ pub mod test {
         let test_rom = TestRom {
             header,
             trainer: None,
-            prg_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
-            chr_rom: vec![2; 1 * CHR_ROM_PAGE_SIZE],
+            prg_rom: vec![1; 3 * 0x8000],
+            chr_rom: vec![2; 8],
         };
 
         let raw = test_rom.dump();
-        let rom = Rom::new(&raw);
+        let rom = Rom::new(&raw).unwrap();
+
+        assert_eq!(rom.mapper, 0);
+        assert_eq!(rom.region, Region::Ntsc);
+        assert_eq!(rom.prg_rom.len(), 3 * 0x8000);
+        assert_eq!(rom.chr_rom.len(), 8);
+        assert_eq!(rom.chr_rom[0], 2);
+        assert_eq!(rom.prg_ram_size, 0x2000);
+        assert_eq!(rom.chr_ram_size, 0);
+    }
+
+    #[test]
+    fn test_errors() {
+        let raw = TestRom {
+            header: vec![
+                0x4e,
+                0x45,
+                0x53,
+                0x1a,
+                0x02, // Size of PRG ROM in 16KB PRG
+                0x01, // Size of CHR ROM in 8KB
+                0b0000_0000,
+                0b0000_0000,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+            ],
+            trainer: None,
+            prg_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        }
+        .dump();
+        assert!(Rom::new(&raw).is_ok());
+
+        assert!(matches!(
+            Rom::new(&raw[..raw.len() - 1]),
+            Err(RomError::TruncatedFile {
+                expected: 0xa010,
+                got: 0xa00f
+            })
+        ));
+        assert!(matches!(
+            Rom::new(&raw[..3]),
+            Err(RomError::TruncatedFile {
+                expected: 16,
+                got: 3
+            })
+        ));
+
+        let mut bad = raw.clone();
+        bad[3] = 0x00;
+        assert!(matches!(Rom::new(&bad), Err(RomError::BadMagic)));
+
+        let mut bad = raw.clone();
+        bad[7] = 0b0000_0100;
+        assert!(matches!(Rom::new(&bad), Err(RomError::UnsupportedVersion)));
 
-        rom.expect_err("NES2 should not be accepted");
+        let mut bad = raw.clone();
+        bad[6] = 0b0110_0000;
+        assert!(matches!(
+            Rom::new(&bad),
+            Err(RomError::UnsupportedMapper(6))
+        ));
     }
 }
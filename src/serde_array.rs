@@ -0,0 +1,20 @@
+//! serde only implements arrays up to 32 elements. Use with
+//! `#[serde(with = "crate::serde_array")]` on the larger byte arrays (RAM, VRAM, OAM).
+
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+pub fn serialize<S: Serializer, const N: usize>(
+    array: &[u8; N],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    array.as_slice().serialize(serializer)
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>, const N: usize>(
+    deserializer: D,
+) -> Result<[u8; N], D::Error> {
+    let bytes = Vec::<u8>::deserialize(deserializer)?;
+    bytes
+        .try_into()
+        .map_err(|bytes: Vec<u8>| D::Error::invalid_length(bytes.len(), &"a fixed size array"))
+}
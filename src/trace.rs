@@ -1,14 +1,169 @@
-use std::{arch::x86_64::CpuidResult, collections::HashMap};
+use std::{
+    arch::x86_64::CpuidResult,
+    collections::{HashMap, HashSet},
+    io,
+    ops::RangeInclusive,
+    path::Path,
+};
 
 use crate::{
-    cpu::{AddressingMode, Mem, CPU},
+    cpu::{AddressingMode, Inspector, CPU},
     opcodes,
 };
 
+/// One decoded instruction, as returned by `disassemble`.
+pub struct Instruction {
+    pub addr: u16,
+    pub bytes: Vec<u8>,
+    pub mnemonic: &'static str,
+    pub mode: AddressingMode,
+    pub formatted_operand: String,
+}
+
+/// Decodes `count` instructions starting at `addr` without changing any machine state.
+/// A byte that isn't a known opcode decodes as a one byte `???`.
+pub fn disassemble(bus: &impl Inspector, addr: u16, count: usize) -> Vec<Instruction> {
+    let mut result = Vec::with_capacity(count);
+    let mut pc = addr;
+
+    for _ in 0..count {
+        let code = bus.inspect(pc);
+        let instruction = match opcodes::OPCODES_MAP.get(&code) {
+            Some(opcode) => {
+                let bytes: Vec<u8> = (0..opcode.len as u16)
+                    .map(|i| bus.inspect(pc.wrapping_add(i)))
+                    .collect();
+                Instruction {
+                    addr: pc,
+                    formatted_operand: format_operand(opcode, pc, &bytes[1..]),
+                    bytes,
+                    mnemonic: opcode.mnemonic,
+                    mode: opcode.mode,
+                }
+            }
+            None => Instruction {
+                addr: pc,
+                bytes: vec![code],
+                mnemonic: "???",
+                mode: AddressingMode::NoneAddressing,
+                formatted_operand: String::new(),
+            },
+        };
+
+        pc = pc.wrapping_add(instruction.bytes.len() as u16);
+        result.push(instruction);
+    }
+
+    result
+}
+
+// The operand as written in assembly, e.g. `#$01`, `$0400,X` or `($33),Y`.
+// Branches show their target and shifts of the accumulator show `A`.
+fn format_operand(opcode: &opcodes::OpCode, addr: u16, operand: &[u8]) -> String {
+    let byte = operand.first().copied().unwrap_or(0);
+    let word = match operand {
+        [lo, hi] => u16::from_le_bytes([*lo, *hi]),
+        _ => byte as u16,
+    };
+
+    match opcode.mode {
+        AddressingMode::Immediate => format!("#${:02X}", byte),
+        AddressingMode::ZeroPage => format!("${:02X}", byte),
+        AddressingMode::ZeroPage_X => format!("${:02X},X", byte),
+        AddressingMode::ZeroPage_Y => format!("${:02X},Y", byte),
+        AddressingMode::Absolute => format!("${:04X}", word),
+        AddressingMode::Absolute_X => format!("${:04X},X", word),
+        AddressingMode::Absolute_Y => format!("${:04X},Y", word),
+        AddressingMode::Indirect => format!("(${:04X})", word),
+        AddressingMode::Indirect_X => format!("(${:02X},X)", byte),
+        AddressingMode::Indirect_Y => format!("(${:02X}),Y", byte),
+        AddressingMode::NoneAddressing => match (branch_target(opcode, addr, byte), opcode.code) {
+            (Some(target), _) => format!("${:04X}", target),
+            (None, 0x4A | 0x0A | 0x6A | 0x2A) => "A".to_string(),
+            _ => String::new(),
+        },
+    }
+}
+
+// Where a branch at `addr` with offset `byte` goes, if `opcode` is a branch
+fn branch_target(opcode: &opcodes::OpCode, addr: u16, byte: u8) -> Option<u16> {
+    match opcode.code {
+        0x90 | 0xB0 | 0xF0 | 0x30 | 0xD0 | 0x10 | 0x50 | 0x70 => {
+            Some(addr.wrapping_add(2).wrapping_add(byte as i8 as u16))
+        }
+        _ => None,
+    }
+}
+
+/// Names for addresses, used by `trace_with_symbols` to label jump and branch targets.
+#[derive(Default)]
+pub struct Symbols {
+    labels: HashMap<u16, String>,
+}
+
+impl Symbols {
+    /// Parses an FCEUX `.nl` file (`$C000#Reset#comment` lines) or plain `C000=Reset` lines.
+    /// Blank lines and lines starting with `;` are skipped.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut symbols = Symbols::default();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+            let (addr, name) = if line.starts_with('$') && line.contains('#') {
+                let mut fields = line.split('#');
+                (fields.next().unwrap_or(""), fields.next().unwrap_or(""))
+            } else {
+                line.split_once('=').unwrap_or((line, ""))
+            };
+            let digits = addr.trim().trim_start_matches('$').trim_start_matches("0x");
+            match (u16::from_str_radix(digits, 16), name.trim()) {
+                (Ok(addr), name) if !name.is_empty() => symbols.insert(addr, name),
+                _ => return Err(format!("Invalid symbol on line {}: {:?}", i + 1, line)),
+            }
+        }
+        Ok(symbols)
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        Symbols::parse(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn insert(&mut self, addr: u16, name: &str) {
+        self.labels.insert(addr, name.to_string());
+    }
+
+    pub fn get(&self, addr: u16) -> Option<&str> {
+        self.labels.get(&addr).map(String::as_str)
+    }
+}
+
+// ` ; name` for a labelled address, to follow the address in a trace line
+fn label(symbols: Option<&Symbols>, addr: u16) -> String {
+    symbols
+        .and_then(|symbols| symbols.get(addr))
+        .map(|name| format!(" ; {}", name))
+        .unwrap_or_default()
+}
+
+/// The next instruction as a nestest.log style line. Memory is peeked with `inspect`, so
+/// tracing an instruction doesn't change what it will read.
 pub fn trace(cpu: &mut CPU) -> String {
+    trace_line(cpu, None)
+}
+
+/// Same as `trace`, with jump and branch targets followed by their label from `symbols`,
+/// e.g. `JSR $C72D ; init`. Lines without a labelled target are identical to `trace`.
+pub fn trace_with_symbols(cpu: &mut CPU, symbols: &Symbols) -> String {
+    trace_line(cpu, Some(symbols))
+}
+
+fn trace_line(cpu: &CPU, symbols: Option<&Symbols>) -> String {
     let mut result = String::new();
     let ref opcode_table: HashMap<u8, &'static opcodes::OpCode> = *opcodes::OPCODES_MAP;
-    let code = cpu.bus.mem_read(cpu.program_counter);
+    let code = cpu.bus.inspect(cpu.program_counter);
     let opcode = opcode_table
         .get(&code)
         .expect(&format!("OpCode {:x} is not recognized", code));
@@ -23,30 +178,31 @@ pub fn trace(cpu: &mut CPU) -> String {
     } else if opcode.len == 2 {
         result.push_str(&format!(
             "{:02X}     ",
-            cpu.bus.mem_read(cpu.program_counter + 1)
+            cpu.bus.inspect(cpu.program_counter + 1)
         ));
     } else if opcode.len == 3 {
         result.push_str(&format!(
             "{:02X} {:02X}  ",
-            cpu.bus.mem_read(cpu.program_counter + 1),
-            cpu.bus.mem_read(cpu.program_counter + 2)
+            cpu.bus.inspect(cpu.program_counter + 1),
+            cpu.bus.inspect(cpu.program_counter + 2)
         ));
     }
 
     // mnemonic & formatted operand
     match opcode.mode {
         AddressingMode::Immediate => {
-            let value = cpu.bus.mem_read(cpu.program_counter + 1);
+            let value = cpu.bus.inspect(cpu.program_counter + 1);
+            let operand = format_operand(opcode, cpu.program_counter, &[value]);
 
             // mnemonic & value with format
             result.push_str(&format!(
                 "{:32}",
-                format!("{} #${:02X}", opcode.mnemonic, value)
+                format!("{} {}", opcode.mnemonic, operand)
             ));
         }
         AddressingMode::ZeroPage => {
             let addr = get_operand_address(cpu, &opcode.mode);
-            let value = cpu.bus.mem_read(addr as u16);
+            let value = cpu.bus.inspect(addr as u16);
 
             // mnemonic & addr with format
             result.push_str(&format!(
@@ -55,9 +211,9 @@ pub fn trace(cpu: &mut CPU) -> String {
             ));
         }
         AddressingMode::ZeroPage_X => {
-            let base = cpu.bus.mem_read(cpu.program_counter + 1);
+            let base = cpu.bus.inspect(cpu.program_counter + 1);
             let addr = get_operand_address(cpu, &opcode.mode);
-            let value = cpu.bus.mem_read(addr);
+            let value = cpu.bus.inspect(addr);
 
             result.push_str(&format!(
                 "{:32}",
@@ -69,9 +225,9 @@ pub fn trace(cpu: &mut CPU) -> String {
         }
 
         AddressingMode::ZeroPage_Y => {
-            let base = cpu.bus.mem_read(cpu.program_counter + 1);
+            let base = cpu.bus.inspect(cpu.program_counter + 1);
             let addr = get_operand_address(cpu, &opcode.mode);
-            let value = cpu.bus.mem_read(addr);
+            let value = cpu.bus.inspect(addr);
 
             result.push_str(&format!(
                 "{:32}",
@@ -83,14 +239,14 @@ pub fn trace(cpu: &mut CPU) -> String {
         }
         AddressingMode::Absolute => {
             let addr = get_operand_address(cpu, &opcode.mode);
-            let value = cpu.bus.mem_read(addr);
+            let value = cpu.bus.inspect(addr);
 
             match opcode.code {
                 // JMP系の命令の場合、値は表示しない
                 0x4c | 0x20 => {
                     result.push_str(&format!(
                         "{:32}",
-                        format!("{} ${:04X}", opcode.mnemonic, addr)
+                        format!("{} ${:04X}{}", opcode.mnemonic, addr, label(symbols, addr))
                     ));
                 }
                 _ => {
@@ -102,12 +258,12 @@ pub fn trace(cpu: &mut CPU) -> String {
             }
         }
         AddressingMode::Absolute_X => {
-            let lo = cpu.bus.mem_read(cpu.program_counter + 1) as u16;
-            let hi = cpu.bus.mem_read(cpu.program_counter + 2) as u16;
+            let lo = cpu.bus.inspect(cpu.program_counter + 1) as u16;
+            let hi = cpu.bus.inspect(cpu.program_counter + 2) as u16;
             let addr = (hi << 8) | lo;
 
             let indexed_addr = addr.wrapping_add(cpu.register_x as u16);
-            let value = cpu.bus.mem_read(indexed_addr);
+            let value = cpu.bus.inspect(indexed_addr);
 
             result.push_str(&format!(
                 "{:32}",
@@ -118,12 +274,12 @@ pub fn trace(cpu: &mut CPU) -> String {
             ));
         }
         AddressingMode::Absolute_Y => {
-            let lo = cpu.bus.mem_read(cpu.program_counter + 1) as u16;
-            let hi = cpu.bus.mem_read(cpu.program_counter + 2) as u16;
+            let lo = cpu.bus.inspect(cpu.program_counter + 1) as u16;
+            let hi = cpu.bus.inspect(cpu.program_counter + 2) as u16;
             let addr = (hi << 8) | lo;
 
             let indexed_addr = addr.wrapping_add(cpu.register_y as u16);
-            let value = cpu.bus.mem_read(indexed_addr);
+            let value = cpu.bus.inspect(indexed_addr);
 
             result.push_str(&format!(
                 "{:32}",
@@ -134,21 +290,27 @@ pub fn trace(cpu: &mut CPU) -> String {
             ));
         }
         AddressingMode::Indirect => {
-            let lo = cpu.bus.mem_read(cpu.program_counter + 1) as u16;
-            let hi = cpu.bus.mem_read(cpu.program_counter + 2) as u16;
+            let lo = cpu.bus.inspect(cpu.program_counter + 1) as u16;
+            let hi = cpu.bus.inspect(cpu.program_counter + 2) as u16;
             let addr = (hi << 8) | lo;
 
             let jmp_addr = get_operand_address(cpu, &opcode.mode);
 
             result.push_str(&format!(
                 "{:32}",
-                format!("{} (${:04X}) = {:04X}", opcode.mnemonic, addr, jmp_addr)
+                format!(
+                    "{} (${:04X}) = {:04X}{}",
+                    opcode.mnemonic,
+                    addr,
+                    jmp_addr,
+                    label(symbols, jmp_addr)
+                )
             ));
         }
         AddressingMode::Indirect_X => {
-            let base = cpu.bus.mem_read(cpu.program_counter + 1);
+            let base = cpu.bus.inspect(cpu.program_counter + 1);
             let addr = get_operand_address(cpu, &opcode.mode);
-            let value = cpu.bus.mem_read(addr);
+            let value = cpu.bus.inspect(addr);
 
             result.push_str(&format!(
                 "{:32}",
@@ -163,10 +325,10 @@ pub fn trace(cpu: &mut CPU) -> String {
             ));
         }
         AddressingMode::Indirect_Y => {
-            let base = cpu.bus.mem_read(cpu.program_counter + 1);
+            let base = cpu.bus.inspect(cpu.program_counter + 1);
             let addr = get_operand_address(cpu, &opcode.mode);
             let addr_before_indexed = addr.wrapping_sub(cpu.register_y as u16);
-            let value = cpu.bus.mem_read(addr);
+            let value = cpu.bus.inspect(addr);
 
             result.push_str(&format!(
                 "{:32}",
@@ -176,24 +338,26 @@ pub fn trace(cpu: &mut CPU) -> String {
                 )
             ));
         }
-        AddressingMode::NoneAddressing => match opcode.code {
-            // ブランチ系のRelativeアドレッシングモードでは、ジャンプ先のアドレスを計算して表示する
-            0x90 | 0xB0 | 0xF0 | 0x30 | 0xD0 | 0x10 | 0x50 | 0x70 => {
-                let offset = cpu.mem_read(cpu.program_counter + 1) as u16;
-                let jmp_addr = cpu.program_counter + 2 + offset;
-                result.push_str(&format!(
-                    "{:32}",
-                    format!("{} ${:04X}", opcode.mnemonic, jmp_addr)
-                ));
-            }
-            // Accumulatorアドレッシングモードの場合、Aと表示する
-            0x4A | 0x0A | 0x6A | 0x2A => {
-                result.push_str(&format!("{:32}", format!("{} A", opcode.mnemonic)));
-            }
-            _ => {
-                result.push_str(&format!("{:32}", opcode.mnemonic));
-            }
-        },
+        // ブランチ系のRelativeアドレッシングモードではジャンプ先のアドレスを、
+        // Accumulatorアドレッシングモードの場合はAを表示する
+        AddressingMode::NoneAddressing => {
+            let operand: Vec<u8> = (1..opcode.len as u16)
+                .map(|i| cpu.bus.inspect(cpu.program_counter + i))
+                .collect();
+            let target = branch_target(
+                opcode,
+                cpu.program_counter,
+                operand.first().copied().unwrap_or(0),
+            );
+            let operand = format_operand(opcode, cpu.program_counter, &operand);
+            let label = target
+                .map(|target| label(symbols, target))
+                .unwrap_or_default();
+            result.push_str(&format!(
+                "{:32}",
+                format!("{} {}{}", opcode.mnemonic, operand, label).trim_end()
+            ));
+        }
     }
 
     result.push_str(&format!(
@@ -205,41 +369,105 @@ pub fn trace(cpu: &mut CPU) -> String {
         cpu.stack_pointer
     ));
 
+    // Same columns as nestest.log, followed by the frame number
+    let (scanline, dot) = cpu.bus.get_ppu_position();
     result.push_str(&format!(
-        "PPU:{:3},{:3},CYC:{:5}",
-        cpu.bus.cycles,
-        cpu.bus.cycles * 3,
+        "PPU:{:3},{:3} CYC:{} FR:{}",
+        scanline,
+        dot,
         cpu.bus.cycles,
+        cpu.bus.ppu_frame()
     ));
 
     result
 }
 
-fn get_operand_address(cpu: &mut CPU, mode: &AddressingMode) -> u16 {
+/// The first line where a run differed from a reference log, see `CPU::run_compare`.
+/// `actual` is empty if the program stopped before the log ended.
+#[derive(Debug, PartialEq)]
+pub struct Divergence {
+    /// 1-based line number in the reference log
+    pub line: usize,
+    pub expected: String,
+    pub actual: String,
+}
+
+// Reference logs such as nestest.log end at the CYC column; trace lines can carry more
+// columns after it
+pub(crate) fn trace_matches(actual: &str, expected: &str) -> bool {
+    actual
+        .strip_prefix(expected)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with(' '))
+}
+
+/// Limits tracing to the instructions of interest, e.g. a single subroutine.
+/// Unset conditions match everything; when both are set, both must match.
+#[derive(Default)]
+pub struct TraceFilter {
+    pub pc_range: Option<RangeInclusive<u16>>,
+    pub opcodes: Option<HashSet<u8>>,
+}
+
+impl TraceFilter {
+    pub fn matches(&self, cpu: &CPU) -> bool {
+        if let Some(range) = &self.pc_range {
+            if !range.contains(&cpu.program_counter) {
+                return false;
+            }
+        }
+
+        if let Some(opcodes) = &self.opcodes {
+            let code = cpu.bus.inspect(cpu.program_counter);
+            if !opcodes.contains(&code) {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+/// Same as `trace`, but returns `None` for instructions rejected by `filter`.
+pub fn trace_filtered(cpu: &mut CPU, filter: &TraceFilter) -> Option<String> {
+    if filter.matches(cpu) {
+        Some(trace(cpu))
+    } else {
+        None
+    }
+}
+
+// Little endian word at `addr`, peeked like every other read in a trace line
+fn inspect_u16(cpu: &CPU, addr: u16) -> u16 {
+    let lo = cpu.bus.inspect(addr) as u16;
+    let hi = cpu.bus.inspect(addr.wrapping_add(1)) as u16;
+    (hi << 8) | lo
+}
+
+fn get_operand_address(cpu: &CPU, mode: &AddressingMode) -> u16 {
     let counter = cpu.program_counter + 1;
 
     match mode {
         AddressingMode::Immediate => counter,
-        AddressingMode::ZeroPage => cpu.mem_read(counter) as u16,
-        AddressingMode::Absolute => cpu.mem_read_u16(counter),
+        AddressingMode::ZeroPage => cpu.bus.inspect(counter) as u16,
+        AddressingMode::Absolute => inspect_u16(cpu, counter),
 
         AddressingMode::ZeroPage_X => {
-            let pos = cpu.mem_read(counter);
+            let pos = cpu.bus.inspect(counter);
             let addr = pos.wrapping_add(cpu.register_x) as u16;
             addr
         }
         AddressingMode::ZeroPage_Y => {
-            let pos = cpu.mem_read(counter);
+            let pos = cpu.bus.inspect(counter);
             let addr = pos.wrapping_add(cpu.register_y) as u16;
             addr
         }
         AddressingMode::Absolute_X => {
-            let pos = cpu.mem_read(counter);
+            let pos = cpu.bus.inspect(counter);
             let addr = pos.wrapping_add(cpu.register_x) as u16;
             addr
         }
         AddressingMode::Absolute_Y => {
-            let base = cpu.mem_read_u16(counter);
+            let base = inspect_u16(cpu, counter);
             let addr = base.wrapping_add(cpu.register_y as u16);
             addr
         }
@@ -249,29 +477,29 @@ fn get_operand_address(cpu: &mut CPU, mode: &AddressingMode) -> u16 {
             // 本来は、$30FFにある値(下位バイト)と$3100(上位バイト)にある値を参照しなければならないが
             // $30FF(下位バイト)と$3000(上位バイト)の値を参照してしまう
             // ここではそれを再現している
-            let addr = cpu.mem_read_u16(counter);
+            let addr = inspect_u16(cpu, counter);
 
             // 対象のアドレスがFFで終わる場合、つまりページをまたぐ場合はバグを再現
             if addr & 0x00FF == 0x00FF {
-                let lo = cpu.mem_read(addr);
-                let hi = cpu.mem_read(addr & 0xFF00);
+                let lo = cpu.bus.inspect(addr);
+                let hi = cpu.bus.inspect(addr & 0xFF00);
                 (hi as u16) << 8 | (lo as u16)
             } else {
-                cpu.mem_read_u16(addr)
+                inspect_u16(cpu, addr)
             }
         }
         AddressingMode::Indirect_X => {
-            let base = cpu.mem_read(counter);
+            let base = cpu.bus.inspect(counter);
 
             let ptr: u8 = (base as u8).wrapping_add(cpu.register_x);
-            let lo = cpu.mem_read(ptr as u16);
-            let hi = cpu.mem_read(ptr.wrapping_add(1) as u16);
+            let lo = cpu.bus.inspect(ptr as u16);
+            let hi = cpu.bus.inspect(ptr.wrapping_add(1) as u16);
             (hi as u16) << 8 | (lo as u16)
         }
         AddressingMode::Indirect_Y => {
-            let base: u8 = cpu.mem_read(counter);
-            let lo = cpu.mem_read(base as u16);
-            let hi = cpu.mem_read((base as u8).wrapping_add(1) as u16);
+            let base: u8 = cpu.bus.inspect(counter);
+            let lo = cpu.bus.inspect(base as u16);
+            let hi = cpu.bus.inspect((base as u8).wrapping_add(1) as u16);
             let deref_base = (hi as u16) << 8 | (lo as u16);
             let deref = deref_base.wrapping_add(cpu.register_y as u16);
             deref
@@ -288,11 +516,12 @@ mod test {
 
     use super::*;
     use crate::bus::Bus;
+    use crate::cpu::Mem;
     use crate::rom::test::TestRom;
 
     #[test]
     fn test_format_trace() {
-        let mut bus = Bus::new(TestRom::create_test_rom(vec![]), |_, _| {});
+        let mut bus = Bus::new(TestRom::create_test_rom(vec![]), |_, _, _| {});
         bus.mem_write(100, 0xa2);
         bus.mem_write(101, 0x01);
         bus.mem_write(102, 0xca);
@@ -310,22 +539,22 @@ mod test {
             result.push(trace(cpu));
         });
         assert_eq!(
-            "0064  A2 01     LDX #$01                        A:01 X:02 Y:03 P:24 SP:FD",
+            "0064  A2 01     LDX #$01                        A:01 X:02 Y:03 P:24 SP:FD PPU:  0,  0 CYC:0 FR:0",
             result[0]
         );
         assert_eq!(
-            "0066  CA        DEX                             A:01 X:01 Y:03 P:24 SP:FD",
+            "0066  CA        DEX                             A:01 X:01 Y:03 P:24 SP:FD PPU:  0,  6 CYC:2 FR:0",
             result[1]
         );
         assert_eq!(
-            "0067  88        DEY                             A:01 X:00 Y:03 P:26 SP:FD",
+            "0067  88        DEY                             A:01 X:00 Y:03 P:26 SP:FD PPU:  0, 12 CYC:4 FR:0",
             result[2]
         );
     }
 
     #[test]
     fn test_format_mem_access() {
-        let mut bus = Bus::new(TestRom::create_test_rom(vec![]), |_, _| {});
+        let mut bus = Bus::new(TestRom::create_test_rom(vec![]), |_, _, _| {});
         // ORA ($33), Y
         bus.mem_write(100, 0x11);
         bus.mem_write(101, 0x33);
@@ -346,14 +575,14 @@ mod test {
             result.push(trace(cpu));
         });
         assert_eq!(
-            "0064  11 33     ORA ($33),Y = 0400 @ 0400 = AA  A:00 X:00 Y:00 P:24 SP:FD",
+            "0064  11 33     ORA ($33),Y = 0400 @ 0400 = AA  A:00 X:00 Y:00 P:24 SP:FD PPU:  0,  0 CYC:0 FR:0",
             result[0]
         );
     }
 
     #[test]
     fn test_zerox_format() {
-        let mut bus = Bus::new(TestRom::create_test_rom(vec![]), |_, _| {});
+        let mut bus = Bus::new(TestRom::create_test_rom(vec![]), |_, _, _| {});
         // ORA ($33), Y
         bus.mem_write(100, 0xb5);
         bus.mem_write(101, 0x33);
@@ -372,14 +601,14 @@ mod test {
         });
 
         assert_eq!(
-            "0064  B5 33     LDA $33,X @ 34 = AA             A:00 X:01 Y:00 P:24 SP:FD",
+            "0064  B5 33     LDA $33,X @ 34 = AA             A:00 X:01 Y:00 P:24 SP:FD PPU:  0,  0 CYC:0 FR:0",
             result[0]
         );
     }
 
     #[test]
     fn test_zeroy_format() {
-        let mut bus = Bus::new(TestRom::create_test_rom(vec![]), |_, _| {});
+        let mut bus = Bus::new(TestRom::create_test_rom(vec![]), |_, _, _| {});
         bus.mem_write(100, 0xb6);
         bus.mem_write(101, 0x33);
 
@@ -397,8 +626,216 @@ mod test {
         });
 
         assert_eq!(
-            "0064  B6 33     LDX $33,Y @ 34 = AA             A:00 X:00 Y:01 P:24 SP:FD",
+            "0064  B6 33     LDX $33,Y @ 34 = AA             A:00 X:00 Y:01 P:24 SP:FD PPU:  0,  0 CYC:0 FR:0",
             result[0]
         );
     }
+
+    #[test]
+    fn test_trace_ppu_position() {
+        let mut bus = Bus::new(TestRom::create_test_rom(vec![]), |_, _, _| {});
+        bus.mem_write(100, 0xea); // NOP
+        bus.mem_write(101, 0x00);
+        // 29917 CPU cycles = 89751 dots = one frame + one scanline + 68 dots
+        for _ in 0..29917 {
+            bus.tick(1);
+        }
+
+        let mut cpu = CPU::new(bus);
+        cpu.reset();
+        cpu.program_counter = 0x64;
+        let line = trace(&mut cpu);
+        let (scanline, dot) = cpu.bus.get_ppu_position();
+
+        assert_eq!((scanline, dot), (1, 68));
+        assert!(line.ends_with(&format!(
+            "PPU:{:3},{:3} CYC:{} FR:1",
+            scanline, dot, cpu.bus.cycles
+        )));
+    }
+
+    #[test]
+    fn test_trace_filter() {
+        let mut bus = Bus::new(TestRom::create_test_rom(vec![]), |_, _, _| {});
+        bus.mem_write(100, 0xa2); // LDX #$01
+        bus.mem_write(101, 0x01);
+        bus.mem_write(102, 0xca); // DEX
+        bus.mem_write(103, 0x88); // DEY
+        bus.mem_write(104, 0xca); // DEX
+        bus.mem_write(105, 0x00);
+
+        let mut cpu = CPU::new(bus);
+        cpu.reset();
+        cpu.program_counter = 0x64;
+        let filter = TraceFilter {
+            pc_range: Some(0x66..=0x68),
+            opcodes: Some(HashSet::from([0xca])),
+        };
+        let mut result: Vec<String> = vec![];
+        cpu.run_with_callback(|cpu| {
+            if let Some(line) = trace_filtered(cpu, &filter) {
+                result.push(line);
+            }
+        });
+
+        assert_eq!(result.len(), 2);
+        assert!(result[0].starts_with("0066  CA        DEX"));
+        assert!(result[1].starts_with("0068  CA        DEX"));
+    }
+
+    // nestest.log style: no FR column
+    const GOLDEN_LOG: &str = "\
+8000  A9 80     LDA #$80                        A:00 X:00 Y:00 P:24 SP:FD PPU:  0,  0 CYC:0
+8002  69 80     ADC #$80                        A:80 X:00 Y:00 P:A4 SP:FD PPU:  0,  6 CYC:2
+8004  85 10     STA $10 = 00                    A:00 X:00 Y:00 P:67 SP:FD PPU:  0, 12 CYC:4
+8006  A2 02     LDX #$02                        A:00 X:00 Y:00 P:67 SP:FD PPU:  0, 21 CYC:7
+8008  CA        DEX                             A:00 X:02 Y:00 P:65 SP:FD PPU:  0, 27 CYC:9
+8009  D0 FD     BNE $8008                       A:00 X:01 Y:00 P:65 SP:FD PPU:  0, 33 CYC:11
+8008  CA        DEX                             A:00 X:01 Y:00 P:65 SP:FD PPU:  0, 42 CYC:14
+8009  D0 FD     BNE $8008                       A:00 X:00 Y:00 P:67 SP:FD PPU:  0, 48 CYC:16
+800B  C9 01     CMP #$01                        A:00 X:00 Y:00 P:67 SP:FD PPU:  0, 54 CYC:18
+800D  00        BRK                             A:00 X:00 Y:00 P:E4 SP:FD PPU:  0, 60 CYC:20
+";
+
+    fn golden_cpu() -> CPU<'static> {
+        let rom = TestRom::create_test_rom(vec![
+            0xa9, 0x80, // LDA #$80
+            0x69, 0x80, // ADC #$80
+            0x85, 0x10, // STA $10
+            0xa2, 0x02, // LDX #$02
+            0xca, // DEX
+            0xd0, 0xfd, // BNE $8008
+            0xc9, 0x01, // CMP #$01
+            0x00,
+        ]);
+        let mut cpu = CPU::new(Bus::new(rom, |_, _, _| {}));
+        cpu.reset();
+        cpu
+    }
+
+    #[test]
+    fn test_run_compare() {
+        let result = golden_cpu().run_compare(GOLDEN_LOG.as_bytes()).unwrap();
+        assert_eq!(result, None);
+
+        // ADC #$80 no longer setting V would show up on the line after it
+        let regressed = GOLDEN_LOG.replacen("P:67", "P:27", 1);
+        let divergence = golden_cpu()
+            .run_compare(regressed.as_bytes())
+            .unwrap()
+            .unwrap();
+        assert_eq!(divergence.line, 3);
+        assert!(divergence.expected.contains("P:27"));
+        assert!(divergence.actual.contains("P:67"));
+
+        let longer = format!("{}8000  A9 80\n", GOLDEN_LOG);
+        let divergence = golden_cpu()
+            .run_compare(longer.as_bytes())
+            .unwrap()
+            .unwrap();
+        assert_eq!((divergence.line, divergence.actual.as_str()), (11, ""));
+    }
+
+    #[test]
+    fn test_trace_has_no_side_effects() {
+        let accesses = std::cell::Cell::new(0);
+        let mut cpu = CPU::new(Bus::new(
+            TestRom::create_test_rom(vec![
+                0xad, 0x02, 0x20, // LDA $2002
+            ]),
+            |_, _, _| {},
+        ));
+        cpu.reset();
+        cpu.bus
+            .set_access_hook(0x0000..=0xffff, |_| accesses.set(accesses.get() + 1));
+        while cpu.bus.get_ppu_position().0 != 241 {
+            cpu.bus.tick(1);
+        }
+
+        trace(&mut cpu);
+        assert_eq!(accesses.get(), 0);
+        // Vblank is still set for the LDA to read
+        cpu.step();
+        assert_eq!(cpu.register_a & 0x80, 0x80);
+    }
+
+    #[test]
+    fn test_trace_with_symbols() {
+        let mut bus = Bus::new(TestRom::create_test_rom(vec![]), |_, _, _| {});
+        let program = [
+            0x20, 0x6a, 0x00, // JSR $006A
+            0xd0, 0xfb, // BNE $0064
+            0x00, //
+            0xe8, // INX (at $006A)
+            0x60, // RTS
+        ];
+        for (i, byte) in program.iter().enumerate() {
+            bus.mem_write(0x64 + i as u16, *byte);
+        }
+        let symbols =
+            Symbols::parse("$0064#start#entry point\n\n; subroutines\n006A=count").unwrap();
+
+        let mut cpu = CPU::new(bus);
+        cpu.reset();
+        cpu.program_counter = 0x64;
+        let mut result: Vec<String> = vec![];
+        cpu.run_with_callback(|cpu| {
+            result.push(trace_with_symbols(cpu, &symbols));
+        });
+        assert_eq!(
+            "0064  20 6A 00  JSR $006A ; count               A:00 X:00 Y:00 P:24 SP:FD PPU:  0,  0 CYC:0 FR:0",
+            result[0]
+        );
+        // Unlabelled lines are unchanged
+        assert_eq!(
+            "006A  E8        INX                             A:00 X:00 Y:00 P:24 SP:FB PPU:  0, 18 CYC:6 FR:0",
+            result[1]
+        );
+        assert!(result[3].starts_with("0067  D0 FB     BNE $0064 ; start"));
+
+        assert!(Symbols::parse("C000").is_err());
+        assert!(Symbols::parse("zzzz=name").is_err());
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let mut bus = Bus::new(TestRom::create_test_rom(vec![]), |_, _, _| {});
+        let program = [
+            0xa2, 0x05, // LDX #$05
+            0xbd, 0x00, 0x04, // LDA $0400,X
+            0x91, 0x33, // STA ($33),Y
+            0xca, // DEX
+            0xd0, 0xf7, // BNE $0065
+            0x0a, // ASL A
+            0x6c, 0xff, 0x30, // JMP ($30FF)
+            0x9e, // SHX, not decoded
+        ];
+        for (i, byte) in program.iter().enumerate() {
+            bus.mem_write(0x64 + i as u16, *byte);
+        }
+
+        let decoded: Vec<(u16, &str, String)> = disassemble(&bus, 0x64, 8)
+            .into_iter()
+            .map(|i| (i.addr, i.mnemonic, i.formatted_operand))
+            .collect();
+        let expected = [
+            (0x64, "LDX", "#$05"),
+            (0x66, "LDA", "$0400,X"),
+            (0x69, "STA", "($33),Y"),
+            (0x6b, "DEX", ""),
+            (0x6c, "BNE", "$0065"),
+            (0x6e, "ASL", "A"),
+            (0x6f, "JMP", "($30FF)"),
+            (0x72, "???", ""),
+        ];
+        assert_eq!(decoded.len(), expected.len());
+        for (got, want) in decoded.iter().zip(expected) {
+            assert_eq!((got.0, got.1, got.2.as_str()), want);
+        }
+
+        let lda = &disassemble(&bus, 0x66, 1)[0];
+        assert_eq!(lda.bytes, vec![0xbd, 0x00, 0x04]);
+        assert_eq!(lda.mode, AddressingMode::Absolute_X);
+        assert_eq!(bus.cycles, 0);
+    }
 }
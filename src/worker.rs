@@ -0,0 +1,137 @@
+//! Runs the emulator on a dedicated thread.
+//!
+//! `CPU` and `Bus` are not `Send` (the gameloop callback is a boxed `FnMut` that may borrow
+//! from its creator), so they can't be moved to another thread. Instead `EmulatorThread::spawn`
+//! moves the `Rom` over and builds the whole machine on the worker thread. From then on the two
+//! threads only talk through channels:
+//!
+//! - every frame is rendered at NMI and sent to `frames()`,
+//! - button changes sent with `set_button_pressed_status` are applied at the next NMI.
+//!
+//! The frame channel is bounded, so the emulator waits for the frontend rather than running
+//! ahead of it. The worker stops when `join` is called, the frame receiver is gone or the
+//! program hits BRK.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use crate::{
+    bus::Bus,
+    cpu::CPU,
+    joypad::{Joypad, JoypadButton},
+    ppu::NesPPU,
+    render::{self, frame::Frame},
+    rom::Rom,
+};
+
+const FRAME_QUEUE: usize = 2;
+
+pub struct EmulatorThread {
+    frames: Receiver<Frame>,
+    input: Sender<(JoypadButton, bool)>,
+    stop: Arc<AtomicBool>,
+    handle: JoinHandle<()>,
+}
+
+impl EmulatorThread {
+    pub fn spawn(rom: Rom) -> Self {
+        let (frame_tx, frame_rx) = mpsc::sync_channel(FRAME_QUEUE);
+        let (input_tx, input_rx) = mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let worker_stop = stop.clone();
+        let handle = thread::spawn(move || run(rom, frame_tx, input_rx, worker_stop));
+
+        EmulatorThread {
+            frames: frame_rx,
+            input: input_tx,
+            stop,
+            handle,
+        }
+    }
+
+    pub fn frames(&self) -> &Receiver<Frame> {
+        &self.frames
+    }
+
+    pub fn set_button_pressed_status(&self, button: JoypadButton, pressed: bool) {
+        // The worker may already have stopped, in which case there is nobody to tell
+        let _ = self.input.send((button, pressed));
+    }
+
+    /// Stops the emulator and waits for the worker thread to finish.
+    pub fn join(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        // Unblocks the worker if it is waiting for room in the frame queue
+        drop(self.frames);
+        self.handle.join().unwrap();
+    }
+}
+
+fn run(
+    rom: Rom,
+    frames: SyncSender<Frame>,
+    input: Receiver<(JoypadButton, bool)>,
+    stop: Arc<AtomicBool>,
+) {
+    let callback_stop = stop.clone();
+
+    let bus = Bus::new(
+        rom,
+        move |ppu: &NesPPU, joypad: &mut Joypad, _: &mut Joypad| {
+            let mut frame = Frame::new();
+            render::render(ppu, &mut frame);
+            if frames.send(frame).is_err() {
+                callback_stop.store(true, Ordering::Relaxed);
+            }
+
+            for (button, pressed) in input.try_iter() {
+                joypad.set_button_pressed_status(button, pressed);
+            }
+        },
+    );
+
+    let mut cpu = CPU::new(bus);
+    cpu.reset();
+    // Checked every instruction, not every frame, so a program that never enables NMI can
+    // still be stopped
+    while !stop.load(Ordering::Relaxed) && cpu.step() {}
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rom::test::TestRom;
+
+    #[test]
+    fn test_frames_from_worker_thread() {
+        let mut rom = TestRom::create_test_rom(vec![
+            0xa9, 0x80, // LDA #$80
+            0x8d, 0x00, 0x20, // STA $2000 (enable NMI)
+            0x4c, 0x05, 0x80, // JMP $8005
+            0x40, // RTI (NMI handler at $8008)
+        ]);
+        rom.prg_rom[0x7ffa] = 0x08;
+        rom.prg_rom[0x7ffb] = 0x80;
+
+        let emulator = EmulatorThread::spawn(rom);
+        emulator.set_button_pressed_status(JoypadButton::START, true);
+        for _ in 0..3 {
+            let frame = emulator.frames().recv().unwrap();
+            assert_eq!(frame.rgb_bytes().len(), 256 * 240 * 3);
+        }
+        emulator.join();
+    }
+
+    #[test]
+    fn test_join_without_nmi() {
+        let rom = TestRom::create_test_rom(vec![
+            0x4c, 0x00, 0x80, // JMP $8000 (NMI is never enabled)
+        ]);
+
+        let emulator = EmulatorThread::spawn(rom);
+        emulator.join();
+    }
+}
@@ -0,0 +1,96 @@
+//! Zapper light gun, plugged into the second controller port ($4017).
+//!
+//! The real gun senses the CRT beam passing under it. The PPU here draws whole frames, so
+//! instead the gun looks at the last rendered frame around the cursor: the game flashes a
+//! white target for a frame and reads the sensor during the next one.
+
+use crate::render::frame::Frame;
+
+// Pixels around the cursor the sensor sees, in each direction
+const SENSOR_RADIUS: usize = 2;
+// Average of R, G and B a pixel needs to count as lit
+const LIGHT_THRESHOLD: u16 = 0xa0;
+// How long a click keeps the trigger pulled, in frames
+const TRIGGER_PULSE_FRAMES: u8 = 3;
+
+#[derive(Default)]
+pub struct Zapper {
+    x: usize,
+    y: usize,
+    light: bool,
+    trigger_frames: u8,
+}
+
+impl Zapper {
+    pub fn new() -> Self {
+        Zapper::default()
+    }
+
+    /// Where the gun points, in frame pixels.
+    pub fn set_position(&mut self, x: usize, y: usize) {
+        self.x = x;
+        self.y = y;
+    }
+
+    pub fn pull_trigger(&mut self) {
+        self.trigger_frames = TRIGGER_PULSE_FRAMES;
+    }
+
+    /// Call once per frame with the frame just rendered.
+    pub fn update(&mut self, frame: &Frame) {
+        let (width, height) = (Frame::PITCH / 3, frame.data.len() / Frame::PITCH);
+        let xs = self.x.saturating_sub(SENSOR_RADIUS)..=(self.x + SENSOR_RADIUS).min(width - 1);
+        let ys = self.y.saturating_sub(SENSOR_RADIUS)..=(self.y + SENSOR_RADIUS).min(height - 1);
+
+        self.light = ys.into_iter().any(|y| {
+            xs.clone().any(|x| {
+                let (r, g, b) = frame.get_pixel(x, y);
+                (r as u16 + g as u16 + b as u16) / 3 >= LIGHT_THRESHOLD
+            })
+        });
+        self.trigger_frames = self.trigger_frames.saturating_sub(1);
+    }
+
+    /// Bits 3 and 4 of a $4017 read. Bit 3 is 0 while light is sensed (the sensor is
+    /// active low), bit 4 is 1 while the trigger is pulled.
+    pub fn read(&self) -> u8 {
+        let no_light = (!self.light as u8) << 3;
+        let trigger = ((self.trigger_frames > 0) as u8) << 4;
+        no_light | trigger
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_light_sense() {
+        let mut zapper = Zapper::new();
+        zapper.set_position(100, 100);
+
+        let mut frame = Frame::new();
+        zapper.update(&frame);
+        assert_eq!(zapper.read() & 0b1000, 0b1000);
+
+        frame.set_pixel(101, 99, (0xff, 0xff, 0xff));
+        zapper.update(&frame);
+        assert_eq!(zapper.read() & 0b1000, 0);
+
+        zapper.set_position(110, 100);
+        zapper.update(&frame);
+        assert_eq!(zapper.read() & 0b1000, 0b1000);
+    }
+
+    #[test]
+    fn test_trigger_pulse() {
+        let mut zapper = Zapper::new();
+        let frame = Frame::new();
+        zapper.pull_trigger();
+        for _ in 0..TRIGGER_PULSE_FRAMES {
+            assert_eq!(zapper.read() & 0b1_0000, 0b1_0000);
+            zapper.update(&frame);
+        }
+        assert_eq!(zapper.read() & 0b1_0000, 0);
+    }
+}
@@ -0,0 +1,50 @@
+use nes::golden::frame_hash;
+use nes::prelude::*;
+
+const NESTEST: &[u8] = include_bytes!("../nestest.nes");
+
+// Hash of the nestest menu screen. Regenerate if rendering changes on purpose.
+const NESTEST_FRAME_60: u64 = 0x71e8_8943_9230_f6c0;
+
+#[test]
+fn test_step_frame_without_callback() {
+    let rom = Rom::new(NESTEST).unwrap();
+    let mut cpu = CPU::new(Bus::new(rom, |_, _, _| {}));
+    cpu.reset();
+
+    for _ in 0..59 {
+        cpu.step_frame().expect("nestest shouldn't hit BRK");
+    }
+    let frame = cpu.step_frame().unwrap();
+
+    // The menu text is drawn, not just the backdrop
+    let first_pixel = &frame.rgb_bytes()[0..3];
+    assert!(frame
+        .rgb_bytes()
+        .chunks(3)
+        .any(|pixel| pixel != first_pixel));
+    assert_eq!(frame_hash(frame), NESTEST_FRAME_60);
+    assert_eq!(cpu.bus.ppu_frame(), 59);
+}
+
+// Checked-in NROM test ROM: diagonal stripes of tiles 0-3 with one palette per quadrant
+// of each attribute cell, plus a sprite at (120, 101)
+const STRIPES: &[u8] = include_bytes!("roms/stripes.nes");
+
+// Hash of its palette indices on frame 3. Regenerate if rendering changes on purpose.
+const STRIPES_FRAME_3: u64 = 0x16a9_6f5d_0c49_9865;
+
+#[test]
+fn test_golden_frame_palette_indices() {
+    let pixels = nes::golden::run_rom_frames(STRIPES, 3).unwrap();
+    let at = |x: usize, y: usize| pixels[y * 256 + x];
+
+    // Tile 0 shows the backdrop, tile 1 at (8, 0) is color 1 of background palette 0
+    assert_eq!(at(0, 0), 0x0f);
+    assert_eq!(at(8, 0), 0x16);
+    // Tile 3 at (16, 8), background palette 1 in the top right of the attribute cell
+    assert_eq!(at(16, 8), 0x31);
+    // The sprite: tile 3, color 3 of sprite palette 1
+    assert_eq!(at(124, 104), 0x11);
+    assert_eq!(nes::golden::buffer_hash(&pixels), STRIPES_FRAME_3);
+}
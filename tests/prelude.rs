@@ -0,0 +1,46 @@
+use std::cell::Cell;
+
+use nes::prelude::*;
+
+// NROM image with one PRG and one CHR bank: enables NMI and loops, the NMI handler is an RTI
+fn nrom_image() -> Vec<u8> {
+    let mut raw = vec![0x4e, 0x45, 0x53, 0x1a, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    let mut prg = vec![0; 0x4000];
+    let program = [
+        0xa9, 0x80, // LDA #$80
+        0x8d, 0x00, 0x20, // STA $2000
+        0x4c, 0x05, 0x80, // JMP $8005
+        0x40, // RTI
+    ];
+    prg[..program.len()].copy_from_slice(&program);
+    prg[0x3ffa..].copy_from_slice(&[0x08, 0x80, 0x00, 0x80, 0x00, 0x80]);
+    raw.extend(prg);
+    raw.extend(vec![0; 0x2000]);
+    raw
+}
+
+#[test]
+fn test_run_a_frame_with_prelude() {
+    let rom = Rom::new(&nrom_image()).unwrap();
+    assert_eq!(rom.screen_mirroring, Mirroring::Horizontal);
+
+    let frames = Cell::new(0);
+    let mut frame = Frame::new();
+    let bus = Bus::new(rom, |ppu: &NesPPU, joypad: &mut Joypad, _: &mut Joypad| {
+        render(ppu, &mut frame);
+        joypad.set_button_pressed_status(JoypadButton::START, true);
+        frames.set(frames.get() + 1);
+    });
+
+    let mut cpu = CPU::new(bus);
+    cpu.reset();
+    while frames.get() == 0 {
+        assert!(cpu.step());
+    }
+    // The first NMI comes at vblank, before the frame counter moves on
+    assert_eq!(cpu.bus.ppu_frame(), 0);
+    assert_eq!(cpu.bus.get_ppu_position().0, 241);
+
+    drop(cpu);
+    assert_eq!(frame.rgb_bytes().len(), Frame::PITCH * 240);
+}